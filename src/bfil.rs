@@ -0,0 +1,472 @@
+//! bfil, the linear intermediate language between the optimized [`BfOpBlock`] tree and codegen
+//!
+//! A bfil program is a flat list of [`Op`]s. All offsets are in cells, relative to the current data pointer,
+//! and loops are delimited by [`Op::LoopStart`]/[`Op::LoopEnd`] pairs.\
+//! Like the tree it's lowered from, it's specific to a [`CellWidth`](crate::brainfuck::CellWidth).
+//!
+//! Textual form, one op per line (indentation is ignored, `;` starts a comment):
+//! ```text
+//! add p[+1], -3       ; p[1] += -3
+//! set p[+0], 0        ; p[0] = 0
+//! move +2             ; p += 2
+//! muladd p[+2], p[+0], 3 ; p[2] += p[0] * 3
+//! scan -1             ; while p[0] != 0 { p += -1 }
+//! out p[+0]           ; write p[0]
+//! in p[+0]            ; read p[0]
+//! loop                ; while p[0] != 0 {
+//! end                 ; }
+//! check p[-1], p[+2]  ; exit with an error unless p[-1] to p[2] are within the tape
+//! checkloop p[-1], p[+2] ; same, but only if p[0] != 0
+//! ```
+
+use std::{fmt, io::{self, Write}};
+use itertools::Itertools;
+use crate::brainfuck::{BfOpBlock, Effect, Span};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+  /// `p[off] += n`
+  AddCell { off: isize, n: i32 },
+  /// `p[off] = value`
+  SetCell { off: isize, value: u32 },
+  /// `p += n`
+  MovePtr(isize),
+  /// `p[dst] += p[src] * k`
+  MulAdd { src: isize, dst: isize, k: i32 },
+  /// `while p[0] != 0 { p += stride }`
+  Scan(isize),
+  /// Write `p[off]` to the output
+  Out { off: isize },
+  /// Read a byte from the input into `p[off]`
+  In { off: isize },
+  /// `while p[0] != 0 {`
+  LoopStart,
+  /// `}`
+  LoopEnd,
+  /// Exit with an error unless `p[min]` to `p[max]` are all within the tape
+  CheckBounds { min: isize, max: isize },
+  /// Same as [`Op::CheckBounds`], but only if `p[0] != 0`\
+  /// Used for checks hoisted out of the loop that follows
+  CheckLoopBounds { min: isize, max: isize },
+}
+
+impl fmt::Display for Op {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      Op::AddCell { off, n } => write!(f, "add p[{off:+}], {n}"),
+      Op::SetCell { off, value } => write!(f, "set p[{off:+}], {value}"),
+      Op::MovePtr(n) => write!(f, "move {n:+}"),
+      Op::MulAdd { src, dst, k } => write!(f, "muladd p[{dst:+}], p[{src:+}], {k}"),
+      Op::Scan(stride) => write!(f, "scan {stride:+}"),
+      Op::Out { off } => write!(f, "out p[{off:+}]"),
+      Op::In { off } => write!(f, "in p[{off:+}]"),
+      Op::LoopStart => write!(f, "loop"),
+      Op::LoopEnd => write!(f, "end"),
+      Op::CheckBounds { min, max } => write!(f, "check p[{min:+}], p[{max:+}]"),
+      Op::CheckLoopBounds { min, max } => write!(f, "checkloop p[{min:+}], p[{max:+}]"),
+    }
+  }
+}
+
+/// Lower an effect on a single cell, `None` for MulAdds
+fn lower_effect(effect: Effect, off: isize) -> Option<Op> {
+  Some(match effect {
+    Effect::CellInc(n) => Op::AddCell { off, n },
+    Effect::CellSet(value) => Op::SetCell { off, value },
+    Effect::MulAdd { .. } => return None,
+    Effect::Output => Op::Out { off },
+    Effect::Input => Op::In { off },
+  })
+}
+
+/// `spans` gets the span of the block every op was lowered from
+fn lower_recursive(block: &BfOpBlock, ops: &mut Vec<Op>, spans: &mut Vec<Option<Span>>) {
+  match block {
+    BfOpBlock::Master(children) => {
+      for child in children {
+        lower_recursive(child, ops, spans);
+      }
+    },
+    BfOpBlock::Loop(children, span) => {
+      ops.push(Op::LoopStart);
+      spans.push(Some(*span));
+      for child in children {
+        lower_recursive(child, ops, spans);
+      }
+      ops.push(Op::LoopEnd);
+      spans.push(Some(*span));
+    },
+    BfOpBlock::Scan { stride, span } => {
+      ops.push(Op::Scan(*stride));
+      spans.push(Some(*span));
+    },
+    BfOpBlock::Unit(unit) => {
+      //MulAdd effects read the cells as they were at the start of the unit,
+      //so they have to be lowered before any other effects
+      for key in unit.effects.keys().copied().sorted() {
+        for effect in &unit.effects[&key] {
+          if let Effect::MulAdd { src, factor } = *effect {
+            ops.push(Op::MulAdd { src, dst: key, k: factor });
+          }
+        }
+      }
+
+      //The effects on the cell the pointer ends up at come last, apart from I/O,
+      //so the pointer can be moved before them instead of after the unit, saving a couple bytes
+      let effects = unit.ordered_effects();
+      let moved = effects.iter().rev()
+        .take_while(|&&(key, effect)| key == unit.ptr_offset && !matches!(effect, Effect::Output | Effect::Input))
+        .count();
+      let (before, after) = effects.split_at(effects.len() - moved);
+      ops.extend(before.iter().filter_map(|&(key, effect)| lower_effect(effect, key)));
+      if unit.ptr_offset != 0 {
+        ops.push(Op::MovePtr(unit.ptr_offset));
+      }
+      ops.extend(after.iter().filter_map(|&(_, effect)| lower_effect(effect, 0)));
+      spans.resize(ops.len(), unit.span);
+    },
+  }
+}
+
+/// Lower an (optimized) op tree into a flat list of bfil ops
+pub fn lower(block: &BfOpBlock) -> Vec<Op> {
+  lower_with_spans(block).0
+}
+
+/// Same as [`lower`], also returning the source code each op was lowered from, see [`BfOpBlock::span`]\
+/// Loop heads and ends get the span of the whole loop
+pub fn lower_with_spans(block: &BfOpBlock) -> (Vec<Op>, Vec<Option<Span>>) {
+  let (mut ops, mut spans) = (vec![], vec![]);
+  lower_recursive(block, &mut ops, &mut spans);
+  (ops, spans)
+}
+
+/// Include `off` in the `(min, max)` range of accessed cells
+fn extend_range(range: &mut Option<(isize, isize)>, off: isize) {
+  *range = Some(match *range {
+    Some((min, max)) => (min.min(off), max.max(off)),
+    None => (off, off),
+  });
+}
+
+/// Index of the `LoopEnd` matching the `LoopStart` at `ops[start]`
+fn loop_end(ops: &[Op], start: usize) -> usize {
+  let mut depth = 0;
+  for (idx, op) in ops.iter().enumerate().skip(start) {
+    match op {
+      Op::LoopStart => depth += 1,
+      Op::LoopEnd => depth -= 1,
+      _ => continue,
+    }
+    if depth == 0 {
+      return idx
+    }
+  }
+  panic!("unbalanced bfil loop")
+}
+
+/// Cells accessed by straight-line code, relative to the data pointer at its start,
+/// and how far it moves the pointer\
+/// MulAdd destinations are left out, as they're only accessed if the source isn't 0
+fn straight_line_accesses(ops: &[Op], range: &mut Option<(isize, isize)>, moved: &mut isize) {
+  for op in ops {
+    match *op {
+      Op::AddCell { off, .. } | Op::SetCell { off, .. } | Op::Out { off } | Op::In { off } => {
+        extend_range(range, *moved + off);
+      },
+      Op::MulAdd { src, .. } => extend_range(range, *moved + src),
+      Op::MovePtr(n) => *moved += n,
+      Op::CheckBounds { .. } | Op::CheckLoopBounds { .. } => (),
+      Op::Scan(_) | Op::LoopStart | Op::LoopEnd => unreachable!("not straight-line code"),
+    }
+  }
+}
+
+/// Range of cells the top level of a loop body accesses on every iteration,
+/// or `None` if the loop isn't balanced, meaning it may move the data pointer
+fn loop_invariant_accesses(body: &[Op]) -> Option<(isize, isize)> {
+  //The loop condition
+  let mut range = Some((0, 0));
+  let mut moved = 0;
+  let mut idx = 0;
+  while idx < body.len() {
+    match body[idx] {
+      Op::Scan(_) => return None,
+      Op::LoopStart => {
+        let end = loop_end(body, idx);
+        loop_invariant_accesses(&body[idx + 1..end])?;
+        extend_range(&mut range, moved);
+        idx = end;
+      },
+      _ => straight_line_accesses(&body[idx..idx + 1], &mut range, &mut moved),
+    }
+    idx += 1;
+  }
+  if moved != 0 {
+    return None
+  }
+  range
+}
+
+/// Insert checks into a sequence of ops, which is followed by an access to `p[0]` if `then_access`
+fn insert_checks_recursive(ops: &[Op], hoisted: bool, then_access: bool, checked: &mut Vec<Op>) {
+  let mut idx = 0;
+  while idx < ops.len() {
+    match ops[idx] {
+      Op::LoopStart => {
+        let end = loop_end(ops, idx);
+        let body = &ops[idx + 1..end];
+        let invariant = loop_invariant_accesses(body);
+        if let Some((min, max)) = invariant {
+          checked.push(Op::CheckLoopBounds { min, max });
+        }
+        checked.push(Op::LoopStart);
+        insert_checks_recursive(body, invariant.is_some(), true, checked);
+        checked.push(Op::LoopEnd);
+        idx = end + 1;
+      },
+      Op::Scan(_) => {
+        checked.push(ops[idx]);
+        idx += 1;
+      },
+      _ => {
+        let len = ops[idx..].iter()
+          .position(|op| matches!(op, Op::LoopStart | Op::Scan(_)))
+          .unwrap_or(ops.len() - idx);
+        let (mut range, mut moved) = (None, 0);
+        straight_line_accesses(&ops[idx..idx + len], &mut range, &mut moved);
+        //Loops and scans start by accessing the cell the pointer ends up at
+        if idx + len < ops.len() || then_access {
+          extend_range(&mut range, moved);
+        }
+        if let (false, Some((min, max))) = (hoisted, range) {
+          checked.push(Op::CheckBounds { min, max });
+        }
+        checked.extend_from_slice(&ops[idx..idx + len]);
+        idx += len;
+      },
+    }
+  }
+}
+
+/// Insert bounds checks in front of tape accesses, see [`CompilerOptions::bounds_checks`]
+///
+/// Straight-line code between loops gets a single check for all the cells it accesses,
+/// including the one the following loop or scan starts at.\
+/// Balanced loops access the same cells on every iteration,
+/// so those checks are hoisted out of them, and done once when entering the loop.\
+/// MulAdd destinations and scans are left to the compiler, since they're accessed conditionally.
+///
+/// [`CompilerOptions::bounds_checks`]: crate::CompilerOptions::bounds_checks
+pub fn insert_bounds_checks(ops: &[Op]) -> Vec<Op> {
+  let mut checked = vec![];
+  insert_checks_recursive(ops, false, false, &mut checked);
+  checked
+}
+
+/// Print bfil ops in their textual form, indenting loop bodies
+pub fn print<W: Write>(ops: &[Op], out: &mut W) -> io::Result<()> {
+  let mut indent = 0;
+  for op in ops {
+    if *op == Op::LoopEnd {
+      indent -= 1;
+    }
+    writeln!(out, "{}{op}", "  ".repeat(indent))?;
+    if *op == Op::LoopStart {
+      indent += 1;
+    }
+  }
+  Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+  /// 1-based line number
+  pub line: usize,
+  /// 1-based column (in characters) of the token the error is about
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Error message along with the token it's about
+type TokenError<'a> = (&'a str, String);
+
+fn parse_num<T: std::str::FromStr>(token: &str) -> Result<T, TokenError<'_>> {
+  token.strip_prefix('+').unwrap_or(token)
+    .parse()
+    .map_err(|_| (token, format!("invalid number `{token}`")))
+}
+
+fn parse_cell(token: &str) -> Result<isize, TokenError<'_>> {
+  token.strip_prefix("p[")
+    .and_then(|token| token.strip_suffix(']'))
+    .ok_or_else(|| (token, format!("expected a cell like `p[+1]`, found `{token}`")))
+    .and_then(parse_num)
+}
+
+fn parse_op(line: &str) -> Result<Op, TokenError<'_>> {
+  let (mnemonic, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+  let operands: Vec<&str> = match operands.trim() {
+    "" => vec![],
+    operands => operands.split(',').map(str::trim).collect(),
+  };
+  let op = match (mnemonic, &operands[..]) {
+    ("add", [off, n]) => Op::AddCell { off: parse_cell(off)?, n: parse_num(n)? },
+    ("set", [off, value]) => Op::SetCell { off: parse_cell(off)?, value: parse_num(value)? },
+    ("move", [n]) => Op::MovePtr(parse_num(n)?),
+    ("muladd", [dst, src, k]) => Op::MulAdd { src: parse_cell(src)?, dst: parse_cell(dst)?, k: parse_num(k)? },
+    ("scan", [stride]) => Op::Scan(parse_num(stride)?),
+    ("out", [off]) => Op::Out { off: parse_cell(off)? },
+    ("in", [off]) => Op::In { off: parse_cell(off)? },
+    ("loop", []) => Op::LoopStart,
+    ("end", []) => Op::LoopEnd,
+    ("check", [min, max]) => Op::CheckBounds { min: parse_cell(min)?, max: parse_cell(max)? },
+    ("checkloop", [min, max]) => Op::CheckLoopBounds { min: parse_cell(min)?, max: parse_cell(max)? },
+    ("add" | "set" | "move" | "muladd" | "scan" | "out" | "in" | "loop" | "end" | "check" | "checkloop", _) => {
+      return Err((mnemonic, format!("wrong number of operands for `{mnemonic}`")))
+    },
+    _ => return Err((mnemonic, format!("unknown op `{mnemonic}`"))),
+  };
+  Ok(op)
+}
+
+/// Parse the textual form of bfil, as produced by [`print()`]
+pub fn parse(text: &str) -> Result<Vec<Op>, ParseError> {
+  let mut ops = vec![];
+  //Line and column of each open `loop`
+  let mut loop_starts = vec![];
+  for (idx, full_line) in text.lines().enumerate() {
+    let line_number = idx + 1;
+    //Tokens are all slices of the line, which gives their column
+    let column = |token: &str| full_line[..token.as_ptr() as usize - full_line.as_ptr() as usize].chars().count() + 1;
+    let line = full_line.split(';').next().unwrap().trim();
+    if line.is_empty() {
+      continue
+    }
+    let op = parse_op(line).map_err(|(token, message)| ParseError { line: line_number, column: column(token), message })?;
+    match op {
+      Op::LoopStart => loop_starts.push((line_number, column(line))),
+      Op::LoopEnd if loop_starts.pop().is_none() => {
+        return Err(ParseError { line: line_number, column: column(line), message: "`end` without a matching `loop`".into() })
+      },
+      _ => (),
+    }
+    ops.push(op);
+  }
+  if let Some((line, column)) = loop_starts.pop() {
+    return Err(ParseError { line, column, message: "`loop` is never closed".into() })
+  }
+  Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::brainfuck::{parse_tree, CellWidth};
+
+  fn round_trip(ops: &[Op]) {
+    let mut text = vec![];
+    print(ops, &mut text).unwrap();
+    assert_eq!(parse(std::str::from_utf8(&text).unwrap()).unwrap(), ops);
+  }
+
+  #[test]
+  fn round_trip_mandelbrot() {
+    let ops = lower(&parse_tree(include_str!("../.bf/mandelbrot.bf"), CellWidth::U8).unwrap());
+    round_trip(&ops);
+    round_trip(&insert_bounds_checks(&ops));
+  }
+
+  #[test]
+  fn round_trip_every_op() {
+    let block = parse_tree("++>-<[.-]>>[->+++<<-->]<<[<<]>>>[>+<-],.", CellWidth::U8).unwrap();
+    let ops = insert_bounds_checks(&lower(&block));
+    let variants: std::collections::HashSet<_> = ops.iter().map(std::mem::discriminant).collect();
+    assert_eq!(variants.len(), 11, "{ops:?}");
+    round_trip(&ops);
+    //Extreme values survive as well
+    round_trip(&[
+      Op::AddCell { off: isize::MIN, n: i32::MIN },
+      Op::SetCell { off: isize::MAX, value: u32::MAX },
+      Op::MulAdd { src: -1, dst: 1, k: i32::MAX },
+    ]);
+  }
+
+  #[test]
+  fn hoisted_checks() {
+    let ops = parse("
+      add p[+1], 1
+      loop
+        add p[+0], -1
+        out p[+2]
+        loop
+          add p[-1], 1
+          set p[+0], 0
+        end
+      end
+      move +1
+      loop
+        add p[+0], -1
+        move +1
+      end
+      scan -1
+      muladd p[+3], p[+0], 2
+    ").unwrap();
+    //Balanced loops are checked once before entering them, nested ones on every iteration of the outer loop.\
+    //The rest is checked in front of every stretch of straight-line code, including the cell the next loop starts at,
+    //except for scans and MulAdd destinations
+    let expected = parse("
+      check p[+0], p[+1]
+      add p[+1], 1
+      checkloop p[+0], p[+2]
+      loop
+        add p[+0], -1
+        out p[+2]
+        checkloop p[-1], p[+0]
+        loop
+          add p[-1], 1
+          set p[+0], 0
+        end
+      end
+      check p[+1], p[+1]
+      move +1
+      loop
+        check p[+0], p[+1]
+        add p[+0], -1
+        move +1
+      end
+      scan -1
+      check p[+0], p[+0]
+      muladd p[+3], p[+0], 2
+    ").unwrap();
+    assert_eq!(insert_bounds_checks(&ops), expected);
+  }
+
+  /// Line, column and message of the error parsing `text`
+  fn error(text: &str) -> (usize, usize, String) {
+    let err = parse(text).unwrap_err();
+    (err.line, err.column, err.message)
+  }
+
+  #[test]
+  fn error_positions() {
+    assert_eq!(error("add p[+0], 1\n  frob p[+0]"), (2, 3, "unknown op `frob`".into()));
+    assert_eq!(error("loop\n  add p[+1]\nend"), (2, 3, "wrong number of operands for `add`".into()));
+    assert_eq!(error("set p[+1], x ; comment"), (1, 12, "invalid number `x`".into()));
+    assert_eq!(error("muladd p[+2], q[+0], 3"), (1, 15, "expected a cell like `p[+1]`, found `q[+0]`".into()));
+    //Numbers inside cells point at the number itself
+    assert_eq!(error("\tout p[+z]"), (1, 8, "invalid number `+z`".into()));
+    assert_eq!(error("loop\nend\n end"), (3, 2, "`end` without a matching `loop`".into()));
+    assert_eq!(error("loop\n loop\nend"), (1, 1, "`loop` is never closed".into()));
+    //Columns count characters, not bytes
+    assert_eq!(error("move +1 ; ü\nmove ü"), (2, 6, "invalid number `ü`".into()));
+  }
+}
@@ -1,44 +1,251 @@
-use std::{collections::HashMap, vec, rc::Rc, cell::RefCell};
+use std::{collections::HashMap, vec, io::{self, Write}, fmt};
 
 use itertools::Itertools;
 
+/// Size of a single cell, cell arithmetic wraps around at this width
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CellWidth {
+  #[default]
+  U8,
+  U16,
+  U32,
+}
+
+impl CellWidth {
+  pub fn bytes(self) -> usize {
+    match self {
+      Self::U8 => 1,
+      Self::U16 => 2,
+      Self::U32 => 4,
+    }
+  }
+
+  /// Largest value a cell can hold, which is also -1
+  pub fn max(self) -> u32 {
+    match self {
+      Self::U8 => u8::MAX as u32,
+      Self::U16 => u16::MAX as u32,
+      Self::U32 => u32::MAX,
+    }
+  }
+
+  /// Truncate a value to the cell width
+  pub fn wrap(self, value: u32) -> u32 {
+    value & self.max()
+  }
+
+  /// Truncate an increment to the cell width, as the signed value closest to 0
+  pub fn wrap_inc(self, n: i32) -> i32 {
+    match self {
+      Self::U8 => n as i8 as i32,
+      Self::U16 => n as i16 as i32,
+      Self::U32 => n,
+    }
+  }
+}
+
+impl std::str::FromStr for CellWidth {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "8" => Ok(Self::U8),
+      "16" => Ok(Self::U16),
+      "32" => Ok(Self::U32),
+      _ => Err(format!("invalid cell width \"{s}\" (expected 8, 16 or 32)")),
+    }
+  }
+}
+
+/// Values are always wrapped to the [`CellWidth`] the tree was parsed with
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Effect {
-  CellInc(i16),
-  CellSet(u8),
+  CellInc(i32),
+  CellSet(u32),
+  /// Add the value of cell `src` *at the start of the unit*, multiplied by `factor`
+  MulAdd { src: isize, factor: i32 },
   Output,
   Input,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct BfUnit {
+  /// Effects on each cell, in program order\
+  /// Effects on different cells are independent, except for I/O (see `io_order`)
   pub effects: HashMap<isize, Vec<Effect>>,
   pub ptr_offset: isize,
+  /// Cells of all Output/Input effects, in program order\
+  /// The n-th occurrence of a cell here is the n-th I/O effect in `effects` for that cell
+  pub io_order: Vec<isize>,
+  /// Source code the unit was built from, `None` if it doesn't contain any instructions\
+  /// Units fused by the optimizer cover the source of all of them
+  pub span: Option<Span>,
+}
+
+impl BfUnit {
+  /// Extend the span of the unit to also cover `span`
+  fn include(&mut self, span: Option<Span>) {
+    self.span = match (self.span, span) {
+      (Some(a), Some(b)) => Some(a.merge(b)),
+      (a, b) => a.or(b),
+    };
+  }
+
+  pub fn has_mul_add(&self) -> bool {
+    self.effects.values().flatten().any(|effect| matches!(effect, Effect::MulAdd { .. }))
+  }
+
+  /// Effects of the unit along with their cell, in an order they can be applied in\
+  /// I/O happens in program order, along with the effects on its cell leading up to it.
+  /// The rest follow cell by cell, with the cell the data pointer moves to last
+  pub fn ordered_effects(&self) -> Vec<(isize, Effect)> {
+    let mut ordered = vec![];
+    let mut applied: HashMap<isize, usize> = HashMap::new();
+    for &cell in &self.io_order {
+      let effects = &self.effects[&cell];
+      let start = applied.get(&cell).copied().unwrap_or(0);
+      let len = effects[start..].iter()
+        .position(|effect| matches!(effect, Effect::Output | Effect::Input))
+        .expect("io_order doesn't match the unit effects") + 1;
+      ordered.extend(effects[start..start + len].iter().map(|&effect| (cell, effect)));
+      applied.insert(cell, start + len);
+    }
+    let cells = self.effects.keys().copied().sorted_by_key(|&cell| (self.ptr_offset != 0 && cell == self.ptr_offset, cell));
+    for cell in cells {
+      let start = applied.get(&cell).copied().unwrap_or(0);
+      ordered.extend(self.effects[&cell][start..].iter().map(|&effect| (cell, effect)));
+    }
+    ordered
+  }
+
+  /// Whether this unit is exactly what a clear or multiply loop gets optimized into,\
+  /// meaning that it's a no-op if the current cell is already 0\
+  /// The body of an empty loop doesn't count, since `[]` never clears anything
+  fn is_cleared_loop(&self) -> bool {
+    let clears = self.effects.get(&0).is_some_and(|effects| effects[..] == [Effect::CellSet(0)]);
+    clears && self.ptr_offset == 0 && self.effects.iter().all(|(&cell, effects)| {
+      match effects[..] {
+        [Effect::CellSet(0)] => cell == 0,
+        [Effect::MulAdd { src: 0, .. }] => cell != 0,
+        _ => false,
+      }
+    })
+  }
 }
 
 #[derive(Clone, Debug)]
 pub enum BfOpBlock {
-  Master(Vec<Rc<RefCell<BfOpBlock>>>),
-  Loop(Vec<Rc<RefCell<BfOpBlock>>>),
+  Master(Vec<BfOpBlock>),
+  /// Loop body, and the span from `[` to `]`
+  Loop(Vec<BfOpBlock>, Span),
   Unit(BfUnit),
+  /// Move the pointer by `stride` until a zero cell is found, aka `[>]` or `[<<]`
+  Scan { stride: isize, span: Span },
 }
 
-fn parse_tree_unoptimized(code: &str) -> Rc<RefCell<BfOpBlock>> {
-  let master = Rc::new(RefCell::new(BfOpBlock::Master(vec![])));
-  let mut stack = vec![];
-  let mut current = Rc::clone(&master);
-  let mut unit = BfUnit::default();
+impl BfOpBlock {
+  /// Source code the block was built from, see [`BfUnit::span`]
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      Self::Master(_) => None,
+      Self::Loop(_, span) | Self::Scan { span, .. } => Some(*span),
+      Self::Unit(unit) => unit.span,
+    }
+  }
 
-  let push_unit = |current: &mut BfOpBlock, unit: BfUnit| {
-    match current {
-      BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks) => {
-        blocks.push(Rc::new(RefCell::new(BfOpBlock::Unit(unit))));
-      },
-      _ => unreachable!()
+  /// Replace the span of a block optimized out of a bigger one, since it now stands for all of it
+  fn set_span(&mut self, new_span: Span) {
+    match self {
+      Self::Master(_) => (),
+      Self::Loop(_, span) | Self::Scan { span, .. } => *span = new_span,
+      Self::Unit(unit) => unit.span = Some(new_span),
     }
-  };
+  }
+}
+
+/// Location of a piece of source code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+  /// Byte offset of the first character
+  pub start: usize,
+  /// Byte offset past the last character
+  pub end: usize,
+  /// 1-based line of the first character
+  pub line: usize,
+  /// 1-based column (in characters) of the first character
+  pub column: usize,
+}
+
+impl Span {
+  /// Smallest span covering both spans
+  pub fn merge(self, other: Span) -> Span {
+    let first = if other.start < self.start { other } else { self };
+    Span { end: self.end.max(other.end), ..first }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyntaxErrorKind {
+  /// `]` without a matching `[`
+  UnmatchedClose,
+  /// `[` that is never closed
+  UnclosedOpen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+  pub kind: SyntaxErrorKind,
+  pub span: Span,
+}
+
+impl fmt::Display for SyntaxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      SyntaxErrorKind::UnmatchedClose => write!(f, "unmatched `]`")?,
+      SyntaxErrorKind::UnclosedOpen => write!(f, "unclosed `[`")?,
+    }
+    write!(f, " at {}:{}", self.span.line, self.span.column)
+  }
+}
+
+/// All syntax errors found in the source code, ordered by position
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+  pub errors: Vec<SyntaxError>,
+}
 
-  for token in code.chars() {
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.errors.iter().join(", "))
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse brainfuck source code into a tree of single-instruction effects, without optimizing it\
+/// Cell arithmetic in the tree does not depend on the cell width
+pub fn parse_tree_unoptimized(code: &str) -> Result<BfOpBlock, ParseError> {
+  let mut errors = vec![];
+  //Parent block children and the span of the `[` that opened the current loop
+  let mut stack: Vec<(Vec<BfOpBlock>, Span)> = vec![];
+  let (mut line, mut column) = (1, 1);
+  let mut current = vec![];
+  let mut unit = BfUnit::default();
+
+  for (position, token) in code.char_indices() {
+    let span = Span {
+      start: position,
+      end: position + token.len_utf8(),
+      line, column,
+    };
+    if token == '\n' {
+      line += 1;
+      column = 1;
+    } else {
+      column += 1;
+    }
+    if matches!(token, '-' | '+' | '<' | '>' | ',' | '.') {
+      unit.include(Some(span));
+    }
     match token {
       '-' | '+' => {
         let change = match token {
@@ -64,95 +271,129 @@ fn parse_tree_unoptimized(code: &str) -> Rc<RefCell<BfOpBlock>> {
           .entry(unit.ptr_offset)
           .or_insert(vec![])
           .push(Effect::Input);
+        unit.io_order.push(unit.ptr_offset);
       },
       '.' => {
         unit.effects
           .entry(unit.ptr_offset)
           .or_insert(vec![])
           .push(Effect::Output);
+        unit.io_order.push(unit.ptr_offset);
       },
       '[' => {
-        push_unit(&mut current.borrow_mut(), std::mem::take(&mut unit));
-        let new_current = match &mut *current.borrow_mut() {
-          BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks) => {
-            let loop_block = Rc::new(RefCell::new(BfOpBlock::Loop(vec![])));
-            blocks.push(Rc::clone(&loop_block));
-            loop_block
-          },
-          _ => unreachable!()
-        };
-        stack.push(Rc::clone(&current));
-        current = new_current;
+        current.push(BfOpBlock::Unit(std::mem::take(&mut unit)));
+        stack.push((std::mem::take(&mut current), span));
       },
       ']' => {
-        push_unit(&mut current.borrow_mut(), std::mem::take(&mut unit));
-        current = stack.pop().expect("Unmatched ]");
+        //Unmatched brackets are skipped to report as many errors as possible
+        let Some((mut parent, open)) = stack.pop() else {
+          errors.push(SyntaxError { kind: SyntaxErrorKind::UnmatchedClose, span });
+          continue
+        };
+        current.push(BfOpBlock::Unit(std::mem::take(&mut unit)));
+        parent.push(BfOpBlock::Loop(current, open.merge(span)));
+        current = parent;
       }
       _ => ()
     }
   }
-  push_unit(&mut current.borrow_mut(), unit);
-  master
+  errors.extend(stack.into_iter().map(|(_, span)| {
+    SyntaxError { kind: SyntaxErrorKind::UnclosedOpen, span }
+  }));
+  if !errors.is_empty() {
+    errors.sort_by_key(|error| error.span.start);
+    return Err(ParseError { errors })
+  }
+  current.push(BfOpBlock::Unit(unit));
+  Ok(BfOpBlock::Master(current))
 }
 
 /// Gets called recursively on every Master or Loop block\
 /// Returns true if any changes were made, in which case this function should be called again\
 ///
 /// Panics:\
-///  - If a Unit block is provided\
+///  - If a Unit or Scan block is provided\
 ///  - If it feels like it
-fn optimize_tree_recursive(block: Rc<RefCell<BfOpBlock>>) -> bool {
+fn optimize_tree_recursive(block: &mut BfOpBlock, width: CellWidth) -> bool {
   let mut modified = false;
 
   //Strip away nested loops
+  //[[X]] does the same thing as [X], since the inner loop always exits with the current cell at 0\
+  //This also applies to loops that were already turned into units, like [[-]] or [[->+<]]
   {
-    //TODO
+    if let BfOpBlock::Loop(blocks, span) = block {
+      let strip = match &blocks[..] {
+        [BfOpBlock::Loop(..) | BfOpBlock::Scan { .. }] => true,
+        [BfOpBlock::Unit(unit)] => unit.is_cleared_loop(),
+        _ => false,
+      };
+      if strip {
+        let span = *span;
+        *block = blocks.pop().unwrap();
+        block.set_span(span);
+        //The inner block can't be optimized any further if it's not a loop
+        if !matches!(block, BfOpBlock::Loop(..)) {
+          return true
+        }
+        modified = true;
+      }
+    }
   }
 
-  let mut binding = block.borrow_mut();
-  let blocks = match &mut *binding {
-    BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks) => blocks,
+  let is_master = matches!(block, BfOpBlock::Master(_));
+  let blocks = match block {
+    BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks, _) => blocks,
     _ => unreachable!()
   };
 
   for block in blocks.iter_mut() {
-    match &mut *block.borrow_mut() {
-      BfOpBlock::Master(_) | BfOpBlock::Loop(_) => (),
+    match block {
+      BfOpBlock::Master(_) | BfOpBlock::Loop(..) | BfOpBlock::Scan { .. } => (),
       BfOpBlock::Unit(unit) => {
         //Optimize block effects
         for (&_, effects) in unit.effects.iter_mut() {
           //Collapse all consecutive CellInc effects into one
+          //I/O effects act as barriers: pending changes are flushed before them
           {
             let mut opt_effects = Vec::with_capacity(effects.len());
             //Cell difference or absolute value in case is_relative is false
-            let mut cell_inc_or_value: i16 = 0;
+            let mut cell_inc_or_value: i32 = 0;
             let mut is_absolute = false;
+            let flush = |opt_effects: &mut Vec<Effect>, cell_inc_or_value: &mut i32, is_absolute: &mut bool| {
+              if *is_absolute {
+                opt_effects.push(Effect::CellSet(width.wrap(*cell_inc_or_value as u32)));
+              } else if *cell_inc_or_value != 0 {
+                opt_effects.push(Effect::CellInc(*cell_inc_or_value));
+              }
+              *cell_inc_or_value = 0;
+              *is_absolute = false;
+            };
             for effect in effects.iter() {
               match effect {
                 Effect::CellInc(n) => {
-                  cell_inc_or_value += *n
+                  cell_inc_or_value = width.wrap_inc(cell_inc_or_value.wrapping_add(*n))
                 },
                 Effect::CellSet(v) => {
-                  cell_inc_or_value = *v as i16;
+                  cell_inc_or_value = *v as i32;
                   is_absolute = true;
                 },
                 Effect::Output => {
-                  if is_absolute {
-                    opt_effects.push(Effect::CellSet(cell_inc_or_value as u8));
-                  } else if cell_inc_or_value != 0 {
-                    opt_effects.push(Effect::CellInc(cell_inc_or_value));
-                    cell_inc_or_value = 0;
-                  }
+                  flush(&mut opt_effects, &mut cell_inc_or_value, &mut is_absolute);
                   opt_effects.push(Effect::Output);
                 },
-                Effect::Input => unimplemented!(),
+                Effect::Input => {
+                  //Anything written to the cell before the input is overwritten anyway,\
+                  //unless we hit EOF, so it still has to be flushed
+                  flush(&mut opt_effects, &mut cell_inc_or_value, &mut is_absolute);
+                  opt_effects.push(Effect::Input);
+                },
+                &Effect::MulAdd { src, factor } => {
+                  flush(&mut opt_effects, &mut cell_inc_or_value, &mut is_absolute);
+                  opt_effects.push(Effect::MulAdd { src, factor });
+                },
               }
             }
-            if is_absolute {
-              opt_effects.push(Effect::CellSet(cell_inc_or_value as u8));
-            } else if cell_inc_or_value != 0 {
-              opt_effects.push(Effect::CellInc(cell_inc_or_value));
-            }
+            flush(&mut opt_effects, &mut cell_inc_or_value, &mut is_absolute);
             if *effects != opt_effects {
               modified = true;
             }
@@ -165,7 +406,7 @@ fn optimize_tree_recursive(block: Rc<RefCell<BfOpBlock>>) -> bool {
 
   //Remove empty blocks
   blocks.retain(|block| {
-    match &*block.borrow() {
+    match block {
       BfOpBlock::Unit(unit) => {
         let keep = !unit.effects.is_empty() || unit.ptr_offset != 0;
         if !keep { modified = true }
@@ -177,158 +418,370 @@ fn optimize_tree_recursive(block: Rc<RefCell<BfOpBlock>>) -> bool {
 
   // If the current block is Master or Loop, and there are consecutive Unit blocks,
   // merge them into the first ones, removing the others
-  let mut merge_into: Option<Rc<RefCell<BfOpBlock>>> = None;
-  blocks.retain_mut(|block| {
-    match &mut *block.borrow_mut() {
-      BfOpBlock::Unit(unit) => {
-        if let Some(merge_into) = &merge_into {
-          let BfOpBlock::Unit(merge_into_unit) = &mut *merge_into.borrow_mut() else {
-            unreachable!()
-          };
-          for (&key, key_effects) in &unit.effects {
-            merge_into_unit.effects.entry(key + merge_into_unit.ptr_offset).or_default().extend_from_slice(key_effects);
-          }
-          merge_into_unit.ptr_offset += unit.ptr_offset;
-          modified = true;
-          false
-        } else {
-          merge_into = Some(Rc::clone(block));
-          true
+  //
+  // MulAdd effects read the cells as they were at the start of the unit,
+  // so units containing them can only be merged into, and never into a previous unit
+  let mut merged: Vec<BfOpBlock> = Vec::with_capacity(blocks.len());
+  for block in blocks.drain(..) {
+    match (merged.last_mut(), block) {
+      (Some(BfOpBlock::Unit(merge_into_unit)), BfOpBlock::Unit(unit)) if !unit.has_mul_add() => {
+        for (key, key_effects) in unit.effects {
+          merge_into_unit.effects.entry(key + merge_into_unit.ptr_offset).or_default().extend(key_effects);
         }
+        merge_into_unit.io_order.extend(unit.io_order.iter().map(|&key| key + merge_into_unit.ptr_offset));
+        merge_into_unit.ptr_offset += unit.ptr_offset;
+        merge_into_unit.include(unit.span);
+        modified = true;
       },
-      _ => {
-        merge_into = None;
-        true
-      },
+      (_, block) => merged.push(block),
     }
-  });
+  }
+  *blocks = merged;
 
-  //Drop borrow_mut binding
-  drop(binding);
+  //Remove dead loops, which are never entered since the current cell is known to be 0:
+  // - loops right after another loop, or a unit that leaves the current cell at 0
+  // - loops at the start of the program, where the whole tape is still 0
+  let mut tape_is_zero = is_master;
+  let mut cell_is_zero = is_master;
+  blocks.retain(|block| {
+    if cell_is_zero && matches!(block, BfOpBlock::Loop(..) | BfOpBlock::Scan { .. }) {
+      modified = true;
+      return false
+    }
+    cell_is_zero = match block {
+      BfOpBlock::Loop(..) | BfOpBlock::Scan { .. } => true,
+      BfOpBlock::Unit(unit) => match unit.effects.get(&unit.ptr_offset) {
+        Some(effects) => effects.last() == Some(&Effect::CellSet(0)),
+        None => tape_is_zero,
+      },
+      BfOpBlock::Master(_) => unreachable!(),
+    };
+    tape_is_zero = false;
+    true
+  });
 
   //Now, if the current block is loop and contains a single unit block that:
   // - does not change the pointer position
-  // - only has a *single* effect that either adds or subs an odd value, or sets current cell to zero
+  // - only has a *single* effect on the current cell that either adds or subs an odd value,
+  //   or sets current cell to zero
   //Turn ourself into a Unit block that sets the cell to 0
   //
   //This optimizes away loops like: [-]+++, and with multi-step optimization should reduce\
   //[-]+++ to a single CellSet(3) effect
-  //TODO: expand this optimization to moves, aka [->+<]
-  let mut new_self = None;
-  if let BfOpBlock::Loop(blocks) = &*block.borrow() {
-    if blocks.len() == 1 {
-      if let BfOpBlock::Unit(unit) = &*blocks[0].borrow() {
-        if unit.ptr_offset == 0 && unit.effects.len() == 1 {
-          let (&cell, effects) = unit.effects.iter().next().unwrap();
-          if effects.len() == 1 {
-            let effect = &effects[0];
-            if let Effect::CellInc(n) = effect {
-              if n.abs() % 2 == 1 {
-                //HACK: borrow checker workaround:
-                new_self = Some(BfOpBlock::Unit(BfUnit {
-                  effects: HashMap::from([(cell, vec![Effect::CellSet(0)])]),
-                  ptr_offset: 0,
-                }));
-              }
-            } else if let Effect::CellSet(0) = effect {
-              //TODO: reduce code duplocation
-              new_self = Some(BfOpBlock::Unit(BfUnit {
-                effects: HashMap::from([(cell, vec![Effect::CellSet(0)])]),
-                ptr_offset: 0,
-              }));
-            }
-          }
+  //
+  //If the loop changes the current cell by exactly 1 and only adds constants to other cells,
+  //it runs exactly (+/-)p[0] times, so it can be turned into a sequence of MulAdd effects instead,
+  //aka [->+<] or [->++>+++<<]
+  //
+  //Loops that only move the pointer are turned into Scan blocks
+  if let BfOpBlock::Loop(blocks, span) = block {
+    let span = *span;
+    if let [BfOpBlock::Unit(unit)] = &blocks[..] {
+      if unit.effects.is_empty() && unit.ptr_offset != 0 {
+        *block = BfOpBlock::Scan { stride: unit.ptr_offset, span };
+        return true
+      }
+      if unit.ptr_offset == 0 && !unit.has_mul_add() {
+        let control = unit.effects.get(&0).map(|effects| &effects[..]);
+        let clears_cell = unit.effects.len() == 1 && match control {
+          Some(&[Effect::CellInc(n)]) => n % 2 != 0,
+          Some(&[Effect::CellSet(0)]) => true,
+          _ => false,
+        };
+        let mul_add_step = match control {
+          Some(&[Effect::CellInc(n @ (1 | -1))]) => Some(n),
+          _ => None,
+        };
+        let all_adds = unit.effects.iter().all(|(&cell, effects)| {
+          cell == 0 || matches!(effects[..], [Effect::CellInc(_)])
+        });
+        if clears_cell {
+          *block = BfOpBlock::Unit(BfUnit {
+            effects: HashMap::from([(0, vec![Effect::CellSet(0)])]),
+            ptr_offset: 0,
+            io_order: vec![],
+            span: Some(span),
+          });
+          //We're a Unit block now, nothing left to optimize here
+          return true
+        } else if let (Some(step), true) = (mul_add_step, all_adds) {
+          //Each iteration adds `n` to the cell, and there are `-p[0] / step` iterations
+          let mut effects: HashMap<isize, Vec<Effect>> = unit.effects.iter()
+            .filter(|(&cell, _)| cell != 0)
+            .map(|(&cell, effects)| {
+              let [Effect::CellInc(n)] = effects[..] else { unreachable!() };
+              (cell, vec![Effect::MulAdd { src: 0, factor: width.wrap_inc(n.wrapping_mul(-step)) }])
+            })
+            .collect();
+          effects.insert(0, vec![Effect::CellSet(0)]);
+          *block = BfOpBlock::Unit(BfUnit { effects, ptr_offset: 0, io_order: vec![], span: Some(span) });
+          return true
         }
       }
     }
   }
-  if let Some(new_self) = new_self {
-    *block.borrow_mut() = new_self;
-    modified = true;
-  }
 
-  {
-    let binding = block.borrow();
-    let blocks = match &*binding {
-      BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks) => blocks,
-      //Since code above CAN in fact turn us into a Unit block, we need to just return here
-      _ => return true, //return modified
-    };
-    for block in blocks.iter() {
-      if matches!(&*block.borrow(), BfOpBlock::Unit(_)) {
-        continue;
-      }
-      if optimize_tree_recursive(Rc::clone(block)) {
-        modified = true;
-      }
+  let (BfOpBlock::Master(blocks) | BfOpBlock::Loop(blocks, _)) = block else {
+    unreachable!()
+  };
+  for block in blocks.iter_mut() {
+    if matches!(block, BfOpBlock::Unit(_) | BfOpBlock::Scan { .. }) {
+      continue;
+    }
+    if optimize_tree_recursive(block, width) {
+      modified = true;
     }
   }
 
   modified
 }
 
-pub fn parse_tree(code: &str) -> Rc<RefCell<BfOpBlock>> {
-  let block = parse_tree_unoptimized(code);
-  let mut iterations = 0;
-  while optimize_tree_recursive(Rc::clone(&block)) {
-    iterations += 1;
-  }
-  println!("Optimized in {} iteration(s)", iterations);
-  block
+/// Parse and optimize brainfuck source code, for cells of the given width
+pub fn parse_tree(code: &str, width: CellWidth) -> Result<BfOpBlock, ParseError> {
+  let mut block = parse_tree_unoptimized(code)?;
+  while optimize_tree_recursive(&mut block, width) {}
+  Ok(block)
 }
 
 /// Hacky function to pretty-print bf op blocks
-pub fn debug_print_tree(block: Rc<RefCell<BfOpBlock>>, indent: usize) {
-  let print_ident = |indent: usize| {
+pub fn debug_print_tree<W: Write>(block: &BfOpBlock, indent: usize, out: &mut W) -> io::Result<()> {
+  let print_ident = |out: &mut W, indent: usize| -> io::Result<()> {
     for _ in 0..indent {
-      print!("  ");
+      write!(out, "  ")?;
     }
+    Ok(())
   };
-  match &*block.borrow() {
+  match block {
     BfOpBlock::Master(blocks) => {
       for block in blocks {
-        debug_print_tree(Rc::clone(block), indent);
+        debug_print_tree(block, indent, out)?;
       }
     },
-    BfOpBlock::Loop(blocks) => {
-      print_ident(indent);
-      println!("loop {{");
+    BfOpBlock::Loop(blocks, _) => {
+      print_ident(out, indent)?;
+      writeln!(out, "loop {{")?;
       for block in blocks {
-        debug_print_tree(Rc::clone(block), indent + 1);
+        debug_print_tree(block, indent + 1, out)?;
       }
-      print_ident(indent);
-      println!("}}");
+      print_ident(out, indent)?;
+      writeln!(out, "}}")?;
+    },
+    BfOpBlock::Scan { stride, .. } => {
+      print_ident(out, indent)?;
+      writeln!(out, "scan {stride:+};")?;
     },
     BfOpBlock::Unit(unit) => {
-      print_ident(indent);
-      println!("unit {{");
+      print_ident(out, indent)?;
+      writeln!(out, "unit {{")?;
       for (offset, effects) in unit.effects.iter().sorted_by_key(|x| *x.0) {
-        print_ident(indent + 1);
-        print!("p[{offset:+}]: ");
+        print_ident(out, indent + 1)?;
+        write!(out, "p[{offset:+}]: ")?;
         for effect in effects {
           match effect {
             Effect::CellInc(change) => {
-              print!("{change:+}; ");
+              write!(out, "{change:+}; ")?;
             },
             Effect::CellSet(value) => {
-              print!("={value};");
+              write!(out, "={value};")?;
+            },
+            Effect::MulAdd { src, factor } => {
+              write!(out, "+=p[{src:+}]*{factor}; ")?;
             },
             Effect::Output => {
-              print!("output; ");
+              write!(out, "output; ")?;
             },
             Effect::Input => {
-              print!("input; ");
+              write!(out, "input; ")?;
             }
           }
         }
-        println!();
+        writeln!(out)?;
+      }
+      if unit.io_order.iter().unique().count() > 1 {
+        print_ident(out, indent + 1)?;
+        writeln!(out, "io: {};", unit.io_order.iter().map(|offset| format!("p[{offset:+}]")).join(", "))?;
       }
       if unit.ptr_offset != 0 {
-        print_ident(indent + 1);
-        println!("p: {:+};", unit.ptr_offset);
+        print_ident(out, indent + 1)?;
+        writeln!(out, "p: {:+};", unit.ptr_offset)?;
       }
-      print_ident(indent);
-      println!("}}");
+      print_ident(out, indent)?;
+      writeln!(out, "}}")?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use SyntaxErrorKind::*;
+
+  /// Syntax errors of `code`, as kind, byte offset, line and column
+  fn errors(code: &str) -> Vec<(SyntaxErrorKind, usize, usize, usize)> {
+    let err = parse_tree_unoptimized(code).unwrap_err();
+    assert_eq!(parse_tree(code, CellWidth::U8).unwrap_err(), err);
+    err.errors.iter().map(|error| {
+      //Every error is about a single bracket
+      assert_eq!(error.span.end, error.span.start + 1);
+      (error.kind, error.span.start, error.span.line, error.span.column)
+    }).collect()
+  }
+
+  #[test]
+  fn unmatched_close() {
+    assert_eq!(errors("]"), [(UnmatchedClose, 0, 1, 1)]);
+    assert_eq!(errors("[]]"), [(UnmatchedClose, 2, 1, 3)]);
+  }
+
+  #[test]
+  fn unclosed_open() {
+    assert_eq!(errors("[["), [(UnclosedOpen, 0, 1, 1), (UnclosedOpen, 1, 1, 2)]);
+    assert_eq!(errors("[[]"), [(UnclosedOpen, 0, 1, 1)]);
+  }
+
+  #[test]
+  fn errors_in_source_order() {
+    assert_eq!(errors("[ ] ] ["), [(UnmatchedClose, 4, 1, 5), (UnclosedOpen, 6, 1, 7)]);
+    assert_eq!(errors("[\n+]]\n [["), [(UnmatchedClose, 4, 2, 3), (UnclosedOpen, 7, 3, 2), (UnclosedOpen, 8, 3, 3)]);
+  }
+
+  /// Optimize the first loop of `code` on its own, outside of the program
+  fn optimize_loop(code: &str) -> BfOpBlock {
+    let BfOpBlock::Master(blocks) = parse_tree_unoptimized(code).unwrap() else { unreachable!() };
+    let mut block = blocks.into_iter().find(|block| matches!(block, BfOpBlock::Loop(..))).unwrap();
+    while matches!(block, BfOpBlock::Loop(..)) && optimize_tree_recursive(&mut block, CellWidth::U8) {}
+    block
+  }
+
+  fn unit(block: &BfOpBlock) -> &BfUnit {
+    match block {
+      BfOpBlock::Unit(unit) => unit,
+      _ => panic!("expected a unit, found {block:?}"),
     }
   }
+
+  /// Effects of a unit, sorted by cell
+  fn effects(unit: &BfUnit) -> Vec<(isize, Vec<Effect>)> {
+    unit.effects.iter().map(|(&cell, effects)| (cell, effects.clone())).sorted_by_key(|&(cell, _)| cell).collect()
+  }
+
+  fn span(start: usize, end: usize) -> Span {
+    Span { start, end, line: 1, column: start + 1 }
+  }
+
+  #[test]
+  fn mul_add_loops() {
+    let block = optimize_loop("[->++>+++<<]");
+    let mul_add = unit(&block);
+    assert_eq!(effects(mul_add), [
+      (0, vec![Effect::CellSet(0)]),
+      (1, vec![Effect::MulAdd { src: 0, factor: 2 }]),
+      (2, vec![Effect::MulAdd { src: 0, factor: 3 }]),
+    ]);
+    assert_eq!((mul_add.ptr_offset, mul_add.span), (0, Some(span(0, 12))));
+    //Counting up runs 256 - p[0] times, which is the same as subtracting p[0]
+    assert_eq!(effects(unit(&optimize_loop("[+<-->]"))), [
+      (-1, vec![Effect::MulAdd { src: 0, factor: 2 }]),
+      (0, vec![Effect::CellSet(0)]),
+    ]);
+    //Steps other than 1 may never reach 0, and loops doing anything but adding can't be turned into multiplications
+    for code in ["[-->+<]", "[->+>[-]<<]", "[->.<]", "[->+<<]"] {
+      assert!(matches!(optimize_loop(code), BfOpBlock::Loop(..)), "{code}");
+    }
+  }
+
+  #[test]
+  fn clear_loops() {
+    for code in ["[-]", "[+]", "[---]"] {
+      assert_eq!(effects(unit(&optimize_loop(code))), [(0, vec![Effect::CellSet(0)])], "{code}");
+    }
+    //Even steps may skip over 0
+    assert!(matches!(optimize_loop("[--]"), BfOpBlock::Loop(..)));
+    //Within a program, the clear is folded into what comes after it
+    let BfOpBlock::Master(blocks) = parse_tree(",[-]+++", CellWidth::U8).unwrap() else { unreachable!() };
+    assert_eq!(effects(unit(&blocks[0])), [(0, vec![Effect::Input, Effect::CellSet(3)])]);
+  }
+
+  /// Blocks of the optimized program
+  fn optimize(code: &str) -> Vec<BfOpBlock> {
+    let BfOpBlock::Master(blocks) = parse_tree(code, CellWidth::U8).unwrap() else { unreachable!() };
+    blocks
+  }
+
+  #[test]
+  fn nested_loops() {
+    //The inner loop always leaves the cell at 0, so the outer one never repeats
+    let block = optimize_loop("[[-]]");
+    assert_eq!(effects(unit(&block)), [(0, vec![Effect::CellSet(0)])]);
+    assert_eq!(unit(&block).span, Some(span(0, 5)));
+    let BfOpBlock::Loop(blocks, loop_span) = optimize_loop("[[[.-]]]") else { panic!("expected a loop") };
+    assert_eq!(loop_span, span(0, 8));
+    assert!(matches!(blocks[..], [BfOpBlock::Unit(_)]), "{blocks:?}");
+    let scan = optimize_loop("[[>]]");
+    assert!(matches!(scan, BfOpBlock::Scan { stride: 1, span: scan_span } if scan_span == span(0, 5)), "{scan:?}");
+    //Anything else in the outer loop keeps it around
+    assert!(matches!(optimize_loop("[[-]+]"), BfOpBlock::Loop(..)));
+    //Empty loops never exit once entered, so they have to stay
+    assert!(matches!(optimize_loop("[[]]"), BfOpBlock::Loop(..)));
+    for code in ["+[]", ",[]", "+[[]]"] {
+      let blocks = optimize(code);
+      assert!(matches!(blocks[..], [BfOpBlock::Unit(_), BfOpBlock::Loop(..)]), "{code}: {blocks:?}");
+    }
+  }
+
+  #[test]
+  fn dead_loops() {
+    //Loops at the start of the program and right after a clear are never entered
+    assert!(optimize("[-][+]").is_empty());
+    let blocks = optimize(",[-][+]");
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(effects(unit(&blocks[0])), [(0, vec![Effect::Input, Effect::CellSet(0)])]);
+    //Same for loops right after another loop
+    let blocks = optimize(",[.,][.]");
+    assert!(matches!(blocks[..], [BfOpBlock::Unit(_), BfOpBlock::Loop(..)]), "{blocks:?}");
+    //Cells the program never touched are still 0 as well
+    assert_eq!(optimize(",[-]>[.]").len(), 1);
+    //But not when the cell may be nonzero again
+    let blocks = optimize(",[.,]+[.-]");
+    assert!(matches!(blocks[..], [BfOpBlock::Unit(_), BfOpBlock::Loop(..), BfOpBlock::Unit(_), BfOpBlock::Loop(..)]), "{blocks:?}");
+    let blocks = optimize(",>,[-]<[.]");
+    assert!(matches!(blocks[..], [BfOpBlock::Unit(_), BfOpBlock::Loop(..)]), "{blocks:?}");
+  }
+
+  #[test]
+  fn io_order() {
+    let blocks = optimize("+>.<.");
+    let io = unit(&blocks[0]);
+    assert_eq!(io.io_order, [1, 0]);
+    assert_eq!(effects(io), [(0, vec![Effect::CellInc(1), Effect::Output]), (1, vec![Effect::Output])]);
+    //Merging units keeps the order across them, relative to where each unit starts
+    let blocks = optimize(",>,[-]<.>>.");
+    assert_eq!(unit(&blocks[0]).io_order, [0, 1, 0, 2]);
+  }
+
+  #[test]
+  fn ordered_effects() {
+    let blocks = optimize("+>.<.>>+<-<<+>>>");
+    assert_eq!(unit(&blocks[0]).ordered_effects(), [
+      //I/O along with what leads up to it
+      (1, Effect::Output), (0, Effect::CellInc(1)), (0, Effect::Output),
+      //Then the rest, with the cell the pointer moves to last
+      (-1, Effect::CellInc(1)), (1, Effect::CellInc(-1)), (2, Effect::CellInc(1)),
+    ]);
+  }
+
+  #[test]
+  fn folding_stops_at_io() {
+    let blocks = optimize("++.+-+.,+++,[-]---.");
+    assert_eq!(effects(unit(&blocks[0])), [(0, vec![
+      Effect::CellInc(2), Effect::Output,
+      Effect::CellInc(1), Effect::Output,
+      Effect::Input, Effect::CellInc(3), Effect::Input,
+      Effect::CellSet(253), Effect::Output,
+    ])]);
+    //Changes that cancel out leave nothing behind
+    let blocks = optimize(",+-.");
+    assert_eq!(effects(unit(&blocks[0])), [(0, vec![Effect::Input, Effect::Output])]);
+  }
 }
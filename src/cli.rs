@@ -0,0 +1,254 @@
+use std::{env, path::PathBuf, str::FromStr};
+use brainfuck_jit::{brainfuck::{SyntaxError, SyntaxErrorKind}, elf, AsmSyntax, CellWidth, EofMode, FlushPolicy};
+
+pub const USAGE: &str = "\
+Usage: beefk [run|build] [OPTIONS] <FILE | -e CODE | ->
+
+Commands:
+  run                 Compile and run a program (default)
+  build               Compile a program to a standalone Linux executable
+
+Options:
+  -e, --eval <CODE>   Run CODE instead of reading a file
+  -o, --output <PATH> Where build writes its output [default: named after FILE]
+      --emit <KIND>   Build an executable, object file or assembly source: exe, obj or asm [default: exe]
+      --symbol <NAME> Name of the function exported by obj and asm [default: bf_main]
+      --syntax <S>    Assembly syntax on x86_64: intel or att [default: intel]
+      --dump-ir       Print the optimized IR tree
+      --dump-bfil     Print the linear bfil IR
+      --dump-hex      Print the generated machine code
+      --dump-asm      Print the disassembled code, annotated with the IR and source
+      --dump-tape <N> Print the first N cells of the tape after execution
+      --time          Print the execution time
+      --tape-size <N> Number of cells on the tape [default: 65536]
+      --tape-max <N>  Let the tape grow on demand, up to N cells
+      --cell-bits <N> Cell size in bits: 8, 16 or 32 [default: 8]
+      --eof-mode <M>  Cell value after reading EOF: unchanged, 0 or 255 [default: unchanged]
+      --flush <P>     When to flush program output: byte, line or full [default: full]
+      --bounds-checks Check tape bounds in generated code, for untrusted programs
+      --interpret     Run the program on the reference interpreter instead of compiling it
+      --no-optimize   Skip the optimizer, running the program as written
+  -h, --help          Print help
+  -V, --version       Print version
+
+Use `-` as FILE to read the program from stdin.
+Diagnostics are printed to stderr, stdout only receives program output.";
+
+/// What `build` produces
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Emit {
+  /// Standalone executable
+  #[default]
+  Exe,
+  /// Relocatable object file
+  Obj,
+  /// Assembly source
+  Asm,
+}
+
+impl FromStr for Emit {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "exe" => Ok(Self::Exe),
+      "obj" => Ok(Self::Obj),
+      "asm" => Ok(Self::Asm),
+      _ => Err(format!("invalid output kind \"{s}\" (expected exe, obj or asm)")),
+    }
+  }
+}
+
+pub enum Source {
+  File(PathBuf),
+  Inline(String),
+  Stdin,
+}
+
+pub struct RunArgs {
+  pub source: Source,
+  /// Output path for `build`
+  pub output: Option<PathBuf>,
+  pub emit: Emit,
+  pub symbol: String,
+  pub syntax: AsmSyntax,
+  pub dump_ir: bool,
+  pub dump_bfil: bool,
+  pub dump_hex: bool,
+  pub dump_asm: bool,
+  pub dump_tape: Option<usize>,
+  pub time: bool,
+  pub tape_size: usize,
+  pub tape_limit: Option<usize>,
+  pub cell_width: CellWidth,
+  pub eof_mode: EofMode,
+  pub flush_policy: FlushPolicy,
+  pub bounds_checks: bool,
+  pub interpret: bool,
+  pub optimize: bool,
+}
+
+pub enum Command {
+  Run(RunArgs),
+  /// Like `run`, but writes a standalone executable to `output` instead of running the program
+  Build(RunArgs),
+  Help,
+  Version,
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+  T::Err: ToString
+{
+  let value = value.ok_or_else(|| format!("{flag} requires a value"))?;
+  value.parse().map_err(|err: T::Err| format!("invalid value for {flag}: {}", err.to_string()))
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+  let mut args = args.by_ref().peekable();
+  let build = args.peek().map(String::as_str) == Some("build");
+  if build || args.peek().map(String::as_str) == Some("run") {
+    args.next();
+  }
+
+  let mut source = None;
+  let mut run_args = RunArgs {
+    source: Source::Stdin,
+    output: None,
+    emit: Emit::default(),
+    symbol: "bf_main".into(),
+    syntax: AsmSyntax::default(),
+    dump_ir: false,
+    dump_bfil: false,
+    dump_hex: false,
+    dump_asm: false,
+    dump_tape: None,
+    time: false,
+    tape_size: 0x10000,
+    tape_limit: None,
+    cell_width: CellWidth::default(),
+    eof_mode: EofMode::default(),
+    flush_policy: FlushPolicy::default(),
+    bounds_checks: false,
+    interpret: false,
+    optimize: true,
+  };
+  let mut set_source = |new_source: Source| {
+    match source.replace(new_source) {
+      None => Ok(()),
+      Some(_) => Err("only one program can be specified".to_string()),
+    }
+  };
+
+  while let Some(arg) = args.next() {
+    //Support both `--flag value` and `--flag=value`
+    let (flag, mut value) = match arg.split_once('=') {
+      Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+      _ => (arg, None),
+    };
+    let mut value = || value.take().or_else(|| args.next());
+    match flag.as_str() {
+      "-h" | "--help" => return Ok(Command::Help),
+      "-V" | "--version" => return Ok(Command::Version),
+      "-e" | "--eval" => set_source(Source::Inline(parse_value(&flag, value())?))?,
+      "-o" | "--output" => run_args.output = Some(parse_value(&flag, value())?),
+      "--emit" => run_args.emit = parse_value(&flag, value())?,
+      "--symbol" => run_args.symbol = parse_value(&flag, value())?,
+      "--syntax" => run_args.syntax = parse_value(&flag, value())?,
+      "--dump-ir" => run_args.dump_ir = true,
+      "--dump-bfil" => run_args.dump_bfil = true,
+      "--dump-hex" => run_args.dump_hex = true,
+      "--dump-asm" => run_args.dump_asm = true,
+      "--dump-tape" => run_args.dump_tape = Some(parse_value(&flag, value())?),
+      "--time" => run_args.time = true,
+      "--tape-size" => run_args.tape_size = parse_value(&flag, value())?,
+      "--tape-max" => run_args.tape_limit = Some(parse_value(&flag, value())?),
+      "--cell-bits" => run_args.cell_width = parse_value(&flag, value())?,
+      "--eof-mode" => run_args.eof_mode = parse_value(&flag, value())?,
+      "--flush" => run_args.flush_policy = parse_value(&flag, value())?,
+      "--bounds-checks" => run_args.bounds_checks = true,
+      "--interpret" => run_args.interpret = true,
+      "--no-optimize" => run_args.optimize = false,
+      "-" => set_source(Source::Stdin)?,
+      _ if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+      _ => set_source(Source::File(flag.into()))?,
+    }
+  }
+
+  run_args.source = source.ok_or("no program specified")?;
+  if run_args.tape_size == 0 {
+    return Err("tape size must be greater than 0".into())
+  }
+  if !build {
+    if run_args.output.is_some() {
+      return Err("--output can only be used with build".into())
+    }
+    return Ok(Command::Run(run_args))
+  }
+  if !elf::is_valid_symbol(&run_args.symbol) {
+    return Err(format!("invalid symbol name \"{}\" (expected a C identifier)", run_args.symbol))
+  }
+  let run_only = [
+    ("--time", run_args.time),
+    ("--dump-tape", run_args.dump_tape.is_some()),
+    ("--tape-max", run_args.tape_limit.is_some()),
+    ("--bounds-checks", run_args.bounds_checks),
+    ("--interpret", run_args.interpret),
+  ];
+  if let Some((flag, _)) = run_only.iter().find(|(_, set)| *set) {
+    return Err(format!("{flag} can't be used with build"))
+  }
+  if run_args.output.is_none() {
+    //Like rustc, put the output in the current directory, named after the source file
+    let extension = match run_args.emit {
+      Emit::Exe => "",
+      Emit::Obj => "o",
+      Emit::Asm => "s",
+    };
+    let output = match &run_args.source {
+      Source::File(path) => path.file_stem().map(|stem| PathBuf::from(stem).with_extension(extension)),
+      _ => None,
+    };
+    let output = output.filter(|output| !matches!(&run_args.source, Source::File(path) if path == output));
+    run_args.output = Some(output.ok_or("build needs --output unless it can be derived from the file name")?);
+  }
+  Ok(Command::Build(run_args))
+}
+
+pub fn args() -> Result<Command, String> {
+  parse_args(env::args().skip(1))
+}
+
+impl Source {
+  /// Name used to refer to the source in diagnostics
+  pub fn name(&self) -> String {
+    match self {
+      Self::File(path) => path.display().to_string(),
+      Self::Inline(_) => "<inline>".into(),
+      Self::Stdin => "<stdin>".into(),
+    }
+  }
+}
+
+/// Render a syntax error rustc-style, with a caret under the offending character
+pub fn render_syntax_error(error: &SyntaxError, code: &str, source_name: &str) -> String {
+  let (message, label) = match error.kind {
+    SyntaxErrorKind::UnmatchedClose => ("unmatched `]`", "no matching `[`"),
+    SyntaxErrorKind::UnclosedOpen => ("unclosed `[`", "this loop is never closed"),
+  };
+  let span = error.span;
+  let line_start = code[..span.start].rfind('\n').map_or(0, |idx| idx + 1);
+  let line = code[line_start..].split('\n').next().unwrap().trim_end_matches('\r');
+  //Keep tabs in the padding so that the caret lines up with the source line
+  let padding: String = code[line_start..span.start].chars()
+    .map(|c| if c == '\t' { '\t' } else { ' ' })
+    .collect();
+  let gutter = " ".repeat(span.line.to_string().len());
+  format!(
+    "error: {message}\n\
+     {gutter}--> {source_name}:{}:{}\n\
+     {gutter} |\n\
+     {} | {line}\n\
+     {gutter} | {padding}^ {label}\n",
+    span.line, span.column, span.line,
+  )
+}
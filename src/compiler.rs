@@ -1,28 +1,353 @@
-use std::{rc::Rc, cell::RefCell};
-use crate::brainfuck::BfOpBlock;
+use std::ops::Range;
+use crate::{bfil::{self, Op}, brainfuck::{CellWidth, Span}, io::RunStatus};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Target {
-  Extern
+  /// `extern "C" fn(tape: *mut u8) -> *mut u8`, doing I/O with raw syscalls on stdin and stdout\
+  /// Returns the final data pointer
+  Extern,
+  /// `extern "C" fn(tape: *mut u8, ctx: *mut RunContext) -> *mut u8`,
+  /// doing I/O through the callbacks in [`RunContext`](crate::io::RunContext)\
+  /// Returns the final data pointer
+  Host,
+}
+
+/// What happens to the cell when `,` hits the end of input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofMode {
+  /// Leave the cell unchanged
+  #[default]
+  Unchanged,
+  /// Set the cell to 0
+  Zero,
+  /// Set the cell to -1 (255 for 8-bit cells)
+  MinusOne,
+}
+
+impl std::str::FromStr for EofMode {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "unchanged" => Ok(Self::Unchanged),
+      "0" | "zero" => Ok(Self::Zero),
+      "255" | "-1" => Ok(Self::MinusOne),
+      _ => Err(format!("invalid eof mode \"{s}\" (expected unchanged, 0 or 255)")),
+    }
+  }
+}
+
+/// When buffered output is written out, besides before any input and at program exit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+  /// After every byte
+  Byte,
+  /// After every newline
+  Line,
+  /// Only when the buffer is full
+  #[default]
+  Full,
+}
+
+impl std::str::FromStr for FlushPolicy {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "byte" => Ok(Self::Byte),
+      "line" => Ok(Self::Line),
+      "full" => Ok(Self::Full),
+      _ => Err(format!("invalid flush policy \"{s}\" (expected byte, line or full)")),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompilerOptions {
+  pub eof_mode: EofMode,
+  pub flush_policy: FlushPolicy,
+  /// Must match the width the program was parsed with
+  pub cell_width: CellWidth,
+  /// Check the data pointer against the tape bounds before accessing cells,
+  /// stopping with an error in [`RunContext::status`](crate::io::RunContext::status) instead\
+  /// Only supported for [`Target::Host`]
+  pub bounds_checks: bool,
+}
+
+/// Output of [`CompilerImpl::compile`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompiledCode {
+  pub code: Vec<u8>,
+  /// Offset in `code` to resume at after a fault on a tape guard page,
+  /// which flushes pending output and returns normally
+  pub fault_exit: usize,
+  /// Offset in `code` where the code of each of the compiled ops starts, followed by where the last one ends\
+  /// Bounds checks count as part of the op they're inserted in front of
+  pub op_offsets: Vec<usize>,
+}
+
+impl CompiledCode {
+  /// Build the source map of the code, given the span every compiled op was lowered from,
+  /// as returned by [`bfil::lower_with_spans`](crate::bfil::lower_with_spans)
+  pub fn source_map(&self, spans: &[Option<Span>]) -> SourceMap {
+    assert_eq!(spans.len() + 1, self.op_offsets.len(), "spans don't match the compiled ops");
+    let mut entries: Vec<(Range<usize>, Span)> = vec![];
+    for (offsets, span) in self.op_offsets.windows(2).zip(spans) {
+      let (&[start, end], Some(span)) = (offsets, *span) else { continue };
+      match entries.last_mut() {
+        //Ops lowered from the same block share an entry
+        Some((range, last_span)) if range.end == start && *last_span == span => range.end = end,
+        _ if start == end => (),
+        _ => entries.push((start..end, span)),
+      }
+    }
+    SourceMap { entries }
+  }
+}
+
+/// Table from ranges of generated code to the source code they were generated from\
+/// Only covers the code of the ops themselves, not the prologue, epilogue or shared runtime routines
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+  /// Sorted, non-overlapping ranges of code offsets
+  entries: Vec<(Range<usize>, Span)>,
+}
+
+impl SourceMap {
+  /// Span the code at `offset` was generated from
+  pub fn lookup(&self, offset: usize) -> Option<Span> {
+    let idx = self.entries.partition_point(|(range, _)| range.end <= offset);
+    self.entries.get(idx)
+      .filter(|(range, _)| range.contains(&offset))
+      .map(|&(_, span)| span)
+  }
+
+  /// All ranges of code covered by the table, in order, with the span each was generated from
+  pub fn entries(&self) -> &[(Range<usize>, Span)] {
+    &self.entries
+  }
+}
+
+/// Compile `ops` with `compile`, inserting bounds checks first if enabled\
+/// The [`CompiledCode::op_offsets`] of the checked ops get mapped back to `ops`
+fn compile_checked(ops: &[Op], target: Target, options: &CompilerOptions, compile: impl FnOnce(&[Op]) -> CompiledCode) -> CompiledCode {
+  if !options.bounds_checks {
+    return compile(ops)
+  }
+  assert_eq!(target, Target::Host, "bounds checks are only supported for Target::Host");
+  let checked = bfil::insert_bounds_checks(ops);
+  let mut compiled = compile(&checked);
+  compiled.op_offsets = strip_check_offsets(ops, &checked, &compiled.op_offsets);
+  compiled
+}
+
+/// Map the [`CompiledCode::op_offsets`] of `checked`, which is `ops` with bounds checks inserted, back to `ops`
+fn strip_check_offsets(ops: &[Op], checked: &[Op], offsets: &[usize]) -> Vec<usize> {
+  let mut stripped = Vec::with_capacity(ops.len() + 1);
+  let mut remaining = ops.iter().peekable();
+  //Start of the checks in front of the next op
+  let mut start = None;
+  for (op, &offset) in checked.iter().zip(offsets) {
+    let op_start = *start.get_or_insert(offset);
+    if remaining.next_if_eq(&op).is_some() {
+      stripped.push(op_start);
+      start = None;
+    }
+  }
+  stripped.push(*offsets.last().unwrap());
+  stripped
+}
+
+/// Value to store into the cell before reading input into it, if any\
+/// Neither read(2) nor [`RunContext::read_byte`](crate::io::RunContext::read_byte) touch the cell on EOF,
+/// so storing the EOF value beforehand is all it takes to implement the [`EofMode`]
+fn eof_value(options: &CompilerOptions) -> Option<u32> {
+  match options.eof_mode {
+    EofMode::Unchanged => None,
+    EofMode::Zero => Some(0),
+    EofMode::MinusOne => Some(options.cell_width.max()),
+  }
+}
+
+/// Consecutive MulAdds with the same source cell, which backends load into a register only once
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct MulAddGroup {
+  src: isize,
+  /// Number of MulAdds in the group
+  len: usize,
+  /// Lowest destination cell
+  min_dst: isize,
+  /// Highest destination cell
+  max_dst: isize,
+}
+
+impl MulAddGroup {
+  /// Group starting at `ops[0]`, which has to be a MulAdd
+  fn starting_at(ops: &[Op]) -> Self {
+    let Op::MulAdd { src, .. } = ops[0] else { panic!("MulAdd group has to start at a MulAdd") };
+    let mut group = Self { src, len: 0, min_dst: isize::MAX, max_dst: isize::MIN };
+    for op in ops {
+      let Op::MulAdd { src: op_src, dst, .. } = *op else { break };
+      if op_src != src {
+        break
+      }
+      group.len += 1;
+      group.min_dst = group.min_dst.min(dst);
+      group.max_dst = group.max_dst.max(dst);
+      //The loaded source no longer matches the cell after this one
+      if dst == src {
+        break
+      }
+    }
+    group
+  }
+}
+
+/// Branch taken by a failed bounds check, `F` being the backend's forward branch
+struct FailedCheck<F> {
+  jump: F,
+  status: RunStatus,
+  /// Position of the check in the code, reported in [`RunContext::check_offset`](crate::io::RunContext::check_offset)
+  pos: usize,
+}
+
+/// Out of line code for a MulAdd group reaching past the tape bounds, `F` being the backend's forward branch
+struct MulAddSlowPath<F> {
+  /// Branches taken by the failed bounds checks of the group
+  jumps: Vec<FailedCheck<F>>,
+  /// Position of the first MulAdd of the group
+  body: usize,
+  /// Position right after the last MulAdd of the group
+  end: usize,
+}
+
+/// MulAdd group currently being generated, and the slow paths of the finished ones
+struct MulAddGroups<F> {
+  /// MulAdds left to generate in the current group, and its slow path
+  current: Option<(usize, MulAddSlowPath<F>)>,
+  slow_paths: Vec<MulAddSlowPath<F>>,
+}
+
+impl<F> Default for MulAddGroups<F> {
+  fn default() -> Self {
+    Self { current: None, slow_paths: vec![] }
+  }
+}
+
+impl<F> MulAddGroups<F> {
+  /// Group the MulAdd at `ops[0]` starts, `None` if it belongs to the current one
+  fn next_group(&self, ops: &[Op]) -> Option<MulAddGroup> {
+    self.current.is_none().then(|| MulAddGroup::starting_at(ops))
+  }
+
+  /// Start generating `group`, whose bounds checks branch with `jumps` and whose first MulAdd is at `body`
+  fn start(&mut self, group: MulAddGroup, jumps: Vec<FailedCheck<F>>, body: usize) {
+    assert!(self.current.is_none(), "MulAdd group started inside of another one");
+    self.current = Some((group.len, MulAddSlowPath { jumps, body, end: 0 }));
+  }
+
+  /// Count a MulAdd of the current group as generated, with the code after it starting at `pos`
+  fn generated(&mut self, pos: usize) {
+    let (remaining, _) = self.current.as_mut().expect("MulAdd outside of a group");
+    *remaining -= 1;
+    if *remaining > 0 {
+      return
+    }
+    let (_, mut slow_path) = self.current.take().unwrap();
+    if !slow_path.jumps.is_empty() {
+      slow_path.end = pos;
+      self.slow_paths.push(slow_path);
+    }
+  }
+}
+
+/// Branches and calls to code emitted after the function body, `F` being the backend's forward branch
+struct Fixups<F> {
+  /// Calls to the flush routine
+  flush_calls: Vec<F>,
+  /// Branches taken by failed bounds checks, with the out of bounds address in a scratch register
+  out_of_bounds: Vec<FailedCheck<F>>,
+  mul_adds: MulAddGroups<F>,
+}
+
+impl<F> Default for Fixups<F> {
+  fn default() -> Self {
+    Self { flush_calls: vec![], out_of_bounds: vec![], mul_adds: MulAddGroups::default() }
+  }
+}
+
+/// Assembly syntax of [`CompilerImpl::disassemble`] listings\
+/// Only matters for x86_64, other backends always use the standard syntax of the GNU assembler
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AsmSyntax {
+  #[default]
+  Intel,
+  Att,
+}
+
+impl std::str::FromStr for AsmSyntax {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "intel" => Ok(Self::Intel),
+      "att" => Ok(Self::Att),
+      _ => Err(format!("invalid assembly syntax \"{s}\" (expected intel or att)")),
+    }
+  }
+}
+
+/// Instruction decoded by [`CompilerImpl::disassemble`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+  /// Offset in the code
+  pub offset: usize,
+  pub len: usize,
+  /// GNU assembler source, referring to branch targets by their [`label`]\
+  /// Bytes the decoder doesn't know are emitted as data instead
+  pub text: String,
+  /// Offset of the branch target, for direct jumps and calls
+  pub target: Option<usize>,
+}
+
+/// Local label used in [`Instruction::text`] for the branch target at `offset`
+pub fn label(offset: usize) -> String {
+  format!(".L{offset:x}")
 }
 
 pub trait CompilerImpl {
+  /// `e_machine` of ELF images containing the generated code
+  const ELF_MACHINE: u16;
   fn supported() -> bool;
-  fn compile(item: Rc<RefCell<BfOpBlock>>, target: Option<Target>) -> Vec<u8>;
+  fn compile(ops: &[Op], target: Target, options: &CompilerOptions) -> CompiledCode;
+  /// Entry point of a standalone Linux executable, calling the [`Target::Extern`] function placed right after it
+  /// on the tape at the absolute address `tape`, then exiting with status 0
+  fn start_stub(tape: u64) -> Vec<u8>;
+  /// Decode code generated by this compiler into an assembly listing
+  fn disassemble(code: &[u8], syntax: AsmSyntax) -> Vec<Instruction>;
 }
 
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
 pub struct DummyCompiler;
 impl CompilerImpl for DummyCompiler {
+  const ELF_MACHINE: u16 = 0;
   fn supported() -> bool { false }
-  fn compile(_: Rc<RefCell<BfOpBlock>>, _: Option<Target>) -> Vec<u8> {
+  fn compile(_: &[Op], _: Target, _: &CompilerOptions) -> CompiledCode {
+    panic!("dummy compiler called")
+  }
+  fn start_stub(_: u64) -> Vec<u8> {
+    panic!("dummy compiler called")
+  }
+  fn disassemble(_: &[u8], _: AsmSyntax) -> Vec<Instruction> {
     panic!("dummy compiler called")
   }
 }
 
 pub mod x86_64;
+pub mod aarch64;
 
 #[cfg(target_arch="x86_64")]
 pub use x86_64::Compiler as NativeCompiler;
 
-#[cfg(not(any(target_arch="x86_64")))]
+#[cfg(target_arch="aarch64")]
+pub use aarch64::Compiler as NativeCompiler;
+
+#[cfg(not(any(target_arch="x86_64", target_arch="aarch64")))]
 pub use DummyCompiler as NativeCompiler;
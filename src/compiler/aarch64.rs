@@ -0,0 +1,509 @@
+use std::mem::offset_of;
+use crate::{bfil::Op, brainfuck::CellWidth, io::{RunContext, RunStatus}};
+use super::{AsmSyntax, CompiledCode, CompilerImpl, CompilerOptions, FailedCheck, FlushPolicy, Instruction, MulAddGroup, MulAddSlowPath, Target};
+
+pub mod encoder;
+pub mod decoder;
+use encoder::{AddSub, Assembler, Cond, Fixup, Mem, Reg, Size};
+
+/// Register holding the data pointer
+const PTR: Reg = Reg::X19;
+/// Register holding the write position in the output buffer\
+/// The buffer itself lives at the bottom of the frame, at [sp]
+const OUT_PTR: Reg = Reg::X20;
+/// Register holding the [`RunContext`] pointer, for [`Target::Host`]
+const CTX: Reg = Reg::X21;
+/// Scratch register for cell values
+const VALUE: Reg = Reg::X9;
+/// Scratch register for constants
+const TMP: Reg = Reg::X10;
+/// Register holding the source cell of a MulAdd group
+const SRC: Reg = Reg::X11;
+/// Scratch register for addresses of cells too far away for an immediate offset
+const ADDR: Reg = Reg::X15;
+/// Size of the output buffer in bytes
+const OUTPUT_BUFFER_SIZE: u32 = 0x1000;
+/// Linux syscall numbers, which differ from x86_64 ones
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+
+/// `blr [RunContext::<callback>]`, with the context as the first argument
+fn gen_host_call(asm: &mut Assembler, callback_offset: usize) {
+  asm.ldr(Size::Dword, Reg::X16, Mem::base(CTX, callback_offset as i32));
+  asm.mov_reg(Size::Dword, Reg::X0, CTX);
+  asm.blr(Reg::X16);
+}
+
+/// Operand size of a single cell
+fn cell_size(width: CellWidth) -> Size {
+  match width {
+    CellWidth::U8 => Size::Byte,
+    CellWidth::U16 => Size::Half,
+    CellWidth::U32 => Size::Word,
+  }
+}
+
+/// rd = rn + imm, using `TMP` for immediates that can't be encoded
+fn gen_add_imm(asm: &mut Assembler, size: Size, rd: Reg, rn: Reg, imm: i64) {
+  let (op, abs) = match imm < 0 {
+    true => (AddSub::Sub, imm.unsigned_abs()),
+    false => (AddSub::Add, imm as u64),
+  };
+  if abs < 0x1000 || (abs & 0xfff == 0 && abs < 0x100_0000) {
+    asm.add_sub_imm(op, size, rd, rn, abs as u32);
+  } else {
+    asm.mov_imm(size, TMP, abs);
+    asm.add_sub_reg(op, size, rd, rn, TMP);
+  }
+}
+
+/// [x19 + offset], with `offset` in cells\
+/// Cells out of reach of an immediate offset get their address computed into `ADDR` first
+fn cell(asm: &mut Assembler, offset: i32, width: CellWidth) -> Mem {
+  let mem = Mem::base(PTR, offset * width.bytes() as i32);
+  if mem.encodable(cell_size(width)) {
+    return mem
+  }
+  gen_add_imm(asm, Size::Dword, ADDR, PTR, mem.disp as i64);
+  Mem::base(ADDR, 0)
+}
+
+/// Address of the cell at `offset` in x9
+fn gen_cell_address(asm: &mut Assembler, offset: i32, width: CellWidth) {
+  gen_add_imm(asm, Size::Dword, VALUE, PTR, offset as i64 * width.bytes() as i64);
+}
+
+/// add x19, cells
+fn add_to_ptr(asm: &mut Assembler, cells: i32, width: CellWidth) {
+  if cells != 0 {
+    gen_add_imm(asm, Size::Dword, PTR, PTR, cells as i64 * width.bytes() as i64);
+  }
+}
+
+/// Load a cell into w9
+fn load_cell(asm: &mut Assembler, offset: i32, width: CellWidth) {
+  let mem = cell(asm, offset, width);
+  asm.ldr(cell_size(width), VALUE, mem);
+}
+
+/// Store w9 (or any other register) into a cell
+fn store_cell(asm: &mut Assembler, offset: i32, width: CellWidth, reg: Reg) {
+  let mem = cell(asm, offset, width);
+  asm.str(cell_size(width), reg, mem);
+}
+
+/// Add `imm` to a cell
+fn add_to_cell(asm: &mut Assembler, offset: i32, imm: i32, width: CellWidth) {
+  if width.wrap(imm as u32) == 0 {
+    return
+  }
+  load_cell(asm, offset, width);
+  //The store truncates the result, so the increment only has to be right modulo the cell width
+  gen_add_imm(asm, Size::Word, VALUE, VALUE, width.wrap_inc(imm) as i64);
+  store_cell(asm, offset, width, VALUE);
+}
+
+/// Set a cell to `value`
+fn gen_set_cell(asm: &mut Assembler, offset: i32, value: u32, width: CellWidth) {
+  let reg = match value {
+    0 => Reg::ZR,
+    _ => {
+      asm.mov_imm(Size::Word, VALUE, value as u64);
+      VALUE
+    },
+  };
+  store_cell(asm, offset, width, reg);
+}
+
+/// Load the current cell into w9, to test it with `cbz`/`cbnz`
+fn gen_test_current_cell(asm: &mut Assembler, width: CellWidth) {
+  asm.ldr(cell_size(width), VALUE, Mem::base(PTR, 0));
+}
+
+/// while [x19] != 0 { x19 += stride }, unrolled 4 times
+fn gen_scan(asm: &mut Assembler, stride: i32, width: CellWidth) {
+  let mut jumps_to_end = vec![];
+  let loop_head = asm.pos();
+  for _ in 0..4 {
+    gen_test_current_cell(asm, width);
+    jumps_to_end.push(asm.cbz_forward(Size::Word, VALUE, false, true));
+    add_to_ptr(asm, stride, width);
+  }
+  asm.b(loop_head);
+  for jump in jumps_to_end {
+    asm.bind(jump);
+  }
+}
+
+/// Branches and calls to code emitted after the function body\
+/// Branches taken by failed bounds checks have the out of bounds address in x9
+type Fixups = super::Fixups<Fixup>;
+
+/// Branch with `status` if the cell at `offset` lies before `tape_start` (underflow) or at/past `tape_end` (overflow),
+/// with its address in x9\
+/// The branch goes to code after the whole function body, so it can't be a short one
+fn gen_check_address(asm: &mut Assembler, offset: i32, width: CellWidth, status: RunStatus) -> FailedCheck<Fixup> {
+  let pos = asm.pos();
+  gen_cell_address(asm, offset, width);
+  let (bound, cond) = match status {
+    RunStatus::TapeUnderflow => (offset_of!(RunContext, tape_start), Cond::Lo),
+    _ => (offset_of!(RunContext, tape_end), Cond::Hs),
+  };
+  asm.ldr(Size::Dword, TMP, Mem::base(CTX, bound as i32));
+  asm.cmp_reg(Size::Dword, VALUE, TMP);
+  FailedCheck { jump: asm.b_cond_forward(cond, false), status, pos }
+}
+
+/// Exit with [`RunStatus::TapeUnderflow`] if [x19 + offset] lies before the start of the tape
+fn gen_check_start(asm: &mut Assembler, offset: i32, width: CellWidth, fixups: &mut Fixups) {
+  let check = gen_check_address(asm, offset, width, RunStatus::TapeUnderflow);
+  fixups.out_of_bounds.push(check);
+}
+
+/// Exit with [`RunStatus::TapeOverflow`] if [x19 + offset] lies past the end of the tape\
+/// The tape holds a whole number of cells, so checking the first byte of the cell is enough
+fn gen_check_end(asm: &mut Assembler, offset: i32, width: CellWidth, fixups: &mut Fixups) {
+  let check = gen_check_address(asm, offset, width, RunStatus::TapeOverflow);
+  fixups.out_of_bounds.push(check);
+}
+
+/// Report the status, offset and position of a failed bounds check in [`RunContext`], then branch to `exit`
+fn gen_out_of_bounds_exit(asm: &mut Assembler, checks: Vec<FailedCheck<Fixup>>, exit: usize) {
+  if checks.is_empty() {
+    return
+  }
+  let report = asm.pos();
+  asm.str(Size::Word, TMP, Mem::base(CTX, offset_of!(RunContext, status) as i32));
+  asm.str(Size::Dword, ADDR, Mem::base(CTX, offset_of!(RunContext, check_offset) as i32));
+  asm.ldr(Size::Dword, TMP, Mem::base(CTX, offset_of!(RunContext, tape_start) as i32));
+  asm.add_sub_reg(AddSub::Sub, Size::Dword, VALUE, VALUE, TMP);
+  asm.str(Size::Dword, VALUE, Mem::base(CTX, offset_of!(RunContext, fault_offset) as i32));
+  asm.b(exit);
+  //One stub per check, with the status in w10 and the position of the check in x15
+  for check in checks {
+    asm.bind(check.jump);
+    asm.mov_imm(Size::Word, TMP, check.status as u64);
+    asm.mov_imm(Size::Dword, ADDR, check.pos as u64);
+    asm.b(report);
+  }
+}
+
+/// while [x19] != 0 { x19 += stride }, exiting before the pointer leaves the tape
+fn gen_checked_scan(asm: &mut Assembler, stride: i32, width: CellWidth, fixups: &mut Fixups) {
+  let loop_head = asm.pos();
+  gen_test_current_cell(asm, width);
+  let end = asm.cbz_forward(Size::Word, VALUE, false, true);
+  if stride < 0 {
+    gen_check_start(asm, stride, width, fixups);
+  } else {
+    gen_check_end(asm, stride, width, fixups);
+  }
+  add_to_ptr(asm, stride, width);
+  asm.b(loop_head);
+  asm.bind(end);
+}
+
+/// Append the low byte of a cell to the output buffer, flushing it according to the flush policy
+fn gen_output(asm: &mut Assembler, offset: i32, width: CellWidth, policy: FlushPolicy, fixups: &mut Fixups) {
+  let mem = cell(asm, offset, width);
+  asm.ldr(Size::Byte, VALUE, mem);
+  asm.str(Size::Byte, VALUE, Mem::base(OUT_PTR, 0));
+  asm.add_sub_imm(AddSub::Add, Size::Dword, OUT_PTR, OUT_PTR, 1);
+  if policy == FlushPolicy::Byte {
+    fixups.flush_calls.push(asm.bl_forward());
+    return
+  }
+  let newline = (policy == FlushPolicy::Line).then(|| {
+    asm.cmp_imm(Size::Word, VALUE, b'\n' as u32);
+    asm.b_cond_forward(Cond::Eq, true)
+  });
+  //Flush if the buffer is full
+  asm.add_sub_imm(AddSub::Add, Size::Dword, TMP, Reg::Sp, OUTPUT_BUFFER_SIZE);
+  asm.cmp_reg(Size::Dword, OUT_PTR, TMP);
+  let not_full = asm.b_cond_forward(Cond::Lo, true);
+  if let Some(newline) = newline {
+    asm.bind(newline);
+  }
+  fixups.flush_calls.push(asm.bl_forward());
+  asm.bind(not_full);
+}
+
+/// Subroutine writing out and emptying the output buffer, called with `bl`
+fn gen_flush_routine(asm: &mut Assembler, target: Target) {
+  //`bl` doesn't touch the stack, so the buffer is right at sp
+  asm.mov_from_sp(Reg::X1);
+  if target == Target::Host {
+    asm.add_sub_reg(AddSub::Subs, Size::Dword, Reg::X2, OUT_PTR, Reg::X1);
+    let empty = asm.b_cond_forward(Cond::Eq, true);
+    //Our own return address is overwritten by the call
+    asm.push_pair(Reg::FP, Reg::LR, 16);
+    gen_host_call(asm, offset_of!(RunContext, write_bytes));
+    asm.pop_pair(Reg::FP, Reg::LR, 16);
+    asm.bind(empty);
+    asm.mov_from_sp(OUT_PTR);
+    asm.ret();
+    return
+  }
+  //x1 advances past whatever each write(2) managed to write, until it catches up with x20
+  let write_loop = asm.pos();
+  asm.add_sub_reg(AddSub::Subs, Size::Dword, Reg::X2, OUT_PTR, Reg::X1);
+  let empty = asm.b_cond_forward(Cond::Ls, true);
+  asm.mov_imm(Size::Dword, Reg::X8, SYS_WRITE);
+  asm.mov_imm(Size::Dword, Reg::X0, 1);
+  asm.svc();
+  //On errors, the rest of the buffer is dropped
+  asm.cmp_imm(Size::Dword, Reg::X0, 0);
+  let failed = asm.b_cond_forward(Cond::Le, true);
+  asm.add_sub_reg(AddSub::Add, Size::Dword, Reg::X1, Reg::X1, Reg::X0);
+  asm.b(write_loop);
+  asm.bind(empty);
+  asm.bind(failed);
+  asm.mov_from_sp(OUT_PTR);
+  asm.ret();
+}
+
+/// Load the source cell of a MulAdd group into w11, returning the branches to its slow path
+///
+/// Like on x86_64, groups reaching past the tape bounds in [`RunContext`] are skipped if the source is 0,
+/// so only programs actually accessing those cells hit a guard region, or fail the bounds check if checked.
+fn start_mul_add_group(asm: &mut Assembler, group: &MulAddGroup, target: Target, width: CellWidth) -> Vec<FailedCheck<Fixup>> {
+  let MulAddGroup { src, min_dst, max_dst, .. } = *group;
+  let mem = cell(asm, src as i32, width);
+  asm.ldr(cell_size(width), SRC, mem);
+  let mut jumps = vec![];
+  if target == Target::Host {
+    if min_dst < src {
+      jumps.push(gen_check_address(asm, min_dst as i32, width, RunStatus::TapeUnderflow));
+    }
+    if max_dst > src {
+      jumps.push(gen_check_address(asm, max_dst as i32, width, RunStatus::TapeOverflow));
+    }
+  }
+  jumps
+}
+
+/// Skip the group if its source is 0, otherwise do the accesses or report the failed bounds check if `checked`
+fn gen_mul_add_slow_path(asm: &mut Assembler, slow_path: MulAddSlowPath<Fixup>, checked: bool, fixups: &mut Fixups) {
+  if checked {
+    for check in slow_path.jumps {
+      asm.bind(check.jump);
+      asm.cbz(Size::Word, SRC, false, slow_path.end);
+      fixups.out_of_bounds.push(FailedCheck { jump: asm.b_forward(), ..check });
+    }
+    return
+  }
+  for check in slow_path.jumps {
+    asm.bind(check.jump);
+  }
+  asm.cbz(Size::Word, SRC, false, slow_path.end);
+  asm.b(slow_path.body);
+}
+
+/// Raw `read`/`write` syscall on a single byte at `[x1]`
+fn gen_io_syscall(asm: &mut Assembler, syscall: u64, fd: u64) {
+  asm.mov_imm(Size::Dword, Reg::X8, syscall);
+  asm.mov_imm(Size::Dword, Reg::X0, fd);
+  asm.mov_imm(Size::Dword, Reg::X2, 1);
+  asm.svc();
+}
+
+/// Returns the [`CompiledCode::op_offsets`] of `ops`
+fn compile_bfil(ops: &[Op], asm: &mut Assembler, target: Target, fixups: &mut Fixups, options: &CompilerOptions) -> Vec<usize> {
+  let width = options.cell_width;
+  //Pending branches out of all currently open loops, and positions right after their heads
+  let mut loop_heads = vec![];
+  let mut op_offsets = Vec::with_capacity(ops.len() + 1);
+  for (idx, op) in ops.iter().enumerate() {
+    op_offsets.push(asm.pos());
+    match *op {
+      Op::LoopStart => {
+        gen_test_current_cell(asm, width);
+        //The loop body may be more than 1MB long
+        let exit = asm.cbz_forward(Size::Word, VALUE, false, false);
+        loop_heads.push((exit, asm.pos()));
+      },
+      Op::LoopEnd => {
+        let (exit, head) = loop_heads.pop().expect("unbalanced bfil loop");
+        gen_test_current_cell(asm, width);
+        asm.cbz(Size::Word, VALUE, true, head);
+        asm.bind(exit);
+      },
+      Op::MovePtr(n) => {
+        add_to_ptr(asm, n as i32, width);
+      },
+      Op::SetCell { off, value } => {
+        gen_set_cell(asm, off as i32, value, width);
+      },
+      Op::AddCell { off, n } => {
+        add_to_cell(asm, off as i32, n, width);
+      },
+      Op::MulAdd { dst, k, .. } => {
+        if let Some(group) = fixups.mul_adds.next_group(&ops[idx..]) {
+          let jumps = start_mul_add_group(asm, &group, target, width);
+          fixups.mul_adds.start(group, jumps, asm.pos());
+        }
+        load_cell(asm, dst as i32, width);
+        match k {
+          1 => asm.add_sub_reg(AddSub::Add, Size::Word, VALUE, VALUE, SRC),
+          -1 => asm.add_sub_reg(AddSub::Sub, Size::Word, VALUE, VALUE, SRC),
+          _ => {
+            asm.mov_imm(Size::Word, TMP, k as u32 as u64);
+            asm.madd(Size::Word, VALUE, SRC, TMP, VALUE);
+          },
+        }
+        store_cell(asm, dst as i32, width, VALUE);
+        fixups.mul_adds.generated(asm.pos());
+      },
+      Op::Scan(stride) if options.bounds_checks => {
+        gen_checked_scan(asm, stride as i32, width, fixups);
+      },
+      Op::Scan(stride) => {
+        gen_scan(asm, stride as i32, width);
+      },
+      Op::CheckBounds { min, max } => {
+        gen_check_start(asm, min as i32, width, fixups);
+        gen_check_end(asm, max as i32, width, fixups);
+      },
+      Op::CheckLoopBounds { min, max } => {
+        gen_test_current_cell(asm, width);
+        let skip = asm.cbz_forward(Size::Word, VALUE, false, true);
+        gen_check_start(asm, min as i32, width, fixups);
+        gen_check_end(asm, max as i32, width, fixups);
+        asm.bind(skip);
+      },
+      Op::Out { off } => {
+        gen_output(asm, off as i32, width, options.flush_policy, fixups);
+      },
+      Op::In { off } => {
+        //Pending output has to be visible before blocking on input
+        fixups.flush_calls.push(asm.bl_forward());
+        if let Some(value) = super::eof_value(options) {
+          gen_set_cell(asm, off as i32, value, width);
+        }
+        match target {
+          Target::Extern if width == CellWidth::U8 => {
+            let mem = cell(asm, off as i32, width);
+            gen_add_imm(asm, Size::Dword, Reg::X1, mem.base, mem.disp as i64);
+            gen_io_syscall(asm, SYS_READ, 0);
+          },
+          Target::Extern => {
+            //Wider cells need the byte zero-extended, so read it into the (just flushed) output buffer first
+            asm.mov_reg(Size::Dword, Reg::X1, OUT_PTR);
+            gen_io_syscall(asm, SYS_READ, 0);
+            asm.cmp_imm(Size::Dword, Reg::X0, 1);
+            let eof = asm.b_cond_forward(Cond::Ne, true);
+            asm.ldr(Size::Byte, VALUE, Mem::base(OUT_PTR, 0));
+            store_cell(asm, off as i32, width, VALUE);
+            asm.bind(eof);
+          },
+          Target::Host => {
+            gen_host_call(asm, offset_of!(RunContext, read_byte));
+            asm.cmp_imm(Size::Word, Reg::X0, 0);
+            let eof = asm.b_cond_forward(Cond::Lt, true);
+            store_cell(asm, off as i32, width, Reg::X0);
+            asm.bind(eof);
+          },
+        }
+      },
+    }
+  }
+  op_offsets.push(asm.pos());
+  op_offsets
+}
+
+/// AAPCS64 prologue and epilogue around the function body\
+/// The function returns the final data pointer\
+/// Returns the position of the epilogue, which can be branched to from anywhere in the body
+fn wrap_function(asm: &mut Assembler, target: Target, fixups: &mut Fixups, body: impl FnOnce(&mut Assembler, &mut Fixups)) -> usize {
+  //Save the frame record and all callee-saved registers we may use, then allocate the output buffer
+  asm.push_pair(Reg::FP, Reg::LR, 48);
+  asm.mov_from_sp(Reg::FP);
+  asm.store_pair(PTR, OUT_PTR, 16);
+  asm.str(Size::Dword, CTX, Mem::base(Reg::Sp, 32));
+  asm.add_sub_imm(AddSub::Sub, Size::Dword, Reg::Sp, Reg::Sp, OUTPUT_BUFFER_SIZE);
+  //mov x19, x0; (and mov x21, x1; for Host) and set up the output buffer at sp
+  asm.mov_reg(Size::Dword, PTR, Reg::X0);
+  if target == Target::Host {
+    asm.mov_reg(Size::Dword, CTX, Reg::X1);
+  }
+  asm.mov_from_sp(OUT_PTR);
+  body(asm, fixups);
+  //flush the output buffer, return the data pointer and restore everything at the end
+  let epilogue = asm.pos();
+  fixups.flush_calls.push(asm.bl_forward());
+  asm.mov_reg(Size::Dword, Reg::X0, PTR);
+  asm.add_sub_imm(AddSub::Add, Size::Dword, Reg::Sp, Reg::Sp, OUTPUT_BUFFER_SIZE);
+  asm.ldr(Size::Dword, CTX, Mem::base(Reg::Sp, 32));
+  asm.load_pair(PTR, OUT_PTR, 16);
+  asm.pop_pair(Reg::FP, Reg::LR, 48);
+  asm.ret();
+  epilogue
+}
+
+pub struct Compiler;
+impl CompilerImpl for Compiler {
+  const ELF_MACHINE: u16 = 183;
+  fn supported() -> bool {
+    cfg!(target_arch = "aarch64") && cfg!(target_os = "linux")
+  }
+  fn compile(ops: &[Op], target: super::Target, options: &CompilerOptions) -> CompiledCode {
+    super::compile_checked(ops, target, options, |ops| {
+      let mut asm = Assembler::new();
+      let mut fixups = Fixups::default();
+      let mut op_offsets = vec![];
+      let fault_exit = wrap_function(&mut asm, target, &mut fixups, |asm, fixups| {
+        op_offsets = compile_bfil(ops, asm, target, fixups, options);
+      });
+      //The slow paths, the bounds check exit and the flush routine are only reached from the body, so they go last
+      for slow_path in std::mem::take(&mut fixups.mul_adds.slow_paths) {
+        gen_mul_add_slow_path(&mut asm, slow_path, options.bounds_checks, &mut fixups);
+      }
+      gen_out_of_bounds_exit(&mut asm, fixups.out_of_bounds, fault_exit);
+      if !fixups.flush_calls.is_empty() {
+        for call in fixups.flush_calls {
+          asm.bind(call);
+        }
+        gen_flush_routine(&mut asm, target);
+      }
+      CompiledCode { code: asm.into_code(), fault_exit, op_offsets }
+    })
+  }
+  fn start_stub(tape: u64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    asm.mov_imm(Size::Dword, Reg::X0, tape);
+    let call = asm.bl_forward();
+    asm.mov_imm(Size::Dword, Reg::X8, SYS_EXIT);
+    asm.mov_imm(Size::Dword, Reg::X0, 0);
+    asm.svc();
+    asm.bind(call);
+    asm.into_code()
+  }
+  fn disassemble(code: &[u8], _: AsmSyntax) -> Vec<Instruction> {
+    decoder::disassemble(code)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{bfil, brainfuck::{parse_tree, CellWidth}};
+  use super::*;
+
+  #[test]
+  fn long_body() {
+    //MulAdd slow paths, the bounds check exit and the loop exit all end up more than 1MB away
+    let code = format!("+[->+<]+[{}]", ".>".repeat(60000));
+    let ops = bfil::lower(&parse_tree(&code, CellWidth::U8).unwrap());
+    let loop_end = ops.iter().rposition(|op| *op == Op::LoopEnd).unwrap();
+    for (target, bounds_checks) in [(Target::Extern, false), (Target::Host, false), (Target::Host, true)] {
+      let options = CompilerOptions { bounds_checks, ..Default::default() };
+      let compiled = Compiler::compile(&ops, target, &options);
+      assert!(compiled.code.len() > 1 << 20);
+      let listing = Compiler::disassemble(&compiled.code, AsmSyntax::default());
+      //Branches out of the code would've been decoded as data
+      assert!(listing.iter().all(|inst| !inst.text.starts_with(".inst")), "{options:?}");
+      let exit = compiled.op_offsets[loop_end + 1];
+      assert!(listing.iter().any(|inst| inst.target == Some(exit) && inst.text.starts_with("b ")), "{options:?}");
+    }
+  }
+}
@@ -0,0 +1,223 @@
+//! Decoder for the subset of AArch64 instructions emitted by the [encoder](super::encoder),
+//! producing GNU assembler source
+
+use crate::compiler::{label, Instruction};
+
+/// Condition code suffixes, in encoding order
+const CONDS: [&str; 16] = ["eq", "ne", "hs", "lo", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", "al", "nv"];
+
+/// `bits` bits of `inst` starting at `shift`
+fn field(inst: u32, shift: u32, bits: u32) -> u32 {
+  (inst >> shift) & ((1 << bits) - 1)
+}
+
+/// Sign-extended `bits` bits of `inst` starting at `shift`
+fn signed_field(inst: u32, shift: u32, bits: u32) -> i64 {
+  let value = field(inst, shift, bits) as i64;
+  (value << (64 - bits)) >> (64 - bits)
+}
+
+/// Name of register `num`, an x register if `x` and a w register otherwise\
+/// Register 31 is either sp or the zero register, depending on the operand
+fn reg(num: u32, x: bool, sp: bool) -> String {
+  match (num, sp, x) {
+    (31, true, true) => "sp".into(),
+    (31, true, false) => "wsp".into(),
+    (31, false, true) => "xzr".into(),
+    (31, false, false) => "wzr".into(),
+    (num, _, true) => format!("x{num}"),
+    (num, _, false) => format!("w{num}"),
+  }
+}
+
+/// `[rn, #disp]`
+fn mem(rn: u32, disp: i64) -> String {
+  match disp {
+    0 => format!("[{}]", reg(rn, true, true)),
+    _ => format!("[{}, #{disp}]", reg(rn, true, true)),
+  }
+}
+
+/// Decode a single instruction at `offset`, returning its text and branch target
+fn decode(inst: u32, offset: usize, code_len: usize) -> Option<(String, Option<usize>)> {
+  let x = inst >> 31 == 1;
+  let (rd, rn, rm) = (field(inst, 0, 5), field(inst, 5, 5), field(inst, 16, 5));
+  let branch = |mnemonic: String, words: i64| {
+    let target = usize::try_from(offset as i64 + words * 4).ok().filter(|&target| target <= code_len)?;
+    Some((format!("{mnemonic} {}", label(target)), Some(target)))
+  };
+  let text = if inst & 0x1f80_0000 == 0x1100_0000 {
+    //add/sub (immediate)
+    let op = field(inst, 29, 2);
+    let imm = field(inst, 10, 12);
+    let shift = if field(inst, 22, 1) == 1 { ", lsl #12" } else { "" };
+    let sets_flags = op & 1 == 1;
+    match op {
+      0b11 if rd == 31 => format!("cmp {}, #{imm}{shift}", reg(rn, x, true)),
+      0b00 if imm == 0 && (rd == 31 || rn == 31) => format!("mov {}, {}", reg(rd, x, true), reg(rn, x, true)),
+      _ => format!("{} {}, {}, #{imm}{shift}", ["add", "adds", "sub", "subs"][op as usize], reg(rd, x, !sets_flags), reg(rn, x, true)),
+    }
+  } else if inst & 0x1f20_fc00 == 0x0b00_0000 && field(inst, 22, 2) == 0 {
+    //add/sub (unshifted register)
+    let op = field(inst, 29, 2);
+    match op {
+      0b11 if rd == 31 => format!("cmp {}, {}", reg(rn, x, false), reg(rm, x, false)),
+      _ => format!("{} {}, {}, {}", ["add", "adds", "sub", "subs"][op as usize], reg(rd, x, false), reg(rn, x, false), reg(rm, x, false)),
+    }
+  } else if inst & 0x7fe0_fc00 == 0x2a00_0000 {
+    //orr (unshifted register)
+    match rn {
+      31 => format!("mov {}, {}", reg(rd, x, false), reg(rm, x, false)),
+      _ => format!("orr {}, {}, {}", reg(rd, x, false), reg(rn, x, false), reg(rm, x, false)),
+    }
+  } else if inst & 0x1f80_0000 == 0x1280_0000 {
+    //Move wide (immediate)
+    let mnemonic = match field(inst, 29, 2) {
+      0b00 => "movn",
+      0b10 => "movz",
+      0b11 => "movk",
+      _ => return None,
+    };
+    let imm = field(inst, 5, 16);
+    match field(inst, 21, 2) * 16 {
+      0 => format!("{mnemonic} {}, #{imm:#x}", reg(rd, x, false)),
+      shift => format!("{mnemonic} {}, #{imm:#x}, lsl #{shift}", reg(rd, x, false)),
+    }
+  } else if inst & 0x7fe0_8000 == 0x1b00_0000 {
+    let ra = field(inst, 10, 5);
+    format!("madd {}, {}, {}, {}", reg(rd, x, false), reg(rn, x, false), reg(rm, x, false), reg(ra, x, false))
+  } else if inst & 0x3f00_0000 == 0x3900_0000 || inst & 0x3f20_0c00 == 0x3800_0000 {
+    //Load/store (unsigned scaled or signed unscaled immediate), only plain loads and stores
+    let size = field(inst, 30, 2);
+    let load = match field(inst, 22, 2) {
+      0 => false,
+      1 => true,
+      _ => return None,
+    };
+    let (scaled, disp) = match inst & 0x0100_0000 != 0 {
+      true => (true, (field(inst, 10, 12) << size) as i64),
+      false => (false, signed_field(inst, 12, 9)),
+    };
+    let mnemonic = format!("{}{}{}",
+      if load { "ld" } else { "st" },
+      if scaled { "r" } else { "ur" },
+      ["b", "h", "", ""][size as usize],
+    );
+    format!("{mnemonic} {}, {}", reg(rd, size == 3, false), mem(rn, disp))
+  } else if inst >> 25 == 0b1010100 {
+    //Load/store pair of x registers
+    let mnemonic = if field(inst, 22, 1) == 1 { "ldp" } else { "stp" };
+    let rt2 = field(inst, 10, 5);
+    let disp = signed_field(inst, 15, 7) * 8;
+    let rn = reg(rn, true, true);
+    let address = match field(inst, 23, 2) {
+      0b01 => format!("[{rn}], #{disp}"),
+      0b10 => format!("[{rn}, #{disp}]"),
+      0b11 => format!("[{rn}, #{disp}]!"),
+      _ => return None,
+    };
+    format!("{mnemonic} {}, {}, {address}", reg(rd, true, false), reg(rt2, true, false))
+  } else if inst & 0xffe0_001f == 0xd400_0001 {
+    format!("svc #{}", field(inst, 5, 16))
+  } else if inst & 0xffff_fc1f == 0xd63f_0000 {
+    format!("blr {}", reg(rn, true, false))
+  } else if inst & 0xffff_fc1f == 0xd65f_0000 {
+    match rn {
+      30 => "ret".into(),
+      _ => format!("ret {}", reg(rn, true, false)),
+    }
+  } else if inst & 0x7c00_0000 == 0x1400_0000 {
+    return branch(if x { "bl" } else { "b" }.into(), signed_field(inst, 0, 26))
+  } else if inst & 0xff00_0010 == 0x5400_0000 {
+    return branch(format!("b.{}", CONDS[rd as usize]), signed_field(inst, 5, 19))
+  } else if inst & 0x7e00_0000 == 0x3400_0000 {
+    let mnemonic = if field(inst, 24, 1) == 1 { "cbnz" } else { "cbz" };
+    return branch(format!("{mnemonic} {},", reg(rd, x, false)), signed_field(inst, 5, 19))
+  } else {
+    return None
+  };
+  Some((text, None))
+}
+
+/// Decode `code` into a listing, falling back to `.inst` for anything the encoder doesn't generate
+pub fn disassemble(code: &[u8]) -> Vec<Instruction> {
+  let mut instructions = vec![];
+  let mut words = code.chunks_exact(4);
+  for (idx, word) in words.by_ref().enumerate() {
+    let offset = idx * 4;
+    let inst = u32::from_le_bytes(word.try_into().unwrap());
+    let (text, target) = decode(inst, offset, code.len())
+      .unwrap_or_else(|| (format!(".inst {inst:#010x}"), None));
+    instructions.push(Instruction { offset, len: 4, text, target });
+  }
+  let tail = code.len() - words.remainder().len();
+  for (idx, byte) in words.remainder().iter().enumerate() {
+    instructions.push(Instruction { offset: tail + idx, len: 1, text: format!(".byte {byte:#04x}"), target: None });
+  }
+  instructions
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::compiler::aarch64::encoder::{AddSub, Assembler, Cond, Mem, Reg, Size};
+  use super::*;
+
+  #[test]
+  fn listing() {
+    let mut asm = Assembler::new();
+    asm.push_pair(Reg::FP, Reg::LR, 48);
+    asm.mov_from_sp(Reg::FP);
+    asm.store_pair(Reg::X19, Reg::X20, 16);
+    asm.add_sub_imm(AddSub::Sub, Size::Dword, Reg::Sp, Reg::Sp, 0x1000);
+    asm.ldr(Size::Byte, Reg::X9, Mem::base(Reg::X19, -1));
+    asm.str(Size::Half, Reg::X9, Mem::base(Reg::X19, 2));
+    asm.ldr(Size::Dword, Reg::X16, Mem::base(Reg::X21, 8));
+    asm.mov_imm(Size::Word, Reg::X10, 0x1234_5678);
+    asm.mov_imm(Size::Dword, Reg::X10, u64::MAX);
+    asm.madd(Size::Word, Reg::X9, Reg::X11, Reg::X10, Reg::X9);
+    asm.add_sub_reg(AddSub::Sub, Size::Word, Reg::X9, Reg::X9, Reg::X11);
+    asm.mov_reg(Size::Dword, Reg::X0, Reg::X21);
+    asm.cmp_imm(Size::Word, Reg::X9, 10);
+    asm.b_cond(Cond::Lo, 0);
+    asm.cbz(Size::Word, Reg::X9, true, 16);
+    asm.blr(Reg::X16);
+    asm.svc();
+    asm.pop_pair(Reg::FP, Reg::LR, 48);
+    asm.ret();
+    asm.b(asm.pos() + 4);
+    asm.emit(0xd503_201f);
+    let mut code = asm.into_code();
+    code.push(0xff);
+    //Checked against llvm-objdump, which writes movz and movn as `mov` with decimal immediates,
+    //and decodes the nop the encoder never emits
+    let expected = [
+      "stp x29, x30, [sp, #-48]!",
+      "mov x29, sp",
+      "stp x19, x20, [sp, #16]",
+      "sub sp, sp, #1, lsl #12",
+      "ldurb w9, [x19, #-1]",
+      "strh w9, [x19, #2]",
+      "ldr x16, [x21, #8]",
+      "movz w10, #0x5678",
+      "movk w10, #0x1234, lsl #16",
+      "movn x10, #0x0",
+      "madd w9, w11, w10, w9",
+      "sub w9, w9, w11",
+      "mov x0, x21",
+      "cmp w9, #10",
+      "b.lo .L0",
+      "cbnz w9, .L10",
+      "blr x16",
+      "svc #0",
+      "ldp x29, x30, [sp], #48",
+      "ret",
+      "b .L54",
+      ".inst 0xd503201f",
+      ".byte 0xff",
+    ];
+    let listing = disassemble(&code);
+    assert_eq!(listing.iter().map(|inst| &inst.text[..]).collect::<Vec<_>>(), expected);
+    let targets: Vec<_> = listing.iter().filter_map(|inst| Some((inst.offset, inst.target?))).collect();
+    assert_eq!(targets, [(0x38, 0), (0x3c, 0x10), (0x50, 0x54)]);
+  }
+}
@@ -0,0 +1,579 @@
+//! Minimal AArch64 instruction encoder, covering the subset of instructions used by the compiler
+
+/// General purpose registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+  X0, X1, X2, X3, X4, X5, X6, X7,
+  X8, X9, X10, X11, X12, X13, X14, X15,
+  X16, X17, X18, X19, X20, X21, X22, X23,
+  X24, X25, X26, X27, X28, X29, X30,
+  /// Stack pointer, or the zero register for instructions that don't accept sp (see [`Reg::ZR`])
+  Sp,
+}
+
+impl Reg {
+  /// xzr/wzr, which shares its encoding with sp
+  pub const ZR: Reg = Reg::Sp;
+  /// Frame pointer
+  pub const FP: Reg = Reg::X29;
+  /// Link register
+  pub const LR: Reg = Reg::X30;
+}
+
+/// Operand size, `Word` and `Dword` are also the sizes of w and x registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+  Byte,
+  Half,
+  Word,
+  Dword,
+}
+
+impl Size {
+  pub fn bytes(self) -> usize {
+    1 << self.log2()
+  }
+
+  fn log2(self) -> u32 {
+    self as u32
+  }
+
+  /// `sf` bit of data processing instructions, only Word and Dword are valid
+  fn sf(self) -> u32 {
+    match self {
+      Size::Word => 0,
+      Size::Dword => 1 << 31,
+      _ => panic!("data processing instructions only support w and x registers"),
+    }
+  }
+}
+
+/// Memory operand, `[base, #disp]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mem {
+  pub base: Reg,
+  pub disp: i32,
+}
+
+impl Mem {
+  /// `[base, #disp]`
+  pub fn base(base: Reg, disp: i32) -> Self {
+    Self { base, disp }
+  }
+
+  /// Offset for the scaled unsigned form of loads and stores of `size`, if it fits
+  fn scaled_disp(self, size: Size) -> Option<u32> {
+    let scaled = self.disp >= 0 && self.disp % size.bytes() as i32 == 0 && self.disp >> size.log2() < 0x1000;
+    scaled.then_some((self.disp as u32) >> size.log2())
+  }
+
+  /// Whether a load or store of `size` can encode this operand
+  pub fn encodable(self, size: Size) -> bool {
+    self.scaled_disp(size).is_some() || (-0x100..0x100).contains(&self.disp)
+  }
+}
+
+/// Add/subtract operations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddSub {
+  Add = 0b00,
+  /// `adds`
+  Adds = 0b01,
+  Sub = 0b10,
+  /// `subs`, aka `cmp` when the destination is the zero register
+  Subs = 0b11,
+}
+
+/// Condition codes for `b.cond`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+  /// Equal/zero
+  Eq = 0x0,
+  /// Not equal/not zero
+  Ne = 0x1,
+  /// Unsigned higher or same (>=)
+  Hs = 0x2,
+  /// Unsigned lower (<)
+  Lo = 0x3,
+  /// Unsigned higher (>)
+  Hi = 0x8,
+  /// Unsigned lower or same (<=)
+  Ls = 0x9,
+  /// Signed greater or equal
+  Ge = 0xa,
+  /// Signed less than
+  Lt = 0xb,
+  /// Signed greater than
+  Gt = 0xc,
+  /// Signed less or equal
+  Le = 0xd,
+}
+
+impl Cond {
+  /// Condition that holds exactly when this one doesn't
+  pub fn invert(self) -> Self {
+    match self {
+      Self::Eq => Self::Ne,
+      Self::Ne => Self::Eq,
+      Self::Hs => Self::Lo,
+      Self::Lo => Self::Hs,
+      Self::Hi => Self::Ls,
+      Self::Ls => Self::Hi,
+      Self::Ge => Self::Lt,
+      Self::Lt => Self::Ge,
+      Self::Gt => Self::Le,
+      Self::Le => Self::Gt,
+    }
+  }
+}
+
+/// Placeholder for a forward branch, to be resolved with [`Assembler::bind`]
+#[must_use]
+#[derive(Debug)]
+pub struct Fixup {
+  /// Position of the branch instruction
+  pos: usize,
+  /// Whether the offset is a 26-bit `b`/`bl` one, instead of a 19-bit conditional one
+  long: bool,
+}
+
+/// Appends encoded instructions to a code buffer
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+  code: Vec<u8>,
+}
+
+impl Assembler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Current position in the code buffer
+  pub fn pos(&self) -> usize {
+    self.code.len()
+  }
+
+  pub fn code(&self) -> &[u8] {
+    &self.code
+  }
+
+  pub fn into_code(self) -> Vec<u8> {
+    self.code
+  }
+
+  /// Append a single instruction word
+  pub fn emit(&mut self, inst: u32) {
+    self.code.extend_from_slice(&inst.to_le_bytes());
+  }
+
+  /// `add`/`adds`/`sub`/`subs rd, rn, #imm`, with `imm` shifted left by 12 if it doesn't fit in 12 bits\
+  /// rd (unless setting flags) and rn may be sp
+  pub fn add_sub_imm(&mut self, op: AddSub, size: Size, rd: Reg, rn: Reg, imm: u32) {
+    let imm = match imm {
+      0..0x1000 => imm << 10,
+      _ if imm & 0xfff == 0 && imm < 0x100_0000 => (1 << 22) | (imm >> 12) << 10,
+      _ => panic!("immediate {imm:#x} can't be encoded"),
+    };
+    self.emit(size.sf() | (op as u32) << 29 | 0x1100_0000 | imm | (rn as u32) << 5 | rd as u32);
+  }
+
+  /// `add`/`adds`/`sub`/`subs rd, rn, rm`\
+  /// Register 31 is the zero register for all operands
+  pub fn add_sub_reg(&mut self, op: AddSub, size: Size, rd: Reg, rn: Reg, rm: Reg) {
+    self.emit(size.sf() | (op as u32) << 29 | 0x0b00_0000 | (rm as u32) << 16 | (rn as u32) << 5 | rd as u32);
+  }
+
+  /// `cmp rn, #imm`
+  pub fn cmp_imm(&mut self, size: Size, rn: Reg, imm: u32) {
+    self.add_sub_imm(AddSub::Subs, size, Reg::ZR, rn, imm);
+  }
+
+  /// `cmp rn, rm`
+  pub fn cmp_reg(&mut self, size: Size, rn: Reg, rm: Reg) {
+    self.add_sub_reg(AddSub::Subs, size, Reg::ZR, rn, rm);
+  }
+
+  /// `mov rd, rm` (`orr rd, zr, rm`), neither may be sp
+  pub fn mov_reg(&mut self, size: Size, rd: Reg, rm: Reg) {
+    self.emit(size.sf() | 0x2a00_0000 | (rm as u32) << 16 | (Reg::ZR as u32) << 5 | rd as u32);
+  }
+
+  /// `mov rd, sp` (`add rd, sp, #0`)
+  pub fn mov_from_sp(&mut self, rd: Reg) {
+    self.add_sub_imm(AddSub::Add, Size::Dword, rd, Reg::Sp, 0);
+  }
+
+  /// `movz rd, #imm16, lsl #shift`
+  pub fn movz(&mut self, size: Size, rd: Reg, imm: u16, shift: u32) {
+    self.emit(size.sf() | 0x5280_0000 | (shift / 16) << 21 | (imm as u32) << 5 | rd as u32);
+  }
+
+  /// `movn rd, #imm16, lsl #shift`
+  pub fn movn(&mut self, size: Size, rd: Reg, imm: u16, shift: u32) {
+    self.emit(size.sf() | 0x1280_0000 | (shift / 16) << 21 | (imm as u32) << 5 | rd as u32);
+  }
+
+  /// `movk rd, #imm16, lsl #shift`
+  pub fn movk(&mut self, size: Size, rd: Reg, imm: u16, shift: u32) {
+    self.emit(size.sf() | 0x7280_0000 | (shift / 16) << 21 | (imm as u32) << 5 | rd as u32);
+  }
+
+  /// Load an arbitrary constant, with `movz` or `movn` followed by as many `movk` as needed
+  pub fn mov_imm(&mut self, size: Size, rd: Reg, imm: u64) {
+    let halves = size.bytes() as u32 / 2;
+    let imm = if size == Size::Word { imm as u32 as u64 } else { imm };
+    let half = |imm: u64, idx: u32| (imm >> (idx * 16)) as u16;
+    //Start from all ones instead of zeroes if that leaves fewer halves to fill in
+    let ones = (0..halves).filter(|&idx| half(imm, idx) == 0xffff).count();
+    let zeroes = (0..halves).filter(|&idx| half(imm, idx) == 0).count();
+    let (fill, first) = match ones > zeroes {
+      true => (0xffff, (0..halves).find(|&idx| half(imm, idx) != 0xffff).unwrap_or(0)),
+      false => (0, (0..halves).find(|&idx| half(imm, idx) != 0).unwrap_or(0)),
+    };
+    match fill {
+      0 => self.movz(size, rd, half(imm, first), first * 16),
+      _ => self.movn(size, rd, !half(imm, first), first * 16),
+    }
+    for idx in first + 1..halves {
+      if half(imm, idx) != fill {
+        self.movk(size, rd, half(imm, idx), idx * 16);
+      }
+    }
+  }
+
+  /// `madd rd, rn, rm, ra` (rd = ra + rn * rm)
+  pub fn madd(&mut self, size: Size, rd: Reg, rn: Reg, rm: Reg, ra: Reg) {
+    self.emit(size.sf() | 0x1b00_0000 | (rm as u32) << 16 | (ra as u32) << 10 | (rn as u32) << 5 | rd as u32);
+  }
+
+  /// Load or store with an immediate offset, picking the scaled unsigned or the unscaled signed form
+  fn load_store(&mut self, size: Size, load: bool, rt: Reg, mem: Mem) {
+    assert!(mem.encodable(size), "offset {} can't be encoded", mem.disp);
+    let base = (size.log2() << 30) | (load as u32) << 22 | (mem.base as u32) << 5 | rt as u32;
+    match mem.scaled_disp(size) {
+      Some(disp) => self.emit(base | 0x3900_0000 | disp << 10),
+      None => self.emit(base | 0x3800_0000 | (mem.disp as u32 & 0x1ff) << 12),
+    }
+  }
+
+  /// `ldrb`/`ldrh`/`ldr rt, [mem]`, zero-extending into a w register for sizes below `Word`
+  pub fn ldr(&mut self, size: Size, rt: Reg, mem: Mem) {
+    self.load_store(size, true, rt, mem);
+  }
+
+  /// `strb`/`strh`/`str rt, [mem]`
+  pub fn str(&mut self, size: Size, rt: Reg, mem: Mem) {
+    self.load_store(size, false, rt, mem);
+  }
+
+  /// `stp rt, rt2, [sp, #-frame]!`, pushing a pair of x registers and allocating the rest of the frame\
+  /// `frame` is at most 496 bytes, so that [`Assembler::pop_pair`] can release it
+  pub fn push_pair(&mut self, rt: Reg, rt2: Reg, frame: i32) {
+    assert!(frame % 16 == 0 && (16..=496).contains(&frame), "invalid frame size");
+    self.emit(0xa980_0000 | ((-frame / 8) as u32 & 0x7f) << 15 | (rt2 as u32) << 10 | (Reg::Sp as u32) << 5 | rt as u32);
+  }
+
+  /// `ldp rt, rt2, [sp], #frame`, the inverse of [`Assembler::push_pair`]
+  pub fn pop_pair(&mut self, rt: Reg, rt2: Reg, frame: i32) {
+    assert!(frame % 16 == 0 && (16..=496).contains(&frame), "invalid frame size");
+    self.emit(0xa8c0_0000 | ((frame / 8) as u32 & 0x7f) << 15 | (rt2 as u32) << 10 | (Reg::Sp as u32) << 5 | rt as u32);
+  }
+
+  /// `stp rt, rt2, [sp, #offset]`, for x registers
+  pub fn store_pair(&mut self, rt: Reg, rt2: Reg, offset: i32) {
+    self.emit(0xa900_0000 | ((offset / 8) as u32 & 0x7f) << 15 | (rt2 as u32) << 10 | (Reg::Sp as u32) << 5 | rt as u32);
+  }
+
+  /// `ldp rt, rt2, [sp, #offset]`, for x registers
+  pub fn load_pair(&mut self, rt: Reg, rt2: Reg, offset: i32) {
+    self.emit(0xa940_0000 | ((offset / 8) as u32 & 0x7f) << 15 | (rt2 as u32) << 10 | (Reg::Sp as u32) << 5 | rt as u32);
+  }
+
+  /// `svc #0`
+  pub fn svc(&mut self) {
+    self.emit(0xd400_0001);
+  }
+
+  /// `blr rn`
+  pub fn blr(&mut self, rn: Reg) {
+    self.emit(0xd63f_0000 | (rn as u32) << 5);
+  }
+
+  /// `ret`
+  pub fn ret(&mut self) {
+    self.emit(0xd65f_03c0);
+  }
+
+  /// Whether `target` is in reach of a branch at the current position with a `bits`-bit offset
+  fn in_range(&self, target: usize, bits: u32) -> bool {
+    let offset = (target as isize - self.pos() as isize) / 4;
+    offset.unsigned_abs() < 1 << (bits - 1)
+  }
+
+  /// Offset from the current position to `target`, in instructions
+  fn offset_to(&self, target: usize, bits: u32) -> u32 {
+    assert!(self.in_range(target, bits), "branch out of range");
+    let offset = (target as isize - self.pos() as isize) / 4;
+    offset as u32 & ((1 << bits) - 1)
+  }
+
+  /// `b target`
+  pub fn b(&mut self, target: usize) {
+    self.emit(0x1400_0000 | self.offset_to(target, 26));
+  }
+
+  /// `b.cond target`, or `b.<inverted cond>` over a `b target` if it's more than 1MB away
+  pub fn b_cond(&mut self, cond: Cond, target: usize) {
+    if !self.in_range(target, 19) {
+      self.b_cond(cond.invert(), self.pos() + 8);
+      return self.b(target)
+    }
+    self.emit(0x5400_0000 | self.offset_to(target, 19) << 5 | cond as u32);
+  }
+
+  /// `cbz`/`cbnz rt, target`, or the opposite one over a `b target` if it's more than 1MB away
+  pub fn cbz(&mut self, size: Size, rt: Reg, nonzero: bool, target: usize) {
+    if !self.in_range(target, 19) {
+      self.cbz(size, rt, !nonzero, self.pos() + 8);
+      return self.b(target)
+    }
+    self.emit(size.sf() | 0x3400_0000 | (nonzero as u32) << 24 | self.offset_to(target, 19) << 5 | rt as u32);
+  }
+
+  /// Branch to a position that is not known yet
+  pub fn b_forward(&mut self) -> Fixup {
+    let pos = self.pos();
+    self.emit(0x1400_0000);
+    Fixup { pos, long: true }
+  }
+
+  /// Call to a position that is not known yet
+  pub fn bl_forward(&mut self) -> Fixup {
+    let pos = self.pos();
+    self.emit(0x9400_0000);
+    Fixup { pos, long: true }
+  }
+
+  /// Conditional branch to a position that is not known yet\
+  /// Short branches reach at most 1MB and panic on bind if the target turns out to be further away,
+  /// long ones branch over a `b` with the inverted condition
+  pub fn b_cond_forward(&mut self, cond: Cond, short: bool) -> Fixup {
+    if !short {
+      self.b_cond(cond.invert(), self.pos() + 8);
+      return self.b_forward()
+    }
+    let pos = self.pos();
+    self.emit(0x5400_0000 | cond as u32);
+    Fixup { pos, long: false }
+  }
+
+  /// `cbz`/`cbnz` to a position that is not known yet, see [`Assembler::b_cond_forward`] for `short`
+  pub fn cbz_forward(&mut self, size: Size, rt: Reg, nonzero: bool, short: bool) -> Fixup {
+    if !short {
+      self.cbz(size, rt, !nonzero, self.pos() + 8);
+      return self.b_forward()
+    }
+    let pos = self.pos();
+    self.emit(size.sf() | 0x3400_0000 | (nonzero as u32) << 24 | rt as u32);
+    Fixup { pos, long: false }
+  }
+
+  /// Resolve a forward branch to the current position
+  pub fn bind(&mut self, fixup: Fixup) {
+    let offset = (self.pos() - fixup.pos) / 4;
+    let field = match fixup.long {
+      true => {
+        assert!(offset < 1 << 25, "branch out of range");
+        offset as u32
+      },
+      false => {
+        assert!(offset < 1 << 18, "branch out of range");
+        (offset as u32) << 5
+      },
+    };
+    let inst = u32::from_le_bytes(self.code[fixup.pos..fixup.pos + 4].try_into().unwrap()) | field;
+    self.code[fixup.pos..fixup.pos + 4].copy_from_slice(&inst.to_le_bytes());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Instruction words emitted by `f`
+  fn encode(f: impl FnOnce(&mut Assembler)) -> Vec<u32> {
+    let mut asm = Assembler::new();
+    f(&mut asm);
+    asm.into_code().chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+  }
+
+  /// `nop`
+  fn nops(asm: &mut Assembler, count: usize) {
+    for _ in 0..count {
+      asm.emit(0xd503_201f);
+    }
+  }
+
+  #[test]
+  fn loads_and_stores() {
+    let ldr = |size, disp| encode(|asm| asm.ldr(size, Reg::X0, Mem::base(Reg::X19, disp)));
+    assert_eq!(ldr(Size::Byte, 0), [0x3940_0260]);
+    assert_eq!(ldr(Size::Byte, 4095), [0x397f_fe60]);
+    assert_eq!(ldr(Size::Byte, -1), [0x385f_f260]);
+    assert_eq!(ldr(Size::Half, 2), [0x7940_0660]);
+    assert_eq!(ldr(Size::Half, 8190), [0x797f_fe60]);
+    assert_eq!(ldr(Size::Half, 1), [0x7840_1260]);
+    assert_eq!(ldr(Size::Word, 16380), [0xb97f_fe60]);
+    assert_eq!(ldr(Size::Word, 255), [0xb84f_f260]);
+    assert_eq!(ldr(Size::Dword, 32760), [0xf97f_fe60]);
+    assert_eq!(encode(|asm| asm.str(Size::Byte, Reg::X1, Mem::base(Reg::X19, 4095))), [0x393f_fe61]);
+    assert_eq!(encode(|asm| asm.str(Size::Byte, Reg::X1, Mem::base(Reg::X19, -256))), [0x3810_0261]);
+    assert_eq!(encode(|asm| asm.str(Size::Half, Reg::X2, Mem::base(Reg::X19, -2))), [0x781f_e262]);
+    assert_eq!(encode(|asm| asm.str(Size::Word, Reg::X0, Mem::base(Reg::X19, 4))), [0xb900_0660]);
+    //Misaligned offsets fall back to the unscaled form
+    assert_eq!(encode(|asm| asm.str(Size::Word, Reg::X0, Mem::base(Reg::X19, 6))), [0xb800_6260]);
+    assert_eq!(encode(|asm| asm.str(Size::Dword, Reg::X1, Mem::base(Reg::Sp, 8))), [0xf900_07e1]);
+    assert_eq!(encode(|asm| asm.str(Size::Dword, Reg::X0, Mem::base(Reg::X19, -8))), [0xf81f_8260]);
+  }
+
+  #[test]
+  fn encodable_offsets() {
+    let encodable = |size, disp| Mem::base(Reg::X19, disp).encodable(size);
+    assert!(encodable(Size::Byte, 4095));
+    assert!(!encodable(Size::Byte, 4096));
+    assert!(encodable(Size::Byte, -256));
+    assert!(!encodable(Size::Byte, -257));
+    assert!(encodable(Size::Half, 255));
+    assert!(!encodable(Size::Half, 257));
+    assert!(encodable(Size::Half, 8190));
+    assert!(!encodable(Size::Half, 8192));
+    assert!(encodable(Size::Word, 6));
+    assert!(!encodable(Size::Word, 258));
+    assert!(!encodable(Size::Word, 16384));
+    assert!(encodable(Size::Dword, 32760));
+    assert!(!encodable(Size::Dword, 32768));
+    assert!(!encodable(Size::Dword, -264));
+  }
+
+  #[test]
+  #[should_panic(expected = "offset 257 can't be encoded")]
+  fn unencodable_offset() {
+    encode(|asm| asm.ldr(Size::Half, Reg::X0, Mem::base(Reg::X19, 257)));
+  }
+
+  #[test]
+  fn add_sub() {
+    assert_eq!(encode(|asm| asm.add_sub_imm(AddSub::Add, Size::Dword, Reg::X0, Reg::X1, 4095)), [0x913f_fc20]);
+    assert_eq!(encode(|asm| asm.add_sub_imm(AddSub::Add, Size::Dword, Reg::X0, Reg::X1, 0x1000)), [0x9140_0420]);
+    assert_eq!(encode(|asm| asm.add_sub_imm(AddSub::Sub, Size::Dword, Reg::Sp, Reg::Sp, 0x1000)), [0xd140_07ff]);
+    assert_eq!(encode(|asm| asm.add_sub_imm(AddSub::Sub, Size::Word, Reg::X0, Reg::X1, 0xff_f000)), [0x517f_fc20]);
+    assert_eq!(encode(|asm| asm.add_sub_imm(AddSub::Adds, Size::Dword, Reg::X2, Reg::X3, 7)), [0xb100_1c62]);
+    assert_eq!(encode(|asm| asm.cmp_imm(Size::Word, Reg::X9, 0)), [0x7100_013f]);
+    assert_eq!(encode(|asm| asm.mov_from_sp(Reg::X0)), [0x9100_03e0]);
+    assert_eq!(encode(|asm| asm.add_sub_reg(AddSub::Add, Size::Dword, Reg::X9, Reg::X19, Reg::X10)), [0x8b0a_0269]);
+    assert_eq!(encode(|asm| asm.cmp_reg(Size::Dword, Reg::X9, Reg::X10)), [0xeb0a_013f]);
+    assert_eq!(encode(|asm| asm.mov_reg(Size::Word, Reg::X0, Reg::X1)), [0x2a01_03e0]);
+  }
+
+  #[test]
+  #[should_panic(expected = "immediate 0x1001 can't be encoded")]
+  fn unencodable_immediate() {
+    encode(|asm| asm.add_sub_imm(AddSub::Add, Size::Dword, Reg::X0, Reg::X1, 0x1001));
+  }
+
+  #[test]
+  fn mov_imm() {
+    let mov = |size, imm| encode(|asm| asm.mov_imm(size, Reg::X0, imm));
+    assert_eq!(mov(Size::Dword, 0), [0xd280_0000]);
+    assert_eq!(mov(Size::Word, 0x1234_5678), [0x528a_cf00, 0x72a2_4680]);
+    assert_eq!(mov(Size::Dword, 1 << 32), [0xd2c0_0020]);
+    assert_eq!(mov(Size::Dword, 0x1_0000_ffff), [0xd29f_ffe0, 0xf2c0_0020]);
+    assert_eq!(mov(Size::Word, 0xffff_0000), [0x52bf_ffe0]);
+    //Mostly ones, starting from movn
+    assert_eq!(mov(Size::Dword, u64::MAX), [0x9280_0000]);
+    assert_eq!(mov(Size::Dword, 0xffff_ffff_ffff_1234), [0x929d_b960]);
+    assert_eq!(mov(Size::Word, -2i64 as u64), [0x1280_0020]);
+  }
+
+  #[test]
+  fn misc() {
+    assert_eq!(encode(|asm| asm.madd(Size::Word, Reg::X0, Reg::X1, Reg::X2, Reg::X3)), [0x1b02_0c20]);
+    assert_eq!(encode(|asm| asm.madd(Size::Dword, Reg::X9, Reg::X10, Reg::X11, Reg::ZR)), [0x9b0b_7d49]);
+    assert_eq!(encode(|asm| asm.svc()), [0xd400_0001]);
+    assert_eq!(encode(|asm| asm.blr(Reg::X16)), [0xd63f_0200]);
+    assert_eq!(encode(|asm| asm.ret()), [0xd65f_03c0]);
+  }
+
+  #[test]
+  fn pairs() {
+    assert_eq!(encode(|asm| asm.push_pair(Reg::FP, Reg::LR, 16)), [0xa9bf_7bfd]);
+    assert_eq!(encode(|asm| asm.push_pair(Reg::X19, Reg::X20, 496)), [0xa9a1_53f3]);
+    assert_eq!(encode(|asm| asm.pop_pair(Reg::FP, Reg::LR, 16)), [0xa8c1_7bfd]);
+    assert_eq!(encode(|asm| asm.pop_pair(Reg::FP, Reg::LR, 496)), [0xa8df_7bfd]);
+    assert_eq!(encode(|asm| asm.store_pair(Reg::X21, Reg::X22, 16)), [0xa901_5bf5]);
+    assert_eq!(encode(|asm| asm.load_pair(Reg::X21, Reg::X22, 16)), [0xa941_5bf5]);
+  }
+
+  #[test]
+  #[should_panic(expected = "invalid frame size")]
+  fn frame_too_large() {
+    //ldp can't pop 512 bytes, so stp doesn't push them either
+    encode(|asm| asm.push_pair(Reg::FP, Reg::LR, 512));
+  }
+
+  #[test]
+  fn backward_branches() {
+    assert_eq!(encode(|asm| { nops(asm, 2); asm.b(0) })[2], 0x17ff_fffe);
+    assert_eq!(encode(|asm| { nops(asm, 3); asm.b_cond(Cond::Lo, 0) })[3], 0x54ff_ffa3);
+    assert_eq!(encode(|asm| asm.cbz(Size::Word, Reg::X9, false, 8)), [0x3400_0049]);
+    assert_eq!(encode(|asm| asm.cbz(Size::Dword, Reg::X9, true, 4)), [0xb500_0029]);
+    //Furthest conditional branch back
+    assert_eq!(encode(|asm| { nops(asm, (1 << 18) - 1); asm.b_cond(Cond::Eq, 0) })[(1 << 18) - 1], 0x5480_0020);
+  }
+
+
+  #[test]
+  fn forward_branches() {
+    let forward = |f: fn(&mut Assembler) -> Fixup, count| encode(|asm| {
+      let fixup = f(asm);
+      nops(asm, count);
+      asm.bind(fixup);
+    })[0];
+    assert_eq!(forward(|asm| asm.b_forward(), 0), 0x1400_0001);
+    assert_eq!(forward(|asm| asm.bl_forward(), 2), 0x9400_0003);
+    assert_eq!(forward(|asm| asm.b_cond_forward(Cond::Ne, true), 1), 0x5400_0041);
+    assert_eq!(forward(|asm| asm.cbz_forward(Size::Word, Reg::X10, false, true), 0), 0x3400_002a);
+    //Furthest conditional branch forward, and a longer unconditional one
+    assert_eq!(forward(|asm| asm.cbz_forward(Size::Word, Reg::X9, false, true), (1 << 18) - 2), 0x347f_ffe9);
+    assert_eq!(forward(|asm| asm.b_forward(), (1 << 18) - 1), 0x1404_0000);
+  }
+
+  #[test]
+  fn long_branches() {
+    //Inverted conditional branches over a b
+    let forward = |f: fn(&mut Assembler) -> Fixup| encode(|asm| {
+      let fixup = f(asm);
+      nops(asm, 1 << 18);
+      asm.bind(fixup);
+    })[..2].to_vec();
+    assert_eq!(forward(|asm| asm.b_cond_forward(Cond::Hs, false)), [0x5400_0043, 0x1404_0001]);
+    assert_eq!(forward(|asm| asm.cbz_forward(Size::Word, Reg::X9, false, false)), [0x3500_0049, 0x1404_0001]);
+    //Backward ones only once out of range
+    let backward = |f: fn(&mut Assembler)| {
+      let code = encode(|asm| { nops(asm, 1 << 18); f(asm) });
+      code[1 << 18..].to_vec()
+    };
+    assert_eq!(backward(|asm| asm.cbz(Size::Word, Reg::X9, true, 0)), [0x3400_0049, 0x17fb_ffff]);
+    assert_eq!(backward(|asm| asm.b_cond(Cond::Eq, 0)), [0x5400_0041, 0x17fb_ffff]);
+  }
+
+  #[test]
+  #[should_panic(expected = "branch out of range")]
+  fn forward_branch_out_of_range() {
+    encode(|asm| {
+      let fixup = asm.cbz_forward(Size::Word, Reg::X9, false, true);
+      nops(asm, (1 << 18) - 1);
+      asm.bind(fixup);
+    });
+  }
+}
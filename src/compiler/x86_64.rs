@@ -1,309 +1,498 @@
-use std::{rc::Rc, cell::RefCell};
-use crate::brainfuck::{BfOpBlock, Effect};
-use super::{CompilerImpl, Target};
+use std::mem::offset_of;
+use crate::{bfil::Op, brainfuck::CellWidth, io::{RunContext, RunStatus}};
+use super::{AsmSyntax, CompiledCode, CompilerImpl, CompilerOptions, FailedCheck, FlushPolicy, Instruction, MulAddGroup, MulAddSlowPath, Target};
 
-/// add rbx, imm
-fn add_to_rbx(code: &mut Vec<u8>, imm: i32) {
-  match imm {
-    0 => (), //no-op
+pub mod encoder;
+pub mod decoder;
+use encoder::{Alu, Assembler, Cond, Fixup, Mem, Reg, Shift, Size, Xmm};
 
-    //inc rbx
-    1 => {
-      // println!("inc rbx");
-      code.extend([0x48, 0xff, 0xc3])
-    },
+/// Register holding the data pointer
+const PTR: Reg = Reg::Rbx;
+/// Register holding the write position in the output buffer\
+/// The buffer itself lives at the top of the stack, at [rsp]
+const OUT_PTR: Reg = Reg::R12;
+/// Register holding the [`RunContext`] pointer, for [`Target::Host`]
+const CTX: Reg = Reg::R13;
+/// Size of the output buffer in bytes
+const OUTPUT_BUFFER_SIZE: i32 = 0x1000;
+/// Linux syscall numbers, which don't depend on the host the code is generated on
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_EXIT: i32 = 60;
 
-    //dec rbx
-    -1 => {
-      // println!("dec rbx");
-      code.extend([0x48, 0xff, 0xcb])
-    },
-
-    //add rbx, imm8
-    2..=0x7f => {
-      // println!("add rbx, {} ;(imm8)", imm as u8);
-      code.extend([0x48, 0x83, 0xc3, imm as u8]);
-    },
+/// `call [RunContext::<callback>]`, with the context as the first argument
+fn gen_host_call(asm: &mut Assembler, callback_offset: usize) {
+  asm.mov_rm_reg(Size::Qword, Reg::Rdi, CTX);
+  asm.call_rm(Mem::base(CTX, callback_offset as i32));
+}
 
-    //sub rbx, imm8
-    -0x80..=-2 => {
-      // println!("sub rbx, {} ;(imm8)", imm as u8);
-      code.extend([0x48, 0x83, 0xeb, (-imm) as u8]);
-    }
+/// Operand size of a single cell
+fn cell_size(width: CellWidth) -> Size {
+  match width {
+    CellWidth::U8 => Size::Byte,
+    CellWidth::U16 => Size::Word,
+    CellWidth::U32 => Size::Dword,
+  }
+}
 
-    //add rbx, imm32
-    imm if imm > 0 => {
-      // println!("add rbx, {} ;(imm32)", imm);
-      code.extend([0x48, 0x81, 0xc3]);
-      code.extend(imm.to_le_bytes());
-    }
+/// [rbx + offset], with `offset` in cells
+fn cell(offset: i32, width: CellWidth) -> Mem {
+  Mem::base(PTR, offset * width.bytes() as i32)
+}
 
-    //sub rbx, imm32
-    _ => {
-      // println!("sub rbx, {} ;(imm32)", imm);
-      code.extend([0x48, 0x81, 0xeb]);
-      code.extend((-imm).to_le_bytes());
-    }
+/// add rbx, cells
+fn add_to_rbx(asm: &mut Assembler, cells: i32, width: CellWidth) {
+  match cells * width.bytes() as i32 {
+    0 => (), //no-op
+    1 => asm.inc(Size::Qword, PTR),
+    -1 => asm.dec(Size::Qword, PTR),
+    imm => asm.alu_imm(Alu::Add, Size::Qword, PTR, imm),
   }
 }
 
-/// add byte ptr [rbx + offset], imm
-fn add_to_ptr_rbx(code: &mut Vec<u8>, offset: i32, imm: i16) {
-  match (offset, imm) {
-    (_, 0) => (), //no-op
+/// add <cell> ptr [rbx + offset], imm
+fn add_to_ptr_rbx(asm: &mut Assembler, offset: i32, imm: i32, width: CellWidth) {
+  let size = cell_size(width);
+  match width.wrap(imm as u32) {
+    0 => (), //no-op
+    1 => asm.inc(size, cell(offset, width)),
+    imm if imm == width.max() => asm.dec(size, cell(offset, width)),
+    _ => asm.alu_imm(Alu::Add, size, cell(offset, width), width.wrap_inc(imm)),
+  }
+}
 
-    //inc byte [rbx]
-    (0, 1)  => {
-      // println!("inc byte ptr [rbx]");
-      code.extend([0xfe, 0x03])
-    }
+/// mov <cell> ptr [rbx + offset], value
+fn gen_set_cell(asm: &mut Assembler, offset: i32, value: u32, width: CellWidth) {
+  asm.mov_imm(cell_size(width), cell(offset, width), value as i32);
+}
 
-    //inc byte [rbx + offset]
-    (_, 1)  => {
-      // println!("inc byte ptr [rbx + {}]", offset as u8);
-      code.extend([0xfe, 0x43, offset as u8])
+/// while [rbx] != 0 { rbx += stride }
+fn gen_scan(asm: &mut Assembler, stride: i32, width: CellWidth) {
+  let mut jumps_to_end = vec![];
+  match stride {
+    //memchr-style SSE2 scan, 16 cells at a time\
+    //Only aligned loads are used, so we never touch a page the plain loop wouldn't touch
+    1 | -1 if width == CellWidth::U8 => {
+      asm.alu_imm(Alu::Cmp, Size::Byte, cell(0, width), 0);
+      jumps_to_end.push(asm.jcc_forward(Cond::E, true));
+      asm.mov_rm_reg(Size::Qword, Reg::Rax, PTR);
+      asm.alu_imm(Alu::And, Size::Qword, Reg::Rax, -16);
+      asm.mov_rm_reg(Size::Dword, Reg::Rcx, PTR);
+      if stride == -1 {
+        asm.not(Size::Dword, Reg::Rcx);
+      }
+      asm.alu_imm(Alu::And, Size::Dword, Reg::Rcx, 15);
+      asm.pxor(Xmm::Xmm1, Xmm::Xmm1);
+      asm.movdqa_load(Xmm::Xmm0, Mem::base(Reg::Rax, 0));
+      asm.pcmpeqb(Xmm::Xmm0, Xmm::Xmm1);
+      asm.pmovmskb(Reg::Rdx, Xmm::Xmm0);
+      //Discard the cells behind the starting position
+      //(shifting by 0 does not update flags, hence the test)
+      if stride == 1 {
+        asm.shift_cl(Shift::Shr, Size::Dword, Reg::Rdx);
+      } else {
+        asm.shift_cl(Shift::Shl, Size::Word, Reg::Rdx);
+      }
+      asm.test(Size::Dword, Reg::Rdx, Reg::Rdx);
+      let found_in_first_block = asm.jcc_forward(Cond::Ne, true);
+      let loop_head = asm.pos();
+      asm.alu_imm(Alu::Add, Size::Qword, Reg::Rax, 16 * stride);
+      if stride == -1 {
+        //Touch the cell the plain loop would access next first, so running off the start of the tape
+        //faults at that cell rather than at the start of the block
+        asm.alu_imm(Alu::Cmp, Size::Byte, Mem::base(Reg::Rax, 15), 0);
+      }
+      asm.movdqa_load(Xmm::Xmm0, Mem::base(Reg::Rax, 0));
+      asm.pcmpeqb(Xmm::Xmm0, Xmm::Xmm1);
+      asm.pmovmskb(Reg::Rdx, Xmm::Xmm0);
+      asm.test(Size::Dword, Reg::Rdx, Reg::Rdx);
+      asm.jcc(Cond::E, loop_head);
+      if stride == 1 {
+        asm.bsf(Size::Dword, Reg::Rdx, Reg::Rdx);
+      } else {
+        asm.bsr(Size::Dword, Reg::Rdx, Reg::Rdx);
+      }
+      asm.lea(PTR, Mem::indexed(Reg::Rax, Reg::Rdx, 1, 0));
+      jumps_to_end.push(asm.jmp_forward(true));
+      asm.bind(found_in_first_block);
+      if stride == 1 {
+        asm.bsf(Size::Dword, Reg::Rdx, Reg::Rdx);
+        asm.alu_rm_reg(Alu::Add, Size::Qword, PTR, Reg::Rdx);
+      } else {
+        //The mask was shifted left by 15 - (rbx & 15)
+        asm.bsr(Size::Dword, Reg::Rdx, Reg::Rdx);
+        asm.lea(PTR, Mem::indexed(PTR, Reg::Rdx, 1, -15));
+      }
     },
-
-    //dec byte [rbx]
-    (0, -1) => {
-      // println!("dec byte ptr [rbx]");
-      code.extend([0xfe, 0x0b])
+    //Plain loop, unrolled 4 times
+    _ => {
+      let loop_head = asm.pos();
+      for _ in 0..4 {
+        asm.alu_imm(Alu::Cmp, cell_size(width), cell(0, width), 0);
+        jumps_to_end.push(asm.jcc_forward(Cond::E, true));
+        add_to_rbx(asm, stride, width);
+      }
+      asm.jmp(loop_head);
     },
+  }
+  for jump in jumps_to_end {
+    asm.bind(jump);
+  }
+}
 
-    //dec byte [rbx + offset]
-    (_, -1) => {
-      // println!("dec byte ptr [rbx + {}]", offset as u8);
-      code.extend([0xfe, 0x4b, offset as u8])
-    },
+/// Jumps and calls to code emitted after the function body\
+/// Jumps taken by failed bounds checks have the out of bounds address in rax
+type Fixups = super::Fixups<Fixup>;
 
-    //add byte [rbx], imm8
-    (0, _) => {
-      // println!("add byte ptr [rbx], {}", imm as u8);
-      code.extend([0x80, 0x03, imm as u8]);
-    },
+/// Exit with [`RunStatus::TapeUnderflow`] if [rbx + offset] lies before the start of the tape
+fn gen_check_start(asm: &mut Assembler, offset: i32, width: CellWidth, fixups: &mut Fixups) {
+  let pos = asm.pos();
+  asm.lea(Reg::Rax, cell(offset, width));
+  asm.alu_reg_rm(Alu::Cmp, Size::Qword, Reg::Rax, Mem::base(CTX, offset_of!(RunContext, tape_start) as i32));
+  let jump = asm.jcc_forward(Cond::B, false);
+  fixups.out_of_bounds.push(FailedCheck { jump, status: RunStatus::TapeUnderflow, pos });
+}
 
-    //add byte [rbx + offset], imm8
-    (_, _) => {
-      // println!("add byte ptr [rbx + {}], {}", offset as u8, imm as u8);
-      code.extend([0x80, 0x43, offset as u8, imm as u8]);
-    },
-  }
+/// Exit with [`RunStatus::TapeOverflow`] if [rbx + offset] lies past the end of the tape\
+/// The tape holds a whole number of cells, so checking the first byte of the cell is enough
+fn gen_check_end(asm: &mut Assembler, offset: i32, width: CellWidth, fixups: &mut Fixups) {
+  let pos = asm.pos();
+  asm.lea(Reg::Rax, cell(offset, width));
+  asm.alu_reg_rm(Alu::Cmp, Size::Qword, Reg::Rax, Mem::base(CTX, offset_of!(RunContext, tape_end) as i32));
+  let jump = asm.jcc_forward(Cond::Ae, false);
+  fixups.out_of_bounds.push(FailedCheck { jump, status: RunStatus::TapeOverflow, pos });
 }
 
-/// je rel (near)
-fn je32(code: &mut Vec<u8>, rel: i32) {
-  // println!("je {:+} ;(NEAR; imm32)", rel);
-  code.extend([0x0f, 0x84]);
-  code.extend(rel.to_le_bytes());
+/// Report the status, offset and position of a failed bounds check in [`RunContext`], then jump to `exit`
+fn gen_out_of_bounds_exit(asm: &mut Assembler, checks: Vec<FailedCheck<Fixup>>, exit: usize) {
+  if checks.is_empty() {
+    return
+  }
+  let report = asm.pos();
+  asm.mov_rm_reg(Size::Dword, Mem::base(CTX, offset_of!(RunContext, status) as i32), Reg::Rdx);
+  asm.mov_rm_reg(Size::Qword, Mem::base(CTX, offset_of!(RunContext, check_offset) as i32), Reg::Rcx);
+  asm.alu_reg_rm(Alu::Sub, Size::Qword, Reg::Rax, Mem::base(CTX, offset_of!(RunContext, tape_start) as i32));
+  asm.mov_rm_reg(Size::Qword, Mem::base(CTX, offset_of!(RunContext, fault_offset) as i32), Reg::Rax);
+  asm.jmp(exit);
+  //One stub per check, with the status in edx and the position of the check in ecx
+  for check in checks {
+    asm.bind(check.jump);
+    asm.mov_imm(Size::Dword, Reg::Rdx, check.status as i32);
+    asm.mov_imm(Size::Dword, Reg::Rcx, check.pos as i32);
+    asm.jmp(report);
+  }
 }
 
-/// je rel (short/near)
-fn je(code: &mut Vec<u8>, rel: i32) {
-  match rel {
-    0 => (), //no-op
-    -0x80..=0x7f => {
-      // println!("je {:+} ;(SHORT; imm8)", rel);
-      code.extend([0x74, rel as u8]);
-    },
-    _ => je32(code, rel),
+/// while [rbx] != 0 { rbx += stride }, exiting before the pointer leaves the tape
+fn gen_checked_scan(asm: &mut Assembler, stride: i32, width: CellWidth, fixups: &mut Fixups) {
+  let loop_head = asm.pos();
+  asm.alu_imm(Alu::Cmp, cell_size(width), cell(0, width), 0);
+  let end = asm.jcc_forward(Cond::E, true);
+  if stride < 0 {
+    gen_check_start(asm, stride, width, fixups);
+  } else {
+    gen_check_end(asm, stride, width, fixups);
   }
+  add_to_rbx(asm, stride, width);
+  asm.jmp(loop_head);
+  asm.bind(end);
 }
 
-/// jne rel (short/near) with optional correction for instruction size
-fn jne(code: &mut Vec<u8>, mut rel: i32, correct_for_instruction_size: bool) {
-  if rel < 0 && correct_for_instruction_size {
-    rel -= 2;
+/// Append the low byte of a cell to the output buffer, flushing it according to the flush policy
+fn gen_output(asm: &mut Assembler, offset: i32, width: CellWidth, policy: FlushPolicy, fixups: &mut Fixups) {
+  asm.movzx(Size::Byte, Reg::Rax, cell(offset, width));
+  asm.mov_rm_reg(Size::Byte, Mem::base(OUT_PTR, 0), Reg::Rax);
+  asm.inc(Size::Qword, OUT_PTR);
+  if policy == FlushPolicy::Byte {
+    fixups.flush_calls.push(asm.call_forward());
+    return
   }
-  match rel {
-    0 => (), //no-op
-    -0x80..=0x7f => {
-      // println!("jne {:+} ;(SHORT; imm8)", rel);
-      code.extend([0x75, rel as u8]);
-    },
-    _ => {
-      rel -= 4;
-      // println!("jne {:+} ;(NEAR; imm32)", rel);
-      code.extend([0x0f, 0x85]);
-      code.extend(rel.to_le_bytes());
-    },
+  let newline = (policy == FlushPolicy::Line).then(|| {
+    asm.alu_imm(Alu::Cmp, Size::Byte, Reg::Rax, b'\n' as i32);
+    asm.jcc_forward(Cond::E, true)
+  });
+  //Flush if the buffer is full
+  asm.lea(Reg::Rax, Mem::base(Reg::Rsp, OUTPUT_BUFFER_SIZE));
+  asm.alu_rm_reg(Alu::Cmp, Size::Qword, OUT_PTR, Reg::Rax);
+  let not_full = asm.jcc_forward(Cond::B, true);
+  if let Some(newline) = newline {
+    asm.bind(newline);
   }
+  fixups.flush_calls.push(asm.call_forward());
+  asm.bind(not_full);
 }
 
-fn gen_set_cell(code: &mut Vec<u8>, key: i32, value: u8) {
-  match value {
-    0 => {
-      // println!("xor al, al");
-      code.extend([0x30, 0xC0]);
-    }
-    _ => {
-      // println!("mov al, 0x{value:02x}");
-      code.extend([0xb0, value]);
-    }
+/// Subroutine writing out and emptying the output buffer, called with `call`
+fn gen_flush_routine(asm: &mut Assembler, target: Target) {
+  //The buffer starts right above our return address
+  asm.lea(Reg::Rsi, Mem::base(Reg::Rsp, 8));
+  if target == Target::Host {
+    asm.mov_rm_reg(Size::Qword, Reg::Rdx, OUT_PTR);
+    asm.alu_rm_reg(Alu::Sub, Size::Qword, Reg::Rdx, Reg::Rsi);
+    let empty = asm.jcc_forward(Cond::E, true);
+    //Realign the stack, which is off by our return address
+    asm.alu_imm(Alu::Sub, Size::Qword, Reg::Rsp, 8);
+    gen_host_call(asm, offset_of!(RunContext, write_bytes));
+    asm.alu_imm(Alu::Add, Size::Qword, Reg::Rsp, 8);
+    asm.bind(empty);
+    asm.lea(OUT_PTR, Mem::base(Reg::Rsp, 8));
+    asm.ret();
+    return
+  }
+  //write(2) may write less than requested, so keep going until everything is written
+  let write_loop = asm.pos();
+  asm.mov_rm_reg(Size::Qword, Reg::Rdx, OUT_PTR);
+  asm.alu_rm_reg(Alu::Sub, Size::Qword, Reg::Rdx, Reg::Rsi);
+  let empty = asm.jcc_forward(Cond::Be, true);
+  asm.mov_imm(Size::Dword, Reg::Rax, SYS_WRITE);
+  asm.mov_imm(Size::Dword, Reg::Rdi, 1);
+  asm.syscall();
+  //On errors, the rest of the buffer is dropped
+  asm.test(Size::Qword, Reg::Rax, Reg::Rax);
+  let failed = asm.jcc_forward(Cond::Le, true);
+  asm.alu_rm_reg(Alu::Add, Size::Qword, Reg::Rsi, Reg::Rax);
+  asm.jmp(write_loop);
+  asm.bind(empty);
+  asm.bind(failed);
+  asm.lea(OUT_PTR, Mem::base(Reg::Rsp, 8));
+  asm.ret();
+}
+
+/// Load the source cell of a MulAdd group into ecx, returning the jumps to its slow path
+///
+/// If the source is 0 the group is a no-op, but unlike the loop it came from it still touches
+/// the destination cells, which may lie outside of the tape.\
+/// For [`Target::Host`], groups reaching past the tape bounds in [`RunContext`] are skipped in that case,
+/// so only programs actually accessing those cells hit a guard region, or fail the bounds check if checked.
+fn start_mul_add_group(asm: &mut Assembler, group: &MulAddGroup, target: Target, width: CellWidth) -> Vec<FailedCheck<Fixup>> {
+  let MulAddGroup { src, min_dst, max_dst, .. } = *group;
+  match cell_size(width) {
+    Size::Dword => asm.mov_reg_rm(Size::Dword, Reg::Rcx, cell(src as i32, width)),
+    size => asm.movzx(size, Reg::Rcx, cell(src as i32, width)),
   }
-  match key {
-    0 => {
-      // println!("mov [rbx], al");
-      code.extend([0x88, 0x03]);
+  let mut jumps = vec![];
+  //The source cell itself is within bounds, otherwise loading it would've faulted\
+  //The checks are almost always passed, so unlike testing the source they're well predicted
+  if target == Target::Host {
+    if min_dst < src {
+      let pos = asm.pos();
+      asm.lea(Reg::Rax, cell(min_dst as i32, width));
+      asm.alu_reg_rm(Alu::Cmp, Size::Qword, Reg::Rax, Mem::base(CTX, offset_of!(RunContext, tape_start) as i32));
+      jumps.push(FailedCheck { jump: asm.jcc_forward(Cond::B, false), status: RunStatus::TapeUnderflow, pos });
     }
-    -0x80..=0x7F => {
-      // println!("mov [rbx + {}], al; (imm8)", key);
-      code.extend([0x88, 0x43, key as u8]);
+    if max_dst > src {
+      let pos = asm.pos();
+      asm.lea(Reg::Rax, cell(max_dst as i32, width));
+      asm.alu_reg_rm(Alu::Cmp, Size::Qword, Reg::Rax, Mem::base(CTX, offset_of!(RunContext, tape_end) as i32));
+      jumps.push(FailedCheck { jump: asm.jcc_forward(Cond::Ae, false), status: RunStatus::TapeOverflow, pos });
     }
-    _ => {
-      // println!("mov [rbx + {}], al; (imm32)", key);
-      code.extend([0x88, 0x83]);
-      code.extend(key.to_le_bytes());
+  }
+  jumps
+}
+
+/// Skip the group if its source is 0, otherwise do the accesses or report the failed bounds check if `checked`
+fn gen_mul_add_slow_path(asm: &mut Assembler, slow_path: MulAddSlowPath<Fixup>, checked: bool, fixups: &mut Fixups) {
+  if checked {
+    for check in slow_path.jumps {
+      asm.bind(check.jump);
+      asm.test(Size::Dword, Reg::Rcx, Reg::Rcx);
+      asm.jcc(Cond::E, slow_path.end);
+      fixups.out_of_bounds.push(FailedCheck { jump: asm.jmp_forward(false), ..check });
     }
+    return
+  }
+  for check in slow_path.jumps {
+    asm.bind(check.jump);
   }
+  asm.test(Size::Dword, Reg::Rcx, Reg::Rcx);
+  asm.jcc(Cond::E, slow_path.end);
+  asm.jmp(slow_path.body);
 }
 
-//TODO: use bfil instead
-fn compile_ast_recursive(
-  item: Rc<RefCell<BfOpBlock>>,
-  code: &mut Vec<u8>,
-) {
-  let item: &BfOpBlock = &item.borrow();
-  match item {
-    BfOpBlock::Loop(children) | BfOpBlock::Master(children) => {
-      match item {
-        BfOpBlock::Master(_) => (),
-        BfOpBlock::Loop(_) => {
-          // println!("; [[[");
-          // println!("cmp byte ptr [rbx], 0");
-          code.extend([0x80, 0x3b, 0x00]);
-          // println!(";loop position is deferred!");
-          je32(code, 0); //DEFERRED, *MUST* use JE32 DUE TO CONST SIZE!
-        },
-        _ => unreachable!()
-      }
-      let len_after_head = code.len();
-      for child in children {
-        compile_ast_recursive(Rc::clone(child), code)
-      }
-      match item {
-        BfOpBlock::Master(_) => (),
-        BfOpBlock::Loop(_) => {
-          // println!("; ]]]");
-          // println!("cmp byte ptr [rbx], 0");
-          code.extend([0x80, 0x3b, 0x00]);
-          jne(code, len_after_head as i32 - code.len() as i32, true);
-          let len_after_tail = code.len();
-          let jp_diff = len_after_tail as i32 - len_after_head as i32;
-          //Fullfill defer
-          code[(len_after_head - 4)..len_after_head].copy_from_slice(
-            jp_diff.to_le_bytes().as_slice()
-          );
-        },
-        _ => unreachable!()
-      }
-    },
-    BfOpBlock::Unit(unit) => {
-      // println!("; ***");
+/// Raw `read`/`write` syscall on a single byte
+fn gen_io_syscall(asm: &mut Assembler, syscall: i32, fd: i32, buf: Mem) {
+  asm.mov_imm(Size::Qword, Reg::Rax, syscall);
+  asm.mov_imm(Size::Qword, Reg::Rdi, fd);
+  asm.mov_imm(Size::Qword, Reg::Rdx, 1);
+  asm.lea(Reg::Rsi, buf);
+  asm.syscall();
+}
 
-      let mut keys: Vec<isize> = unit.effects.keys().copied().collect();
-      keys.sort();
-      //if there's a key that matches final offset, move it to the end
-      //This makes Optimized ptrs optimization possible
-      //this is ok since all changes to memory within a single block can be considered parallel
-      //and thus order doesn't matter
-      let mut optimized_ptr = false;
-      if unit.ptr_offset != 0 {
-        if let Some(idx) = keys.iter().position(|&key| key == unit.ptr_offset) {
-          if idx != keys.len() - 1 {
-            keys.remove(idx);
-            keys.push(unit.ptr_offset);
-          }
-          optimized_ptr = true;
+/// Returns the [`CompiledCode::op_offsets`] of `ops`
+fn compile_bfil(ops: &[Op], asm: &mut Assembler, target: Target, fixups: &mut Fixups, options: &CompilerOptions) -> Vec<usize> {
+  let width = options.cell_width;
+  let size = cell_size(width);
+  //Pending jumps out of all currently open loops, and positions right after their heads
+  let mut loop_heads = vec![];
+  let mut op_offsets = Vec::with_capacity(ops.len() + 1);
+  for (idx, op) in ops.iter().enumerate() {
+    op_offsets.push(asm.pos());
+    match *op {
+      Op::LoopStart => {
+        asm.alu_imm(Alu::Cmp, size, cell(0, width), 0);
+        let exit = asm.jcc_forward(Cond::E, false);
+        loop_heads.push((exit, asm.pos()));
+      },
+      Op::LoopEnd => {
+        let (exit, head) = loop_heads.pop().expect("unbalanced bfil loop");
+        asm.alu_imm(Alu::Cmp, size, cell(0, width), 0);
+        asm.jcc(Cond::Ne, head);
+        asm.bind(exit);
+      },
+      Op::MovePtr(n) => {
+        add_to_rbx(asm, n as i32, width);
+      },
+      Op::SetCell { off, value } => {
+        gen_set_cell(asm, off as i32, value, width);
+      },
+      Op::AddCell { off, n } => {
+        add_to_ptr_rbx(asm, off as i32, n, width);
+      },
+      Op::MulAdd { dst, k, .. } => {
+        if let Some(group) = fixups.mul_adds.next_group(&ops[idx..]) {
+          let jumps = start_mul_add_group(asm, &group, target, width);
+          fixups.mul_adds.start(group, jumps, asm.pos());
         }
-      }
-
-      //Process keys
-      for (idx, &key) in keys.iter().enumerate() {
-        // if Optimized ptr, instead of setting rbx AFTER the Unit ends
-        // set it BEFORE PROCESSING THE LAST KEY, saving a couple bytes
-        let mut key_shift = 0;
-        if optimized_ptr && idx == keys.len() - 1 {
-          // println!("; optimized:");
-          add_to_rbx(code, unit.ptr_offset as i32);
-          // Due to the line above,
-          // key memory accesses need to be shifted so that [rbx + key] is available at [rbx]
-          // XXX: DO NOT ADD DIRECTLY TO KEY, SINCE IT'S ALSO USED TO INDEX INTO HASHMAP!
-          key_shift = -key as i32;
+        match k {
+          1 => asm.alu_rm_reg(Alu::Add, size, cell(dst as i32, width), Reg::Rcx),
+          -1 => asm.alu_rm_reg(Alu::Sub, size, cell(dst as i32, width), Reg::Rcx),
+          _ => {
+            asm.imul_imm(Size::Dword, Reg::Rax, Reg::Rcx, k);
+            asm.alu_rm_reg(Alu::Add, size, cell(dst as i32, width), Reg::Rax);
+          },
         }
-
-        // Materialize effects
-        let effects = unit.effects.get(&key).unwrap();
-        for effect in effects {
-          match effect {
-            &Effect::CellSet(value) => {
-              gen_set_cell(code, key as i32 + key_shift, value);
-            },
-            &Effect::CellInc(by) => {
-              add_to_ptr_rbx(code, key as i32 + key_shift, by);
-            },
-            //TODO optimize add
-            Effect::Output => {
-              // println!(
-              //   "\
-              //     mov rax, 1 ; OUTPUT \n\
-              //     mov rdi, 1 \n\
-              //     mov rdx, 1 \n\
-              //     mov rsi, rbx \n\
-              //     add rsi, {} ;(imm32) \n\
-              //     syscall \
-              //   ",
-              //   key as i32 + key_shift
-              // );
-              code.extend([
-                0x48, 0xC7, 0xC0, 0x01, 0x00, 0x00, 0x00, //mov rax, 1
-                0x48, 0xC7, 0xC7, 0x01, 0x00, 0x00, 0x00, //mov rdi, 1
-                0x48, 0xC7, 0xC2, 0x01, 0x00, 0x00, 0x00, //mov rdx, 1
-                0x48, 0x89, 0xDE, //mov rsi, rbx
-                0x48, 0x81, 0xC6, //add rsi, imm32
-              ]);
-              code.extend((key as i32 + key_shift).to_le_bytes().as_slice());
-              code.extend([0x0F, 0x05]); //syscall
-            }
-            //TODO Effect::Input
-            _ => unimplemented!()
-          }
+        fixups.mul_adds.generated(asm.pos());
+      },
+      Op::Scan(stride) if options.bounds_checks => {
+        gen_checked_scan(asm, stride as i32, width, fixups);
+      },
+      Op::Scan(stride) => {
+        gen_scan(asm, stride as i32, width);
+      },
+      Op::CheckBounds { min, max } => {
+        gen_check_start(asm, min as i32, width, fixups);
+        gen_check_end(asm, max as i32, width, fixups);
+      },
+      Op::CheckLoopBounds { min, max } => {
+        asm.alu_imm(Alu::Cmp, size, cell(0, width), 0);
+        let skip = asm.jcc_forward(Cond::E, true);
+        gen_check_start(asm, min as i32, width, fixups);
+        gen_check_end(asm, max as i32, width, fixups);
+        asm.bind(skip);
+      },
+      Op::Out { off } => {
+        gen_output(asm, off as i32, width, options.flush_policy, fixups);
+      },
+      Op::In { off } => {
+        //Pending output has to be visible before blocking on input
+        fixups.flush_calls.push(asm.call_forward());
+        if let Some(value) = super::eof_value(options) {
+          gen_set_cell(asm, off as i32, value, width);
         }
-      }
-
-      //If not using optimized ptr optimization, just set the rbx AFTER the Unit ends
-      if !optimized_ptr {
-        add_to_rbx(code, unit.ptr_offset as i32);
-      }
+        match target {
+          Target::Extern if width == CellWidth::U8 => {
+            gen_io_syscall(asm, SYS_READ, 0, cell(off as i32, width));
+          },
+          Target::Extern => {
+            //Wider cells need the byte zero-extended, so read it into the (just flushed) output buffer first
+            gen_io_syscall(asm, SYS_READ, 0, Mem::base(OUT_PTR, 0));
+            asm.alu_imm(Alu::Cmp, Size::Qword, Reg::Rax, 1);
+            let eof = asm.jcc_forward(Cond::Ne, true);
+            asm.movzx(Size::Byte, Reg::Rax, Mem::base(OUT_PTR, 0));
+            asm.mov_rm_reg(size, cell(off as i32, width), Reg::Rax);
+            asm.bind(eof);
+          },
+          Target::Host => {
+            gen_host_call(asm, offset_of!(RunContext, read_byte));
+            asm.test(Size::Dword, Reg::Rax, Reg::Rax);
+            let eof = asm.jcc_forward(Cond::S, true);
+            asm.mov_rm_reg(size, cell(off as i32, width), Reg::Rax);
+            asm.bind(eof);
+          },
+        }
+      },
     }
   }
+  op_offsets.push(asm.pos());
+  op_offsets
 }
 
-fn compile_ast(item: Rc<RefCell<BfOpBlock>>) -> Vec<u8> {
-  let mut code = vec![];
-  compile_ast_recursive(item, &mut code);
-  code
-}
-
-fn wrap_extern(code: &mut Vec<u8>) {
-  //mov rbp, rdi; at start
-  code.reserve(4);
-  code.insert(0, 0xfb);
-  code.insert(0, 0x89);
-  code.insert(0, 0x48);
-  //ret; at the end
-  code.push(0xC3);
+/// System V prologue and epilogue around the function body\
+/// The function returns the final data pointer\
+/// Returns the position of the epilogue, which can be jumped to from anywhere in the body
+fn wrap_function(asm: &mut Assembler, target: Target, fixups: &mut Fixups, body: impl FnOnce(&mut Assembler, &mut Fixups)) -> usize {
+  //Save all callee-saved registers we use
+  let saved_regs: &[Reg] = match target {
+    Target::Extern => &[PTR, OUT_PTR],
+    Target::Host => &[PTR, OUT_PTR, CTX],
+  };
+  for &reg in saved_regs {
+    asm.push(reg);
+  }
+  //Calls need the stack to be 16-byte aligned,
+  //so the return address, saved registers and frame have to add up to a multiple of 16
+  let frame_size = OUTPUT_BUFFER_SIZE + if saved_regs.len() % 2 == 0 { 8 } else { 0 };
+  asm.alu_imm(Alu::Sub, Size::Qword, Reg::Rsp, frame_size);
+  //mov rbx, rdi; (and mov r13, rsi; for Host) and set up the output buffer at start
+  asm.mov_rm_reg(Size::Qword, PTR, Reg::Rdi);
+  if target == Target::Host {
+    asm.mov_rm_reg(Size::Qword, CTX, Reg::Rsi);
+  }
+  asm.mov_rm_reg(Size::Qword, OUT_PTR, Reg::Rsp);
+  body(asm, fixups);
+  //flush the output buffer, return the data pointer and restore everything at the end
+  let epilogue = asm.pos();
+  fixups.flush_calls.push(asm.call_forward());
+  asm.mov_rm_reg(Size::Qword, Reg::Rax, PTR);
+  asm.alu_imm(Alu::Add, Size::Qword, Reg::Rsp, frame_size);
+  for &reg in saved_regs.iter().rev() {
+    asm.pop(reg);
+  }
+  asm.ret();
+  epilogue
 }
 
 pub struct Compiler;
 impl CompilerImpl for Compiler {
+  const ELF_MACHINE: u16 = 62;
   fn supported() -> bool {
     cfg!(target_arch = "x86_64") && cfg!(unix)
   }
-  fn compile(item: Rc<RefCell<BfOpBlock>>, target: Option<super::Target>) -> Vec<u8> {
-    let mut code = compile_ast(item);
-    if target == Some(Target::Extern) {
-      wrap_extern(&mut code)
-    }
-    code
+  fn compile(ops: &[Op], target: super::Target, options: &CompilerOptions) -> CompiledCode {
+    super::compile_checked(ops, target, options, |ops| {
+      let mut asm = Assembler::new();
+      let mut fixups = Fixups::default();
+      let mut op_offsets = vec![];
+      let fault_exit = wrap_function(&mut asm, target, &mut fixups, |asm, fixups| {
+        op_offsets = compile_bfil(ops, asm, target, fixups, options);
+      });
+      //Cold paths and shared runtime routines go after the function itself
+      for slow_path in std::mem::take(&mut fixups.mul_adds.slow_paths) {
+        gen_mul_add_slow_path(&mut asm, slow_path, options.bounds_checks, &mut fixups);
+      }
+      gen_out_of_bounds_exit(&mut asm, fixups.out_of_bounds, fault_exit);
+      if !fixups.flush_calls.is_empty() {
+        for call in fixups.flush_calls {
+          asm.bind(call);
+        }
+        gen_flush_routine(&mut asm, target);
+      }
+      CompiledCode { code: asm.into_code(), fault_exit, op_offsets }
+    })
+  }
+  fn start_stub(tape: u64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    //The whole image lives in the low 4GB, and 32-bit moves zero the upper half
+    asm.mov_imm(Size::Dword, Reg::Rdi, u32::try_from(tape).expect("tape address out of range") as i32);
+    //The stack is 16-byte aligned at the entry point, just like right before a call
+    let call = asm.call_forward();
+    asm.mov_imm(Size::Dword, Reg::Rax, SYS_EXIT);
+    asm.mov_imm(Size::Dword, Reg::Rdi, 0);
+    asm.syscall();
+    asm.bind(call);
+    asm.into_code()
+  }
+  fn disassemble(code: &[u8], syntax: AsmSyntax) -> Vec<Instruction> {
+    decoder::disassemble(code, syntax)
   }
 }
@@ -0,0 +1,378 @@
+//! Decoder for the subset of x86_64 instructions emitted by the [encoder](super::encoder),
+//! producing GNU assembler source in Intel or AT&T syntax
+
+use crate::compiler::{label, AsmSyntax, Instruction};
+use super::encoder::Size;
+
+const REGS: [&str; 16] = [
+  "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+  "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+
+/// Group 1 ALU operations, in encoding order
+const ALU: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+/// Condition code suffixes, in encoding order
+const CONDS: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g"];
+
+/// Name of the general purpose register `reg` of the given size\
+/// With a REX prefix, byte registers 4 to 7 are spl, bpl, sil and dil instead of ah, ch, dh and bh
+fn reg_name(reg: u8, size: Size, rex: bool) -> String {
+  let name = REGS[reg as usize];
+  match size {
+    Size::Qword => name.into(),
+    _ if reg >= 8 => format!("{name}{}", ["b", "w", "d"][size as usize]),
+    Size::Dword => format!("e{}", &name[1..]),
+    Size::Word => name[1..].into(),
+    Size::Byte => match reg {
+      0..4 => format!("{}l", &name[1..2]),
+      _ if rex => format!("{}l", &name[1..]),
+      _ => ["ah", "ch", "dh", "bh"][reg as usize - 4].into(),
+    },
+  }
+}
+
+/// AT&T mnemonic suffix for an operand size
+fn suffix(size: Size) -> char {
+  match size {
+    Size::Byte => 'b',
+    Size::Word => 'w',
+    Size::Dword => 'l',
+    Size::Qword => 'q',
+  }
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+  Reg(String),
+  Xmm(u8),
+  /// `[base + index * scale + disp]`, with the size of the access (`None` for lea)
+  Mem { base: u8, index: Option<(u8, u8)>, disp: i32, ptr: Option<&'static str> },
+  Imm(i64),
+  Target(usize),
+}
+
+impl Operand {
+  fn intel(&self) -> String {
+    match self {
+      Operand::Reg(name) => name.clone(),
+      Operand::Xmm(xmm) => format!("xmm{xmm}"),
+      Operand::Mem { base, index, disp, ptr } => {
+        let ptr = ptr.map_or(String::new(), |ptr| format!("{ptr} ptr "));
+        let index = index.map_or(String::new(), |(index, scale)| format!("+{}*{scale}", REGS[index as usize]));
+        let disp = match disp {
+          0 => String::new(),
+          _ => format!("{disp:+}"),
+        };
+        format!("{ptr}[{}{index}{disp}]", REGS[*base as usize])
+      },
+      Operand::Imm(imm) => imm.to_string(),
+      Operand::Target(target) => label(*target),
+    }
+  }
+
+  fn att(&self) -> String {
+    match self {
+      Operand::Reg(name) => format!("%{name}"),
+      Operand::Xmm(xmm) => format!("%xmm{xmm}"),
+      Operand::Mem { base, index, disp, .. } => {
+        let index = index.map_or(String::new(), |(index, scale)| format!(",%{},{scale}", REGS[index as usize]));
+        let disp = match disp {
+          0 => String::new(),
+          _ => disp.to_string(),
+        };
+        format!("{disp}(%{}{index})", REGS[*base as usize])
+      },
+      Operand::Imm(imm) => format!("${imm}"),
+      Operand::Target(target) => label(*target),
+    }
+  }
+}
+
+/// Decoded instruction, with operands in Intel order
+struct Inst {
+  mnemonic: String,
+  /// Mnemonic in AT&T syntax, usually with an operand size suffix
+  att_mnemonic: String,
+  operands: Vec<Operand>,
+  target: Option<usize>,
+}
+
+impl Inst {
+  fn new(mnemonic: &str, size: Option<Size>, operands: Vec<Operand>) -> Self {
+    let att_mnemonic = match size {
+      Some(size) => format!("{mnemonic}{}", suffix(size)),
+      None => mnemonic.into(),
+    };
+    Self { mnemonic: mnemonic.into(), att_mnemonic, operands, target: None }
+  }
+
+  fn branch(mnemonic: &str, target: usize) -> Self {
+    Self { target: Some(target), ..Self::new(mnemonic, None, vec![Operand::Target(target)]) }
+  }
+
+  fn format(&self, syntax: AsmSyntax) -> String {
+    let (mnemonic, operands) = match syntax {
+      AsmSyntax::Intel => (&self.mnemonic, self.operands.iter().map(Operand::intel).collect::<Vec<_>>()),
+      AsmSyntax::Att => (&self.att_mnemonic, self.operands.iter().rev().map(|operand| match operand {
+        //Indirect calls take a `*`
+        Operand::Mem { .. } if self.mnemonic == "call" => format!("*{}", operand.att()),
+        operand => operand.att(),
+      }).collect()),
+    };
+    match operands.is_empty() {
+      true => mnemonic.clone(),
+      false => format!("{mnemonic} {}", operands.join(", ")),
+    }
+  }
+}
+
+/// Register or memory operand, before knowing its size
+enum Rm {
+  Reg(u8),
+  Mem { base: u8, index: Option<(u8, u8)>, disp: i32 },
+}
+
+struct Decoder<'a> {
+  code: &'a [u8],
+  pos: usize,
+  rex: u8,
+}
+
+impl Decoder<'_> {
+  fn byte(&mut self) -> Option<u8> {
+    let byte = *self.code.get(self.pos)?;
+    self.pos += 1;
+    Some(byte)
+  }
+
+  /// Sign-extended immediate
+  fn imm(&mut self, size: Size) -> Option<i64> {
+    let bytes = self.code.get(self.pos..self.pos + size.bytes())?;
+    self.pos += size.bytes();
+    Some(match size {
+      Size::Byte => bytes[0] as i8 as i64,
+      Size::Word => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+      Size::Dword => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+      Size::Qword => i64::from_le_bytes(bytes.try_into().unwrap()),
+    })
+  }
+
+  /// Branch target of a relative displacement of `size`
+  fn target(&mut self, size: Size) -> Option<usize> {
+    let rel = self.imm(size)?;
+    usize::try_from(self.pos as i64 + rel).ok().filter(|&target| target <= self.code.len())
+  }
+
+  /// ModRM, SIB and displacement, returning the reg field and the r/m operand\
+  /// RIP-relative and base-less addressing are never generated, so they're not supported
+  fn modrm(&mut self) -> Option<(u8, Rm)> {
+    let modrm = self.byte()?;
+    let (mode, reg, rm) = (modrm >> 6, (modrm >> 3) & 0b111, modrm & 0b111);
+    let reg = reg | (self.rex & 0b100) << 1;
+    if mode == 0b11 {
+      return Some((reg, Rm::Reg(rm | (self.rex & 1) << 3)))
+    }
+    let (base, index) = match rm {
+      0b100 => {
+        let sib = self.byte()?;
+        let index = ((sib >> 3) & 0b111) | (self.rex & 0b10) << 2;
+        let index = (index != 0b100).then_some((index, 1 << (sib >> 6)));
+        (sib & 0b111, index)
+      },
+      rm => (rm, None),
+    };
+    if mode == 0b00 && base == 0b101 {
+      return None
+    }
+    let disp = match mode {
+      0b01 => self.imm(Size::Byte)?,
+      0b10 => self.imm(Size::Dword)?,
+      _ => 0,
+    };
+    Some((reg, Rm::Mem { base: base | (self.rex & 1) << 3, index, disp: disp as i32 }))
+  }
+
+  fn reg(&self, reg: u8, size: Size) -> Operand {
+    Operand::Reg(reg_name(reg, size, self.rex != 0))
+  }
+
+  fn rm(&self, rm: &Rm, size: Size) -> Operand {
+    let ptr = match size {
+      Size::Byte => "byte",
+      Size::Word => "word",
+      Size::Dword => "dword",
+      Size::Qword => "qword",
+    };
+    match *rm {
+      Rm::Reg(reg) => self.reg(reg, size),
+      Rm::Mem { base, index, disp } => Operand::Mem { base, index, disp, ptr: Some(ptr) },
+    }
+  }
+
+  /// r/m operand of an SSE instruction, `ptr` being the size of memory operands
+  fn xmm_rm(rm: &Rm, ptr: Option<&'static str>) -> Operand {
+    match *rm {
+      Rm::Reg(reg) => Operand::Xmm(reg),
+      Rm::Mem { base, index, disp } => Operand::Mem { base, index, disp, ptr },
+    }
+  }
+
+  fn decode(&mut self) -> Option<Inst> {
+    let mut opcode = self.byte()?;
+    let operand_size_prefix = opcode == 0x66;
+    if operand_size_prefix {
+      opcode = self.byte()?;
+    }
+    if opcode & 0xf0 == 0x40 {
+      self.rex = opcode;
+      opcode = self.byte()?;
+    }
+    let size = match (self.rex & 0b1000 != 0, operand_size_prefix) {
+      (true, _) => Size::Qword,
+      (false, true) => Size::Word,
+      (false, false) => Size::Dword,
+    };
+    //Byte forms of most instructions have an even opcode
+    let sized = |byte_form: bool| if byte_form { Size::Byte } else { size };
+    //Immediates are at most 32 bits, sign-extended for qwords
+    let full_imm = match size {
+      Size::Word => Size::Word,
+      _ => Size::Dword,
+    };
+    Some(match opcode {
+      0x00..=0x3f if opcode & 0b111 < 4 => {
+        let size = sized(opcode & 1 == 0);
+        let (reg, rm) = self.modrm()?;
+        let (reg, rm) = (self.reg(reg, size), self.rm(&rm, size));
+        let operands = if opcode & 0b10 == 0 { vec![rm, reg] } else { vec![reg, rm] };
+        Inst::new(ALU[opcode as usize >> 3], Some(size), operands)
+      },
+      0x80 | 0x81 | 0x83 => {
+        let size = sized(opcode == 0x80);
+        let (op, rm) = self.modrm()?;
+        let imm = self.imm(if opcode == 0x81 { full_imm } else { Size::Byte })?;
+        Inst::new(ALU[op as usize], Some(size), vec![self.rm(&rm, size), Operand::Imm(imm)])
+      },
+      0x84 | 0x85 | 0x88 | 0x89 => {
+        let size = sized(opcode & 1 == 0);
+        let (reg, rm) = self.modrm()?;
+        let mnemonic = if opcode < 0x88 { "test" } else { "mov" };
+        Inst::new(mnemonic, Some(size), vec![self.rm(&rm, size), self.reg(reg, size)])
+      },
+      0x8a | 0x8b => {
+        let size = sized(opcode == 0x8a);
+        let (reg, rm) = self.modrm()?;
+        Inst::new("mov", Some(size), vec![self.reg(reg, size), self.rm(&rm, size)])
+      },
+      0x8d => {
+        let (reg, Rm::Mem { base, index, disp }) = self.modrm()? else { return None };
+        Inst::new("lea", Some(size), vec![self.reg(reg, size), Operand::Mem { base, index, disp, ptr: None }])
+      },
+      0xc6 | 0xc7 => {
+        let size = sized(opcode == 0xc6);
+        let (0, rm) = self.modrm()? else { return None };
+        let imm = self.imm(if size == Size::Byte { Size::Byte } else { full_imm })?;
+        Inst::new("mov", Some(size), vec![self.rm(&rm, size), Operand::Imm(imm)])
+      },
+      0xfe | 0xff => {
+        let size = sized(opcode == 0xfe);
+        match self.modrm()? {
+          (0, rm) => Inst::new("inc", Some(size), vec![self.rm(&rm, size)]),
+          (1, rm) => Inst::new("dec", Some(size), vec![self.rm(&rm, size)]),
+          //Indirect calls always take a qword
+          (2, rm) if opcode == 0xff => Inst::new("call", None, vec![self.rm(&rm, Size::Qword)]),
+          _ => return None,
+        }
+      },
+      0xf6 | 0xf7 => {
+        let size = sized(opcode == 0xf6);
+        let (2, rm) = self.modrm()? else { return None };
+        Inst::new("not", Some(size), vec![self.rm(&rm, size)])
+      },
+      0xd2 | 0xd3 => {
+        let size = sized(opcode == 0xd2);
+        let (op, rm) = self.modrm()?;
+        let mnemonic = match op {
+          4 => "shl",
+          5 => "shr",
+          _ => return None,
+        };
+        Inst::new(mnemonic, Some(size), vec![self.rm(&rm, size), Operand::Reg("cl".into())])
+      },
+      0x69 | 0x6b => {
+        let (reg, rm) = self.modrm()?;
+        let imm = self.imm(if opcode == 0x6b { Size::Byte } else { full_imm })?;
+        Inst::new("imul", Some(size), vec![self.reg(reg, size), self.rm(&rm, size), Operand::Imm(imm)])
+      },
+      0x50..=0x5f => {
+        let reg = (opcode & 0b111) | (self.rex & 1) << 3;
+        let mnemonic = if opcode < 0x58 { "push" } else { "pop" };
+        Inst::new(mnemonic, None, vec![self.reg(reg, Size::Qword)])
+      },
+      0x70..=0x7f => Inst::branch(&format!("j{}", CONDS[opcode as usize & 0xf]), self.target(Size::Byte)?),
+      0xeb => Inst::branch("jmp", self.target(Size::Byte)?),
+      0xe9 => Inst::branch("jmp", self.target(Size::Dword)?),
+      0xe8 => Inst::branch("call", self.target(Size::Dword)?),
+      0xc3 => Inst::new("ret", None, vec![]),
+      0x0f => match self.byte()? {
+        0x05 => Inst::new("syscall", None, vec![]),
+        opcode @ 0x80..=0x8f => Inst::branch(&format!("j{}", CONDS[opcode as usize & 0xf]), self.target(Size::Dword)?),
+        opcode @ (0xb6 | 0xb7) => {
+          let src_size = if opcode == 0xb6 { Size::Byte } else { Size::Word };
+          let (reg, rm) = self.modrm()?;
+          Inst {
+            att_mnemonic: format!("movz{}{}", suffix(src_size), suffix(size)),
+            ..Inst::new("movzx", None, vec![self.reg(reg, size), self.rm(&rm, src_size)])
+          }
+        },
+        opcode @ (0xbc | 0xbd) => {
+          let (reg, rm) = self.modrm()?;
+          let mnemonic = if opcode == 0xbc { "bsf" } else { "bsr" };
+          Inst::new(mnemonic, Some(size), vec![self.reg(reg, size), self.rm(&rm, size)])
+        },
+        opcode @ (0x74 | 0xef | 0x6f) if operand_size_prefix => {
+          let (reg, rm) = self.modrm()?;
+          let mnemonic = match opcode {
+            0x74 => "pcmpeqb",
+            0xef => "pxor",
+            _ => "movdqa",
+          };
+          Inst::new(mnemonic, None, vec![Operand::Xmm(reg), Self::xmm_rm(&rm, Some("xmmword"))])
+        },
+        0xd7 if operand_size_prefix => {
+          let (reg, rm @ Rm::Reg(_)) = self.modrm()? else { return None };
+          Inst::new("pmovmskb", None, vec![self.reg(reg, Size::Dword), Self::xmm_rm(&rm, None)])
+        },
+        _ => return None,
+      },
+      _ => return None,
+    })
+  }
+}
+
+/// Decode `code` into a listing, falling back to `.byte` for anything the encoder doesn't generate
+pub fn disassemble(code: &[u8], syntax: AsmSyntax) -> Vec<Instruction> {
+  let mut instructions = vec![];
+  let mut offset = 0;
+  while offset < code.len() {
+    let mut decoder = Decoder { code, pos: offset, rex: 0 };
+    let instruction = match decoder.decode() {
+      Some(inst) => Instruction {
+        offset,
+        len: decoder.pos - offset,
+        text: inst.format(syntax),
+        target: inst.target,
+      },
+      None => Instruction {
+        offset,
+        len: 1,
+        text: format!(".byte {:#04x}", code[offset]),
+        target: None,
+      },
+    };
+    offset += instruction.len;
+    instructions.push(instruction);
+  }
+  instructions
+}
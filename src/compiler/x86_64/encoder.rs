@@ -0,0 +1,618 @@
+//! Minimal x86_64 instruction encoder, covering the subset of instructions used by the compiler
+
+/// General purpose registers, in encoding order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+  Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi,
+  R8, R9, R10, R11, R12, R13, R14, R15,
+}
+
+impl Reg {
+  fn low_bits(self) -> u8 {
+    self as u8 & 0b111
+  }
+
+  fn is_extended(self) -> bool {
+    self as u8 >= 8
+  }
+}
+
+/// SSE registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Xmm {
+  Xmm0, Xmm1, Xmm2, Xmm3, Xmm4, Xmm5, Xmm6, Xmm7,
+}
+
+/// Operand size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+  Byte,
+  Word,
+  Dword,
+  Qword,
+}
+
+impl Size {
+  pub fn bytes(self) -> usize {
+    match self {
+      Size::Byte => 1,
+      Size::Word => 2,
+      Size::Dword => 4,
+      Size::Qword => 8,
+    }
+  }
+
+  /// Size of the immediate used by most instructions with this operand size
+  fn min_imm(self) -> Size {
+    match self {
+      Size::Qword => Size::Dword,
+      size => size,
+    }
+  }
+}
+
+/// Memory operand, `[base + index * scale + disp]`\
+/// The shortest displacement encoding is picked automatically
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mem {
+  pub base: Reg,
+  pub index: Option<(Reg, u8)>,
+  pub disp: i32,
+}
+
+impl Mem {
+  /// `[base + disp]`
+  pub fn base(base: Reg, disp: i32) -> Self {
+    Self { base, index: None, disp }
+  }
+
+  /// `[base + index * scale + disp]`
+  pub fn indexed(base: Reg, index: Reg, scale: u8, disp: i32) -> Self {
+    assert!(matches!(scale, 1 | 2 | 4 | 8), "invalid scale");
+    assert_ne!(index, Reg::Rsp, "rsp can't be used as an index");
+    Self { base, index: Some((index, scale)), disp }
+  }
+}
+
+/// Register or memory operand (the r/m part of ModRM)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rm {
+  Reg(Reg),
+  Mem(Mem),
+}
+
+impl From<Reg> for Rm {
+  fn from(reg: Reg) -> Self { Rm::Reg(reg) }
+}
+
+impl From<Mem> for Rm {
+  fn from(mem: Mem) -> Self { Rm::Mem(mem) }
+}
+
+/// Group 1 ALU operations, in encoding order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alu {
+  Add, Or, Adc, Sbb, And, Sub, Xor, Cmp,
+}
+
+/// Group 2 shift operations (only the ones we use)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shift {
+  Shl = 4,
+  Shr = 5,
+}
+
+/// Condition codes for `jcc`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+  /// Below (unsigned <)
+  B = 0x2,
+  /// Above or equal (unsigned >=)
+  Ae = 0x3,
+  /// Equal/zero
+  E = 0x4,
+  /// Not equal/not zero
+  Ne = 0x5,
+  /// Below or equal (unsigned <=)
+  Be = 0x6,
+  /// Above (unsigned >)
+  A = 0x7,
+  /// Sign (negative)
+  S = 0x8,
+  /// Less or equal (signed <=)
+  Le = 0xe,
+}
+
+/// Placeholder for a forward jump, to be resolved with [`Assembler::bind`]
+#[must_use]
+#[derive(Debug)]
+pub struct Fixup {
+  /// Position right after the jump instruction
+  end: usize,
+  short: bool,
+}
+
+/// Appends encoded instructions to a code buffer
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+  code: Vec<u8>,
+}
+
+impl Assembler {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Current position in the code buffer
+  pub fn pos(&self) -> usize {
+    self.code.len()
+  }
+
+  pub fn code(&self) -> &[u8] {
+    &self.code
+  }
+
+  pub fn into_code(self) -> Vec<u8> {
+    self.code
+  }
+
+  pub fn bytes(&mut self, bytes: &[u8]) {
+    self.code.extend_from_slice(bytes);
+  }
+
+  fn imm(&mut self, size: Size, imm: i64) {
+    self.bytes(&imm.to_le_bytes()[..size.bytes()]);
+  }
+
+  /// Emit legacy prefix, REX, opcode and ModRM/SIB/displacement\
+  /// `reg` is either a register number or an opcode extension
+  fn op_rm(&mut self, size: Size, opcode: &[u8], reg: u8, rm: Rm) {
+    self.op_rm_rex(size, opcode, reg, rm, false)
+  }
+
+  /// Same as `op_rm`, optionally forcing a REX prefix,
+  /// which is required to access spl, bpl, sil and dil instead of ah, ch, dh and bh\
+  /// (`reg` may be an opcode extension, so this can't be inferred for it)
+  fn op_rm_rex(&mut self, size: Size, opcode: &[u8], reg: u8, rm: Rm, force_rex: bool) {
+    let force_rex = force_rex || matches!(rm, Rm::Reg(rm) if Self::byte_reg_needs_rex(size, rm as u8));
+    if size == Size::Word {
+      self.code.push(0x66);
+    }
+    let (b, x) = match rm {
+      Rm::Reg(reg) => (reg.is_extended(), false),
+      Rm::Mem(mem) => (mem.base.is_extended(), mem.index.is_some_and(|(index, _)| index.is_extended())),
+    };
+    let rex = 0x40
+      | (((size == Size::Qword) as u8) << 3)
+      | (((reg >= 8) as u8) << 2)
+      | ((x as u8) << 1)
+      | (b as u8);
+    if rex != 0x40 || force_rex {
+      self.code.push(rex);
+    }
+    self.bytes(opcode);
+    let reg = (reg & 0b111) << 3;
+    match rm {
+      Rm::Reg(rm) => self.code.push(0b11_000_000 | reg | rm.low_bits()),
+      Rm::Mem(mem) => {
+        //rbp/r13 as a base always require a displacement
+        let mode = match mem.disp {
+          0 if mem.base.low_bits() != 0b101 => 0b00,
+          -0x80..=0x7f => 0b01,
+          _ => 0b10,
+        };
+        //rsp/r12 as a base always require a SIB byte
+        match mem.index {
+          None if mem.base.low_bits() != 0b100 => {
+            self.code.push((mode << 6) | reg | mem.base.low_bits());
+          },
+          index => {
+            self.code.push((mode << 6) | reg | 0b100);
+            let (index, scale) = match index {
+              Some((index, scale)) => (index.low_bits(), scale.trailing_zeros() as u8),
+              None => (0b100, 0),
+            };
+            self.code.push((scale << 6) | (index << 3) | mem.base.low_bits());
+          },
+        }
+        match mode {
+          0b01 => self.code.push(mem.disp as u8),
+          0b10 => self.bytes(&mem.disp.to_le_bytes()),
+          _ => (),
+        }
+      },
+    }
+  }
+
+  /// Whether a byte-sized register operand needs a REX prefix (spl, bpl, sil, dil)
+  fn byte_reg_needs_rex(size: Size, reg: u8) -> bool {
+    size == Size::Byte && (4..8).contains(&reg)
+  }
+
+  /// `<op> rm, imm`, picking the sign-extended imm8 form when possible
+  pub fn alu_imm(&mut self, op: Alu, size: Size, rm: impl Into<Rm>, imm: i32) {
+    let rm = rm.into();
+    if size == Size::Byte {
+      self.op_rm(size, &[0x80], op as u8, rm);
+      self.imm(Size::Byte, imm as i64);
+    } else if (-0x80..=0x7f).contains(&imm) {
+      self.op_rm(size, &[0x83], op as u8, rm);
+      self.imm(Size::Byte, imm as i64);
+    } else {
+      self.op_rm(size, &[0x81], op as u8, rm);
+      self.imm(size.min_imm(), imm as i64);
+    }
+  }
+
+  /// `<op> rm, reg`
+  pub fn alu_rm_reg(&mut self, op: Alu, size: Size, rm: impl Into<Rm>, reg: Reg) {
+    let opcode = ((op as u8) << 3) | (size != Size::Byte) as u8;
+    let force_rex = Self::byte_reg_needs_rex(size, reg as u8);
+    self.op_rm_rex(size, &[opcode], reg as u8, rm.into(), force_rex);
+  }
+
+  /// `<op> reg, rm`
+  pub fn alu_reg_rm(&mut self, op: Alu, size: Size, reg: Reg, rm: impl Into<Rm>) {
+    let opcode = ((op as u8) << 3) | 0b10 | (size != Size::Byte) as u8;
+    let force_rex = Self::byte_reg_needs_rex(size, reg as u8);
+    self.op_rm_rex(size, &[opcode], reg as u8, rm.into(), force_rex);
+  }
+
+  /// `inc rm`
+  pub fn inc(&mut self, size: Size, rm: impl Into<Rm>) {
+    self.op_rm(size, &[if size == Size::Byte { 0xfe } else { 0xff }], 0, rm.into());
+  }
+
+  /// `dec rm`
+  pub fn dec(&mut self, size: Size, rm: impl Into<Rm>) {
+    self.op_rm(size, &[if size == Size::Byte { 0xfe } else { 0xff }], 1, rm.into());
+  }
+
+  /// `not rm`
+  pub fn not(&mut self, size: Size, rm: impl Into<Rm>) {
+    self.op_rm(size, &[if size == Size::Byte { 0xf6 } else { 0xf7 }], 2, rm.into());
+  }
+
+  /// `test rm, reg`
+  pub fn test(&mut self, size: Size, rm: impl Into<Rm>, reg: Reg) {
+    self.op_rm(size, &[if size == Size::Byte { 0x84 } else { 0x85 }], reg as u8, rm.into());
+  }
+
+  /// `shl/shr rm, cl`
+  pub fn shift_cl(&mut self, op: Shift, size: Size, rm: impl Into<Rm>) {
+    self.op_rm(size, &[if size == Size::Byte { 0xd2 } else { 0xd3 }], op as u8, rm.into());
+  }
+
+  /// `mov rm, imm`\
+  /// For qwords, the immediate is sign-extended from 32 bits
+  pub fn mov_imm(&mut self, size: Size, rm: impl Into<Rm>, imm: i32) {
+    self.op_rm(size, &[if size == Size::Byte { 0xc6 } else { 0xc7 }], 0, rm.into());
+    self.imm(size.min_imm(), imm as i64);
+  }
+
+  /// `mov rm, reg`
+  pub fn mov_rm_reg(&mut self, size: Size, rm: impl Into<Rm>, reg: Reg) {
+    let force_rex = Self::byte_reg_needs_rex(size, reg as u8);
+    self.op_rm_rex(size, &[if size == Size::Byte { 0x88 } else { 0x89 }], reg as u8, rm.into(), force_rex);
+  }
+
+  /// `mov reg, rm`
+  pub fn mov_reg_rm(&mut self, size: Size, reg: Reg, rm: impl Into<Rm>) {
+    let force_rex = Self::byte_reg_needs_rex(size, reg as u8);
+    self.op_rm_rex(size, &[if size == Size::Byte { 0x8a } else { 0x8b }], reg as u8, rm.into(), force_rex);
+  }
+
+  /// `movzx reg32, byte/word rm`
+  pub fn movzx(&mut self, src_size: Size, reg: Reg, rm: impl Into<Rm>) {
+    let opcode = match src_size {
+      Size::Byte => 0xb6,
+      Size::Word => 0xb7,
+      _ => panic!("movzx only supports byte and word sources"),
+    };
+    self.op_rm(Size::Dword, &[0x0f, opcode], reg as u8, rm.into());
+  }
+
+  /// `lea reg, [mem]`
+  pub fn lea(&mut self, reg: Reg, mem: Mem) {
+    self.op_rm(Size::Qword, &[0x8d], reg as u8, mem.into());
+  }
+
+  /// `imul reg, rm, imm`
+  pub fn imul_imm(&mut self, size: Size, reg: Reg, rm: impl Into<Rm>, imm: i32) {
+    assert_ne!(size, Size::Byte, "imul has no byte form");
+    if (-0x80..=0x7f).contains(&imm) {
+      self.op_rm(size, &[0x6b], reg as u8, rm.into());
+      self.imm(Size::Byte, imm as i64);
+    } else {
+      self.op_rm(size, &[0x69], reg as u8, rm.into());
+      self.imm(size.min_imm(), imm as i64);
+    }
+  }
+
+  /// `bsf reg, rm`
+  pub fn bsf(&mut self, size: Size, reg: Reg, rm: impl Into<Rm>) {
+    self.op_rm(size, &[0x0f, 0xbc], reg as u8, rm.into());
+  }
+
+  /// `bsr reg, rm`
+  pub fn bsr(&mut self, size: Size, reg: Reg, rm: impl Into<Rm>) {
+    self.op_rm(size, &[0x0f, 0xbd], reg as u8, rm.into());
+  }
+
+  /// `pxor xmm, xmm`
+  pub fn pxor(&mut self, dst: Xmm, src: Xmm) {
+    self.bytes(&[0x66, 0x0f, 0xef, 0b11_000_000 | ((dst as u8) << 3) | src as u8]);
+  }
+
+  /// `pcmpeqb xmm, xmm`
+  pub fn pcmpeqb(&mut self, dst: Xmm, src: Xmm) {
+    self.bytes(&[0x66, 0x0f, 0x74, 0b11_000_000 | ((dst as u8) << 3) | src as u8]);
+  }
+
+  /// `movdqa xmm, [mem]` (mem must be 16-byte aligned)
+  pub fn movdqa_load(&mut self, dst: Xmm, mem: Mem) {
+    self.code.push(0x66);
+    self.op_rm(Size::Dword, &[0x0f, 0x6f], dst as u8, mem.into());
+  }
+
+  /// `pmovmskb reg32, xmm`
+  pub fn pmovmskb(&mut self, reg: Reg, src: Xmm) {
+    self.code.push(0x66);
+    self.op_rm(Size::Dword, &[0x0f, 0xd7], reg as u8, xmm_rm(src));
+  }
+
+  /// `push reg` (64-bit)
+  pub fn push(&mut self, reg: Reg) {
+    if reg as u8 >= 8 {
+      self.code.push(0x41);
+    }
+    self.code.push(0x50 | (reg as u8 & 7));
+  }
+
+  /// `pop reg` (64-bit)
+  pub fn pop(&mut self, reg: Reg) {
+    if reg as u8 >= 8 {
+      self.code.push(0x41);
+    }
+    self.code.push(0x58 | (reg as u8 & 7));
+  }
+
+  /// `syscall`
+  pub fn syscall(&mut self) {
+    self.bytes(&[0x0f, 0x05]);
+  }
+
+  /// `ret`
+  pub fn ret(&mut self) {
+    self.code.push(0xc3);
+  }
+
+  /// Conditional jump to a known position, picking the short form when possible
+  pub fn jcc(&mut self, cond: Cond, target: usize) {
+    let rel = target as i64 - (self.pos() as i64 + 2);
+    if (-0x80..=0x7f).contains(&rel) {
+      self.bytes(&[0x70 | cond as u8, rel as u8]);
+    } else {
+      self.bytes(&[0x0f, 0x80 | cond as u8]);
+      self.imm(Size::Dword, target as i64 - (self.pos() as i64 + 4));
+    }
+  }
+
+  /// Unconditional jump to a known position, picking the short form when possible
+  pub fn jmp(&mut self, target: usize) {
+    let rel = target as i64 - (self.pos() as i64 + 2);
+    if (-0x80..=0x7f).contains(&rel) {
+      self.bytes(&[0xeb, rel as u8]);
+    } else {
+      self.code.push(0xe9);
+      self.imm(Size::Dword, target as i64 - (self.pos() as i64 + 4));
+    }
+  }
+
+  /// Indirect call, `call qword rm`
+  pub fn call_rm(&mut self, rm: impl Into<Rm>) {
+    //64-bit operand size is the default here, no REX.W needed
+    self.op_rm(Size::Dword, &[0xff], 2, rm.into());
+  }
+
+  /// Call to a position that is not known yet
+  pub fn call_forward(&mut self) -> Fixup {
+    self.bytes(&[0xe8, 0, 0, 0, 0]);
+    Fixup { end: self.pos(), short: false }
+  }
+
+  /// Conditional jump to a position that is not known yet\
+  /// Short jumps panic on bind if the target turns out to be too far away
+  pub fn jcc_forward(&mut self, cond: Cond, short: bool) -> Fixup {
+    if short {
+      self.bytes(&[0x70 | cond as u8, 0]);
+    } else {
+      self.bytes(&[0x0f, 0x80 | cond as u8, 0, 0, 0, 0]);
+    }
+    Fixup { end: self.pos(), short }
+  }
+
+  /// Unconditional jump to a position that is not known yet
+  pub fn jmp_forward(&mut self, short: bool) -> Fixup {
+    if short {
+      self.bytes(&[0xeb, 0]);
+    } else {
+      self.bytes(&[0xe9, 0, 0, 0, 0]);
+    }
+    Fixup { end: self.pos(), short }
+  }
+
+  /// Resolve a forward jump to the current position
+  pub fn bind(&mut self, fixup: Fixup) {
+    let rel = self.pos() - fixup.end;
+    if fixup.short {
+      assert!(rel <= 0x7f, "short jump out of range");
+      self.code[fixup.end - 1] = rel as u8;
+    } else {
+      self.code[(fixup.end - 4)..fixup.end].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+  }
+}
+
+/// Xmm registers share encoding numbers with general purpose registers
+fn xmm_rm(xmm: Xmm) -> Rm {
+  Rm::Reg([Reg::Rax, Reg::Rcx, Reg::Rdx, Reg::Rbx, Reg::Rsp, Reg::Rbp, Reg::Rsi, Reg::Rdi][xmm as usize])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    f(&mut asm);
+    asm.into_code()
+  }
+
+  /// `mov byte [mem], al`
+  fn store(mem: Mem) -> Vec<u8> {
+    encode(|asm| asm.mov_rm_reg(Size::Byte, mem, Reg::Rax))
+  }
+
+  #[test]
+  fn displacements() {
+    assert_eq!(store(Mem::base(Reg::Rbx, 0)), [0x88, 0x03]);
+    assert_eq!(store(Mem::base(Reg::Rbx, 127)), [0x88, 0x43, 0x7f]);
+    assert_eq!(store(Mem::base(Reg::Rbx, 128)), [0x88, 0x83, 0x80, 0x00, 0x00, 0x00]);
+    assert_eq!(store(Mem::base(Reg::Rbx, -128)), [0x88, 0x43, 0x80]);
+    assert_eq!(store(Mem::base(Reg::Rbx, -129)), [0x88, 0x83, 0x7f, 0xff, 0xff, 0xff]);
+  }
+
+  #[test]
+  fn special_bases() {
+    //rbp and r13 need a displacement even if it's 0
+    assert_eq!(store(Mem::base(Reg::Rbp, 0)), [0x88, 0x45, 0x00]);
+    assert_eq!(store(Mem::base(Reg::R13, 0)), [0x41, 0x88, 0x45, 0x00]);
+    //rsp and r12 need a SIB byte
+    assert_eq!(store(Mem::base(Reg::Rsp, 0)), [0x88, 0x04, 0x24]);
+    assert_eq!(store(Mem::base(Reg::R12, 8)), [0x41, 0x88, 0x44, 0x24, 0x08]);
+    assert_eq!(
+      encode(|asm| asm.lea(Reg::Rcx, Mem::base(Reg::Rsp, 4096))),
+      [0x48, 0x8d, 0x8c, 0x24, 0x00, 0x10, 0x00, 0x00],
+    );
+    assert_eq!(encode(|asm| asm.lea(Reg::Rbx, Mem::indexed(Reg::Rax, Reg::Rdx, 1, 0))), [0x48, 0x8d, 0x1c, 0x10]);
+    assert_eq!(encode(|asm| asm.lea(Reg::Rax, Mem::indexed(Reg::R13, Reg::R9, 8, 0))), [0x4b, 0x8d, 0x44, 0xcd, 0x00]);
+  }
+
+  #[test]
+  fn rex_prefixes() {
+    //Without REX, these would be dh and bh
+    assert_eq!(encode(|asm| asm.mov_rm_reg(Size::Byte, Mem::base(Reg::Rbx, 0), Reg::Rsi)), [0x40, 0x88, 0x33]);
+    assert_eq!(encode(|asm| asm.mov_rm_reg(Size::Byte, Mem::base(Reg::Rbx, 0), Reg::Rdi)), [0x40, 0x88, 0x3b]);
+    assert_eq!(encode(|asm| asm.mov_reg_rm(Size::Byte, Reg::Rsi, Mem::base(Reg::Rbx, 0))), [0x40, 0x8a, 0x33]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Cmp, Size::Byte, Reg::Rsi, 0)), [0x40, 0x80, 0xfe, 0x00]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Cmp, Size::Byte, Reg::Rbx, 0)), [0x80, 0xfb, 0x00]);
+    //r8-r15 in reg, base and index
+    assert_eq!(encode(|asm| asm.mov_reg_rm(Size::Byte, Reg::R8, Mem::base(Reg::Rbx, 0))), [0x44, 0x8a, 0x03]);
+    assert_eq!(encode(|asm| asm.mov_rm_reg(Size::Byte, Mem::base(Reg::R12, 0), Reg::R15)), [0x45, 0x88, 0x3c, 0x24]);
+    assert_eq!(encode(|asm| asm.dec(Size::Qword, Mem::base(Reg::R12, 0))), [0x49, 0xff, 0x0c, 0x24]);
+    assert_eq!(encode(|asm| asm.push(Reg::R12)), [0x41, 0x54]);
+    assert_eq!(encode(|asm| asm.pop(Reg::Rbx)), [0x5b]);
+    assert_eq!(encode(|asm| asm.call_rm(Mem::base(Reg::R13, 8))), [0x41, 0xff, 0x55, 0x08]);
+  }
+
+  #[test]
+  fn immediates() {
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Add, Size::Qword, Reg::Rbx, 127)), [0x48, 0x83, 0xc3, 0x7f]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Add, Size::Qword, Reg::Rbx, 128)), [0x48, 0x81, 0xc3, 0x80, 0x00, 0x00, 0x00]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Sub, Size::Qword, Reg::Rbx, -128)), [0x48, 0x83, 0xeb, 0x80]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Add, Size::Qword, Reg::Rbx, -129)), [0x48, 0x81, 0xc3, 0x7f, 0xff, 0xff, 0xff]);
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::And, Size::Qword, Reg::Rax, -16)), [0x48, 0x83, 0xe0, 0xf0]);
+    //Bytes only have an imm8 form, words take an imm16
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Cmp, Size::Byte, Mem::base(Reg::Rbx, 0), 0)), [0x80, 0x3b, 0x00]);
+    assert_eq!(
+      encode(|asm| asm.alu_imm(Alu::Add, Size::Word, Mem::base(Reg::Rbx, 2), 300)),
+      [0x66, 0x81, 0x43, 0x02, 0x2c, 0x01],
+    );
+    assert_eq!(encode(|asm| asm.alu_imm(Alu::Add, Size::Dword, Mem::base(Reg::Rbx, 0), 1000)), [0x81, 0x03, 0xe8, 0x03, 0x00, 0x00]);
+    assert_eq!(encode(|asm| asm.imul_imm(Size::Dword, Reg::Rax, Reg::Rcx, 8)), [0x6b, 0xc1, 0x08]);
+    assert_eq!(encode(|asm| asm.imul_imm(Size::Dword, Reg::Rax, Reg::Rcx, 1000)), [0x69, 0xc1, 0xe8, 0x03, 0x00, 0x00]);
+    assert_eq!(encode(|asm| asm.mov_imm(Size::Dword, Reg::Rax, 60)), [0xc7, 0xc0, 0x3c, 0x00, 0x00, 0x00]);
+    assert_eq!(encode(|asm| asm.mov_imm(Size::Byte, Mem::base(Reg::Rbx, 0), 5)), [0xc6, 0x03, 0x05]);
+    assert_eq!(encode(|asm| asm.mov_imm(Size::Word, Mem::base(Reg::Rbx, 0), 0x1234)), [0x66, 0xc7, 0x03, 0x34, 0x12]);
+    assert_eq!(encode(|asm| asm.mov_imm(Size::Qword, Reg::Rdi, -1)), [0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff]);
+  }
+
+  #[test]
+  fn loads_and_shifts() {
+    assert_eq!(encode(|asm| asm.movzx(Size::Byte, Reg::Rcx, Mem::base(Reg::Rbx, 0))), [0x0f, 0xb6, 0x0b]);
+    assert_eq!(encode(|asm| asm.movzx(Size::Word, Reg::Rax, Mem::base(Reg::Rbx, 2))), [0x0f, 0xb7, 0x43, 0x02]);
+    assert_eq!(encode(|asm| asm.movzx(Size::Byte, Reg::R9, Mem::base(Reg::R12, 0))), [0x45, 0x0f, 0xb6, 0x0c, 0x24]);
+    assert_eq!(encode(|asm| asm.inc(Size::Byte, Mem::base(Reg::Rbx, 1))), [0xfe, 0x43, 0x01]);
+    assert_eq!(encode(|asm| asm.shift_cl(Shift::Shl, Size::Word, Reg::Rdx)), [0x66, 0xd3, 0xe2]);
+    assert_eq!(encode(|asm| asm.shift_cl(Shift::Shr, Size::Dword, Reg::Rdx)), [0xd3, 0xea]);
+  }
+
+  #[test]
+  fn sse() {
+    assert_eq!(encode(|asm| asm.pxor(Xmm::Xmm1, Xmm::Xmm1)), [0x66, 0x0f, 0xef, 0xc9]);
+    assert_eq!(encode(|asm| asm.movdqa_load(Xmm::Xmm0, Mem::base(Reg::Rax, 0))), [0x66, 0x0f, 0x6f, 0x00]);
+    //The operand size prefix goes before REX
+    assert_eq!(encode(|asm| asm.movdqa_load(Xmm::Xmm0, Mem::base(Reg::R8, 16))), [0x66, 0x41, 0x0f, 0x6f, 0x40, 0x10]);
+    assert_eq!(encode(|asm| asm.pcmpeqb(Xmm::Xmm0, Xmm::Xmm1)), [0x66, 0x0f, 0x74, 0xc1]);
+    assert_eq!(encode(|asm| asm.pmovmskb(Reg::Rdx, Xmm::Xmm0)), [0x66, 0x0f, 0xd7, 0xd0]);
+    assert_eq!(encode(|asm| asm.pmovmskb(Reg::R9, Xmm::Xmm0)), [0x66, 0x44, 0x0f, 0xd7, 0xc8]);
+  }
+
+  /// `padding` nops followed by whatever `f` emits
+  fn after_nops(padding: usize, f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+    encode(|asm| {
+      asm.bytes(&vec![0x90; padding]);
+      f(asm);
+    })[padding..].to_vec()
+  }
+
+  #[test]
+  fn backward_jumps() {
+    //Relative to the end of the 2-byte short form
+    assert_eq!(after_nops(126, |asm| asm.jcc(Cond::E, 0)), [0x74, 0x80]);
+    assert_eq!(after_nops(127, |asm| asm.jcc(Cond::E, 0)), [0x0f, 0x84, 0x7b, 0xff, 0xff, 0xff]);
+    assert_eq!(after_nops(126, |asm| asm.jmp(0)), [0xeb, 0x80]);
+    assert_eq!(after_nops(127, |asm| asm.jmp(0)), [0xe9, 0x7c, 0xff, 0xff, 0xff]);
+    //Forward targets that are already known work too
+    assert_eq!(encode(|asm| asm.jcc(Cond::Ne, 129)), [0x75, 0x7f]);
+    assert_eq!(encode(|asm| asm.jcc(Cond::Ne, 130)), [0x0f, 0x85, 0x7c, 0x00, 0x00, 0x00]);
+  }
+
+  #[test]
+  fn forward_jumps() {
+    let short = encode(|asm| {
+      let jump = asm.jcc_forward(Cond::B, true);
+      asm.bytes(&[0x90; 127]);
+      asm.bind(jump);
+    });
+    assert_eq!(short[..2], [0x72, 0x7f]);
+    let near = encode(|asm| {
+      let jump = asm.jcc_forward(Cond::Ae, false);
+      asm.bytes(&[0x90; 200]);
+      asm.bind(jump);
+    });
+    assert_eq!(near[..6], [0x0f, 0x83, 0xc8, 0x00, 0x00, 0x00]);
+    let jmp = encode(|asm| {
+      let jump = asm.jmp_forward(false);
+      asm.bytes(&[0x90; 3]);
+      asm.bind(jump);
+    });
+    assert_eq!(jmp[..5], [0xe9, 0x03, 0x00, 0x00, 0x00]);
+    let call = encode(|asm| {
+      let call = asm.call_forward();
+      asm.bind(call);
+    });
+    assert_eq!(call, [0xe8, 0x00, 0x00, 0x00, 0x00]);
+  }
+
+  #[test]
+  #[should_panic(expected = "short jump out of range")]
+  fn short_jump_out_of_range() {
+    encode(|asm| {
+      let jump = asm.jmp_forward(true);
+      asm.bytes(&[0x90; 128]);
+      asm.bind(jump);
+    });
+  }
+}
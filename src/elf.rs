@@ -0,0 +1,518 @@
+//! ELF executables and object files
+//!
+//! Generated code is position independent, so ahead of time compilation only needs to wrap it in a static,
+//! non-PIE ELF64 image along with a `_start` stub that runs it and exits, without involving libc.\
+//! The tape lives in a zero-initialized segment, with unmapped address space before it serving as a guard region,
+//! and nothing mapped right after it.\
+//! Code compiled without a [`RunContext`](crate::io::RunContext) can't skip MulAdds with a 0 source near the tape bounds,
+//! so the segment also pads the tape on both sides by as much as the program can reach, and these accesses land there.
+//! Accessing the tape further out of bounds kills the process with SIGSEGV, losing any pending output.
+//!
+//! Object files and assembly source export the same code as a C function instead, to be linked into other programs.
+
+use std::{collections::HashSet, fmt::Write};
+use crate::{
+  bfil::Op,
+  compiler::{label, AsmSyntax, CompilerImpl, CompilerOptions, NativeCompiler, Target},
+  program::{self, Error},
+  tape::Tape,
+};
+
+/// Address the image is loaded at
+const BASE_ADDRESS: u64 = 0x40_0000;
+/// Segment alignment, large enough for any page size used by Linux
+const SEGMENT_ALIGN: u64 = 0x1_0000;
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const PROGRAM_HEADER_COUNT: u64 = 3;
+/// Offset of the code in the file, right after the headers
+const CODE_OFFSET: u64 = HEADER_SIZE + PROGRAM_HEADER_SIZE * PROGRAM_HEADER_COUNT;
+
+/// Largest supported tape size in bytes, the whole image has to fit in the low 4GB of the address space
+pub const MAX_TAPE_SIZE: usize = 1 << 31;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+fn align_up(value: u64, align: u64) -> u64 {
+  value.div_ceil(align) * align
+}
+
+/// String table holding `strings`, along with the offset of each of them
+fn string_table(strings: &[&str]) -> (Vec<u8>, Vec<u32>) {
+  //String tables start with an empty string
+  let mut table = vec![0];
+  let offsets = strings.iter().map(|string| {
+    let offset = table.len() as u32;
+    table.extend_from_slice(string.as_bytes());
+    table.push(0);
+    offset
+  }).collect();
+  (table, offsets)
+}
+
+/// Fields of the ELF file header that vary between files
+struct FileHeader {
+  kind: u16,
+  entry: u64,
+  program_headers: u16,
+  section_headers_offset: u64,
+  section_headers: u16,
+  /// Index of the section holding section names
+  section_names: u16,
+}
+
+impl FileHeader {
+  fn write(&self, image: &mut Vec<u8>) {
+    //e_ident: 64-bit, little endian, System V ABI
+    image.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    //e_type, e_machine and e_version
+    image.extend_from_slice(&self.kind.to_le_bytes());
+    image.extend_from_slice(&NativeCompiler::ELF_MACHINE.to_le_bytes());
+    image.extend_from_slice(&1u32.to_le_bytes());
+    //e_entry, e_phoff, e_shoff and e_flags
+    image.extend_from_slice(&self.entry.to_le_bytes());
+    let program_headers_offset = if self.program_headers > 0 { HEADER_SIZE } else { 0 };
+    image.extend_from_slice(&program_headers_offset.to_le_bytes());
+    image.extend_from_slice(&self.section_headers_offset.to_le_bytes());
+    image.extend_from_slice(&0u32.to_le_bytes());
+    //e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum and e_shstrndx
+    image.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&self.program_headers.to_le_bytes());
+    image.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&self.section_headers.to_le_bytes());
+    image.extend_from_slice(&self.section_names.to_le_bytes());
+  }
+}
+
+struct ProgramHeader {
+  kind: u32,
+  flags: u32,
+  offset: u64,
+  address: u64,
+  file_size: u64,
+  mem_size: u64,
+}
+
+impl ProgramHeader {
+  fn write(&self, image: &mut Vec<u8>) {
+    image.extend_from_slice(&self.kind.to_le_bytes());
+    image.extend_from_slice(&self.flags.to_le_bytes());
+    image.extend_from_slice(&self.offset.to_le_bytes());
+    //p_vaddr and p_paddr
+    image.extend_from_slice(&self.address.to_le_bytes());
+    image.extend_from_slice(&self.address.to_le_bytes());
+    image.extend_from_slice(&self.file_size.to_le_bytes());
+    image.extend_from_slice(&self.mem_size.to_le_bytes());
+    let align = if self.kind == PT_LOAD { SEGMENT_ALIGN } else { 0 };
+    image.extend_from_slice(&align.to_le_bytes());
+  }
+}
+
+/// Build a static Linux executable running a bfil program on a zeroed tape of `tape_size` bytes,
+/// doing I/O on stdin and stdout\
+/// The process exits with status 0 once the program finishes
+///
+/// Panics if bounds checks are enabled or `tape_size` is larger than [`MAX_TAPE_SIZE`]
+pub fn executable(ops: &[Op], options: &CompilerOptions, tape_size: usize) -> Result<Vec<u8>, Error> {
+  if !NativeCompiler::supported() {
+    return Err(Error::Unsupported)
+  }
+  assert!(!options.bounds_checks, "bounds checks are not supported in standalone executables");
+  assert!(tape_size <= MAX_TAPE_SIZE, "tape is too large for a standalone executable");
+  let function = NativeCompiler::compile(ops, Target::Extern, options).code;
+  //The size of the stub doesn't depend on the tape address, which isn't known yet
+  let code_size = NativeCompiler::start_stub(0).len() as u64 + function.len() as u64;
+  let code_end = BASE_ADDRESS + CODE_OFFSET + code_size;
+  let padding = align_up((program::reach(ops) * options.cell_width.bytes()) as u64, SEGMENT_ALIGN);
+  let guard_size = align_up(padding.max(Tape::DEFAULT_GUARD_SIZE as u64), SEGMENT_ALIGN);
+  let tape_segment = align_up(code_end, SEGMENT_ALIGN) + guard_size;
+  let tape_address = tape_segment + padding;
+  let mut code = NativeCompiler::start_stub(tape_address);
+  code.extend_from_slice(&function);
+  assert_eq!(code.len() as u64, code_size, "start stub size depends on the tape address");
+
+  let mut image = Vec::with_capacity((CODE_OFFSET + code_size) as usize);
+  FileHeader {
+    kind: ET_EXEC,
+    entry: BASE_ADDRESS + CODE_OFFSET,
+    program_headers: PROGRAM_HEADER_COUNT as u16,
+    section_headers_offset: 0,
+    section_headers: 0,
+    section_names: 0,
+  }.write(&mut image);
+
+  let headers = [
+    //Headers and code
+    ProgramHeader {
+      kind: PT_LOAD,
+      flags: PF_R | PF_X,
+      offset: 0,
+      address: BASE_ADDRESS,
+      file_size: CODE_OFFSET + code_size,
+      mem_size: CODE_OFFSET + code_size,
+    },
+    //Padded tape, not backed by the file
+    ProgramHeader {
+      kind: PT_LOAD,
+      flags: PF_R | PF_W,
+      offset: 0,
+      address: tape_segment,
+      file_size: 0,
+      mem_size: padding + tape_size as u64 + padding,
+    },
+    //Non-executable stack
+    ProgramHeader {
+      kind: PT_GNU_STACK,
+      flags: PF_R | PF_W,
+      offset: 0,
+      address: 0,
+      file_size: 0,
+      mem_size: 0,
+    },
+  ];
+  for header in &headers {
+    header.write(&mut image);
+  }
+  debug_assert_eq!(image.len() as u64, CODE_OFFSET);
+  image.extend_from_slice(&code);
+  Ok(image)
+}
+
+struct SectionHeader {
+  name: u32,
+  kind: u32,
+  flags: u64,
+  offset: u64,
+  size: u64,
+  link: u32,
+  info: u32,
+  align: u64,
+  entry_size: u64,
+}
+
+impl SectionHeader {
+  fn write(&self, image: &mut Vec<u8>) {
+    image.extend_from_slice(&self.name.to_le_bytes());
+    image.extend_from_slice(&self.kind.to_le_bytes());
+    image.extend_from_slice(&self.flags.to_le_bytes());
+    //sh_addr, always 0 in object files
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&self.offset.to_le_bytes());
+    image.extend_from_slice(&self.size.to_le_bytes());
+    image.extend_from_slice(&self.link.to_le_bytes());
+    image.extend_from_slice(&self.info.to_le_bytes());
+    image.extend_from_slice(&self.align.to_le_bytes());
+    image.extend_from_slice(&self.entry_size.to_le_bytes());
+  }
+}
+
+/// Symbol table entry, for a symbol in `.text`
+fn write_symbol(image: &mut Vec<u8>, name: u32, info: u8, size: u64) {
+  image.extend_from_slice(&name.to_le_bytes());
+  image.push(info);
+  //st_other, st_shndx and st_value
+  image.push(0);
+  image.extend_from_slice(&1u16.to_le_bytes());
+  image.extend_from_slice(&0u64.to_le_bytes());
+  image.extend_from_slice(&size.to_le_bytes());
+}
+
+/// Whether `symbol` is a valid C identifier, and so can name the function exported by [`object`] and [`assembly`]
+pub fn is_valid_symbol(symbol: &str) -> bool {
+  symbol.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+    && symbol.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Compile a bfil program to a function for linking with C code, `uint8_t *symbol(uint8_t *tape)`
+fn compile_function(ops: &[Op], options: &CompilerOptions, symbol: &str) -> Result<Vec<u8>, Error> {
+  if !NativeCompiler::supported() {
+    return Err(Error::Unsupported)
+  }
+  assert!(!options.bounds_checks, "bounds checks are not supported without a RunContext");
+  assert!(is_valid_symbol(symbol), "invalid symbol name {symbol:?}");
+  Ok(NativeCompiler::compile(ops, Target::Extern, options).code)
+}
+
+/// Build a relocatable object file defining a bfil program as the global function
+/// `uint8_t *symbol(uint8_t *tape)`, doing I/O on stdin and stdout and returning the final data pointer\
+/// The code needs no relocations, and like all generated code it relies on the memory around the tape being mapped
+/// as far as [`Program::reach`](crate::Program::reach) goes, since it doesn't check the tape bounds
+///
+/// Panics if bounds checks are enabled or `symbol` is not a valid C identifier
+pub fn object(ops: &[Op], options: &CompilerOptions, symbol: &str) -> Result<Vec<u8>, Error> {
+  let code = compile_function(ops, options, symbol)?;
+  let (section_names, name_offsets) = string_table(&[".text", ".note.GNU-stack", ".symtab", ".strtab", ".shstrtab"]);
+  let (strings, string_offsets) = string_table(&[symbol]);
+
+  //Header, code, symbols, strings, section names, section headers
+  let mut image = vec![0; HEADER_SIZE as usize];
+  let code_offset = image.len() as u64;
+  image.extend_from_slice(&code);
+  image.resize(align_up(image.len() as u64, 8) as usize, 0);
+  let symbols_offset = image.len() as u64;
+  image.extend_from_slice(&[0; SYMBOL_SIZE as usize]);
+  write_symbol(&mut image, 0, STB_LOCAL << 4 | STT_SECTION, 0);
+  write_symbol(&mut image, string_offsets[0], STB_GLOBAL << 4 | STT_FUNC, code.len() as u64);
+  let strings_offset = image.len() as u64;
+  image.extend_from_slice(&strings);
+  let section_names_offset = image.len() as u64;
+  image.extend_from_slice(&section_names);
+  image.resize(align_up(image.len() as u64, 8) as usize, 0);
+
+  let sections = [
+    //Reserved null section
+    SectionHeader { name: 0, kind: 0, flags: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entry_size: 0 },
+    SectionHeader {
+      name: name_offsets[0],
+      kind: SHT_PROGBITS,
+      flags: SHF_ALLOC | SHF_EXECINSTR,
+      offset: code_offset,
+      size: code.len() as u64,
+      link: 0,
+      info: 0,
+      align: 16,
+      entry_size: 0,
+    },
+    //Non-executable stack
+    SectionHeader { name: name_offsets[1], kind: SHT_PROGBITS, flags: 0, offset: code_offset, size: 0, link: 0, info: 0, align: 1, entry_size: 0 },
+    //Linked to the string table, local symbols come first
+    SectionHeader {
+      name: name_offsets[2],
+      kind: SHT_SYMTAB,
+      flags: 0,
+      offset: symbols_offset,
+      size: strings_offset - symbols_offset,
+      link: 4,
+      info: 2,
+      align: 8,
+      entry_size: SYMBOL_SIZE,
+    },
+    SectionHeader {
+      name: name_offsets[3],
+      kind: SHT_STRTAB,
+      flags: 0,
+      offset: strings_offset,
+      size: strings.len() as u64,
+      link: 0,
+      info: 0,
+      align: 1,
+      entry_size: 0,
+    },
+    SectionHeader {
+      name: name_offsets[4],
+      kind: SHT_STRTAB,
+      flags: 0,
+      offset: section_names_offset,
+      size: section_names.len() as u64,
+      link: 0,
+      info: 0,
+      align: 1,
+      entry_size: 0,
+    },
+  ];
+  let mut header = vec![];
+  FileHeader {
+    kind: ET_REL,
+    entry: 0,
+    program_headers: 0,
+    section_headers_offset: image.len() as u64,
+    section_headers: sections.len() as u16,
+    section_names: 5,
+  }.write(&mut header);
+  image[..HEADER_SIZE as usize].copy_from_slice(&header);
+  for section in &sections {
+    section.write(&mut image);
+  }
+  Ok(image)
+}
+
+/// GNU assembler source equivalent to the object file built by [`object`], with instructions in `syntax`
+///
+/// Panics if bounds checks are enabled or `symbol` is not a valid C identifier
+pub fn assembly(ops: &[Op], options: &CompilerOptions, symbol: &str, syntax: AsmSyntax) -> Result<String, Error> {
+  let code = compile_function(ops, options, symbol)?;
+  let instructions = NativeCompiler::disassemble(&code, syntax);
+  let targets: HashSet<usize> = instructions.iter().filter_map(|inst| inst.target).collect();
+  let mut source = String::new();
+  if cfg!(target_arch = "x86_64") && syntax == AsmSyntax::Intel {
+    source.push_str("\t.intel_syntax noprefix\n");
+  }
+  writeln!(source, "\t.text\n\t.globl {symbol}\n\t.type {symbol}, %function\n\t.p2align 4\n{symbol}:").unwrap();
+  for inst in &instructions {
+    if targets.contains(&inst.offset) {
+      writeln!(source, "{}:", label(inst.offset)).unwrap();
+    }
+    writeln!(source, "\t{}", inst.text).unwrap();
+  }
+  //Forward branches may be bound right past the last instruction
+  if targets.contains(&code.len()) {
+    writeln!(source, "{}:", label(code.len())).unwrap();
+  }
+  writeln!(source, "\t.size {symbol}, .-{symbol}").unwrap();
+  writeln!(source, "\t.section .note.GNU-stack,\"\",%progbits").unwrap();
+  Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use itertools::Itertools;
+  use crate::{bfil, brainfuck::{parse_tree, CellWidth}};
+
+  fn u16_at(image: &[u8], offset: u64) -> u16 {
+    u16::from_le_bytes(image[offset as usize..][..2].try_into().unwrap())
+  }
+
+  fn u32_at(image: &[u8], offset: u64) -> u32 {
+    u32::from_le_bytes(image[offset as usize..][..4].try_into().unwrap())
+  }
+
+  fn u64_at(image: &[u8], offset: u64) -> u64 {
+    u64::from_le_bytes(image[offset as usize..][..8].try_into().unwrap())
+  }
+
+  /// NUL terminated string at `offset`
+  fn str_at(image: &[u8], offset: u64) -> &str {
+    let bytes = &image[offset as usize..];
+    std::str::from_utf8(&bytes[..bytes.iter().position(|&byte| byte == 0).unwrap()]).unwrap()
+  }
+
+  fn ops() -> Vec<Op> {
+    bfil::lower(&parse_tree("+[->+<]>[-<++>]<.[>]", CellWidth::U8).unwrap())
+  }
+
+  /// Fields of the file header shared by executables and object files: type, entry, program headers, section headers
+  fn file_header(image: &[u8]) -> (u16, u64, u16, u16) {
+    assert_eq!(image[..8], [0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    assert_eq!((u16_at(image, 18), u32_at(image, 20)), (NativeCompiler::ELF_MACHINE, 1));
+    assert_eq!((u16_at(image, 52), u16_at(image, 54), u16_at(image, 58)), (64, 56, 64));
+    (u16_at(image, 16), u64_at(image, 24), u16_at(image, 56), u16_at(image, 60))
+  }
+
+  #[test]
+  fn executable_headers() {
+    if !NativeCompiler::supported() {
+      return
+    }
+    let options = CompilerOptions::default();
+    let image = executable(&ops(), &options, 1000).unwrap();
+    assert_eq!(file_header(&image), (ET_EXEC, BASE_ADDRESS + CODE_OFFSET, 3, 0));
+    assert_eq!(u64_at(&image, 32), HEADER_SIZE);
+    //Type, flags, offset, address, file size, memory size and alignment of each program header
+    let headers: Vec<_> = (0..PROGRAM_HEADER_COUNT).map(|idx| {
+      let header = HEADER_SIZE + idx * PROGRAM_HEADER_SIZE;
+      assert_eq!(u64_at(&image, header + 16), u64_at(&image, header + 24), "p_vaddr and p_paddr differ");
+      let fields = [8, 16, 32, 40, 48].map(|field| u64_at(&image, header + field));
+      (u32_at(&image, header), u32_at(&image, header + 4), fields)
+    }).collect();
+    let file_size = image.len() as u64;
+    assert_eq!(headers[0], (PT_LOAD, PF_R | PF_X, [0, BASE_ADDRESS, file_size, file_size, SEGMENT_ALIGN]));
+    assert_eq!(headers[2], (PT_GNU_STACK, PF_R | PF_W, [0; 5]));
+    //The tape segment is zeroed memory, starting a guard region after the code, with padding around the tape
+    let (kind, flags, [offset, address, tape_file_size, mem_size, align]) = headers[1];
+    assert_eq!((kind, flags, offset, tape_file_size, align), (PT_LOAD, PF_R | PF_W, 0, 0, SEGMENT_ALIGN));
+    let padding = (mem_size - 1000) / 2;
+    assert_eq!((address % SEGMENT_ALIGN, padding % SEGMENT_ALIGN), (0, 0));
+    assert!(address >= BASE_ADDRESS + file_size + Tape::DEFAULT_GUARD_SIZE as u64);
+    assert!(padding as usize >= program::reach(&ops()));
+    //The start stub, pointed at the tape, is followed by the program
+    let code = NativeCompiler::compile(&ops(), Target::Extern, &options).code;
+    assert_eq!(image[CODE_OFFSET as usize..], [NativeCompiler::start_stub(address + padding), code].concat());
+  }
+
+  #[test]
+  fn object_sections_and_symbols() {
+    if !NativeCompiler::supported() {
+      return
+    }
+    let options = CompilerOptions::default();
+    let image = object(&ops(), &options, "run_bf").unwrap();
+    let section_headers = u64_at(&image, 40);
+    assert_eq!(file_header(&image), (ET_REL, 0, 0, 6));
+    assert_eq!((u64_at(&image, 32), u16_at(&image, 62)), (0, 5));
+    assert_eq!(section_headers + 6 * SECTION_HEADER_SIZE, image.len() as u64);
+    //Name, type, flags, offset, size, link, info and entry size of each section
+    let section = |idx: u64| {
+      let header = section_headers + idx * SECTION_HEADER_SIZE;
+      assert_eq!(u64_at(&image, header + 16), 0, "sh_addr isn't 0");
+      let [offset, size, entry_size] = [24, 32, 56].map(|field| u64_at(&image, header + field));
+      let (link, info) = (u32_at(&image, header + 40), u32_at(&image, header + 44));
+      (u32_at(&image, header), u32_at(&image, header + 4), u64_at(&image, header + 8), offset, size, link, info, entry_size)
+    };
+    assert_eq!(section(0), (0, 0, 0, 0, 0, 0, 0, 0));
+    let (_, _, _, names_offset, ..) = section(5);
+    let names: Vec<_> = (1..6).map(|idx| str_at(&image, names_offset + section(idx).0 as u64)).collect();
+    assert_eq!(names, [".text", ".note.GNU-stack", ".symtab", ".strtab", ".shstrtab"]);
+    //The code needs no relocations
+    let code = NativeCompiler::compile(&ops(), Target::Extern, &options).code;
+    let (_, kind, flags, code_offset, code_size, ..) = section(1);
+    assert_eq!((kind, flags, &image[code_offset as usize..][..code_size as usize]), (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, &code[..]));
+    let (_, kind, flags, _, size, ..) = section(2);
+    assert_eq!((kind, flags, size), (SHT_PROGBITS, 0, 0));
+    //A null symbol, the .text section symbol, then the function, the only global symbol
+    let (_, kind, _, symbols, size, link, info, entry_size) = section(3);
+    assert_eq!((kind, size, link, info, entry_size), (SHT_SYMTAB, 3 * SYMBOL_SIZE, 4, 2, SYMBOL_SIZE));
+    let (_, kind, _, strings, ..) = section(4);
+    assert_eq!(kind, SHT_STRTAB);
+    let symbol = |idx: u64| {
+      let entry = symbols + idx * SYMBOL_SIZE;
+      let name = str_at(&image, strings + u32_at(&image, entry) as u64);
+      (name, image[entry as usize + 4], u16_at(&image, entry + 6), u64_at(&image, entry + 8), u64_at(&image, entry + 16))
+    };
+    assert_eq!(symbol(0), ("", 0, 0, 0, 0));
+    assert_eq!(symbol(1), ("", STB_LOCAL << 4 | STT_SECTION, 1, 0, 0));
+    assert_eq!(symbol(2), ("run_bf", STB_GLOBAL << 4 | STT_FUNC, 1, 0, code.len() as u64));
+  }
+
+  #[test]
+  fn assembly_labels() {
+    if !NativeCompiler::supported() {
+      return
+    }
+    let (ops, options) = (ops(), CompilerOptions::default());
+    let code = NativeCompiler::compile(&ops, Target::Extern, &options).code;
+    for syntax in [AsmSyntax::Intel, AsmSyntax::Att] {
+      let source = assembly(&ops, &options, "run_bf", syntax).unwrap();
+      let header = "\t.text\n\t.globl run_bf\n\t.type run_bf, %function\n\t.p2align 4\nrun_bf:\n";
+      let (syntax_line, body) = source.split_once(header).unwrap();
+      let intel = cfg!(target_arch = "x86_64") && syntax == AsmSyntax::Intel;
+      assert_eq!(syntax_line, if intel { "\t.intel_syntax noprefix\n" } else { "" });
+      //Every branch target gets a label right in front of the instruction at that offset, and nothing else does
+      let instructions = NativeCompiler::disassemble(&code, syntax);
+      let targets: Vec<usize> = instructions.iter().filter_map(|inst| inst.target).sorted().dedup().collect();
+      assert!(!targets.is_empty());
+      let mut lines = body.lines().peekable();
+      let mut labeled = vec![];
+      for offset in instructions.iter().map(|inst| inst.offset).chain([code.len()]) {
+        if lines.next_if_eq(&format!("{}:", label(offset)).as_str()).is_some() {
+          labeled.push(offset);
+        }
+        if let Some(inst) = instructions.iter().find(|inst| inst.offset == offset) {
+          assert_eq!(lines.next(), Some(format!("\t{}", inst.text).as_str()));
+          assert!(inst.target.is_none_or(|target| inst.text.contains(&label(target))), "{}", inst.text);
+        }
+      }
+      assert_eq!(labeled, targets);
+      assert_eq!(lines.collect::<Vec<_>>(), ["\t.size run_bf, .-run_bf", "\t.section .note.GNU-stack,\"\",%progbits"]);
+    }
+  }
+}
@@ -0,0 +1,509 @@
+//! Reference tree-walking interpreter
+//!
+//! Runs [`BfOpBlock`] trees directly, optimized or not, with the same tape and I/O semantics as compiled code.\
+//! [`Program`](crate::Program) falls back to it when the native compiler doesn't support the current target,
+//! and it doubles as an oracle for checking the optimizer and the code generators.
+//!
+//! Unlike compiled code, every cell access is checked, so it doesn't rely on the tape guard regions.
+
+use std::io;
+use crate::{
+  brainfuck::{BfOpBlock, BfUnit, Effect, Span},
+  compiler::{CompilerOptions, EofMode, FlushPolicy},
+  io::BfIo,
+  program::RunError,
+  tape::{self, Tape},
+};
+
+/// Size of the output buffer in bytes, same as in generated code
+const OUTPUT_BUFFER_SIZE: usize = 0x1000;
+
+/// A [`BfUnit`] with its effects put in the order they're applied in, worked out once before running
+struct Unit {
+  /// Cell offset and effect of each step
+  steps: Vec<(isize, Effect)>,
+  /// Cells read by MulAdd effects, without duplicates
+  sources: Vec<isize>,
+  ptr_offset: isize,
+  span: Option<Span>,
+}
+
+impl Unit {
+  fn new(unit: &BfUnit) -> Self {
+    let mut sources = vec![];
+    for effect in unit.effects.values().flatten() {
+      if let Effect::MulAdd { src, .. } = *effect {
+        if !sources.contains(&src) {
+          sources.push(src);
+        }
+      }
+    }
+    Self { steps: unit.ordered_effects(), sources, ptr_offset: unit.ptr_offset, span: unit.span }
+  }
+}
+
+/// [`BfOpBlock`] with its units prepared for running
+enum Node {
+  Master(Vec<Node>),
+  Loop(Vec<Node>, Span),
+  Unit(Unit),
+  Scan { stride: isize, span: Span },
+}
+
+impl Node {
+  fn new(block: &BfOpBlock) -> Self {
+    match block {
+      BfOpBlock::Master(children) => Self::Master(children.iter().map(Self::new).collect()),
+      BfOpBlock::Loop(children, span) => Self::Loop(children.iter().map(Self::new).collect(), *span),
+      BfOpBlock::Unit(unit) => Self::Unit(Unit::new(unit)),
+      &BfOpBlock::Scan { stride, span } => Self::Scan { stride, span },
+    }
+  }
+}
+
+struct Interpreter<'a> {
+  tape: &'a mut Tape,
+  io: &'a mut dyn BfIo,
+  options: &'a CompilerOptions,
+  /// Data pointer, in cells
+  ptr: isize,
+  output: Vec<u8>,
+  /// MulAdd sources of the running unit along with their values, kept around to reuse the allocation
+  sources: Vec<(isize, u32)>,
+  /// First error returned by `io`, after which all input is treated as EOF and all output is discarded
+  error: Option<io::Error>,
+}
+
+impl Interpreter<'_> {
+  /// Byte offset of the cell at `ptr + off`, growing the tape to cover it if needed
+  fn cell_offset(&mut self, off: isize) -> Result<usize, RunError> {
+    let idx = self.ptr + off;
+    let Ok(idx) = usize::try_from(idx) else {
+      return Err(RunError::TapeUnderflow { offset: idx, span: None })
+    };
+    let bytes = self.options.cell_width.bytes();
+    let end = idx.checked_mul(bytes).and_then(|start| start.checked_add(bytes));
+    match end {
+      Some(end) if end <= self.tape.len() => (),
+      //Grow like guarded tapes do, at least doubling
+      Some(end) if end <= self.tape.limit() => self.tape.grow(end.max(2 * self.tape.len()).min(self.tape.limit())),
+      _ => return Err(RunError::TapeOverflow { offset: idx, span: None }),
+    }
+    Ok(idx * bytes)
+  }
+
+  fn load(&mut self, off: isize) -> Result<u32, RunError> {
+    let start = self.cell_offset(off)?;
+    Ok(tape::load_cell(&self.tape[start..start + self.options.cell_width.bytes()]))
+  }
+
+  fn store(&mut self, off: isize, value: u32) -> Result<(), RunError> {
+    let start = self.cell_offset(off)?;
+    tape::store_cell(&mut self.tape[start..start + self.options.cell_width.bytes()], value);
+    Ok(())
+  }
+
+  /// Write out and empty the output buffer, flushing `io` as well unless the policy is [`FlushPolicy::Full`]
+  fn flush(&mut self) {
+    if !self.output.is_empty() && self.error.is_none() {
+      let result = self.io.write_bytes(&self.output);
+      //Byte and line buffered output is meant to show up right away, even if `io` buffers it again
+      let result = result.and_then(|()| match self.options.flush_policy {
+        FlushPolicy::Full => Ok(()),
+        FlushPolicy::Byte | FlushPolicy::Line => self.io.flush(),
+      });
+      if let Err(err) = result {
+        self.error = Some(err);
+      }
+    }
+    self.output.clear();
+  }
+
+  /// Write out the output buffer, then flush everything written to `io` so far
+  fn flush_io(&mut self) {
+    self.flush();
+    if self.error.is_none() {
+      if let Err(err) = self.io.flush() {
+        self.error = Some(err);
+      }
+    }
+  }
+
+  /// Append a byte to the output buffer, flushing it according to the flush policy
+  fn output(&mut self, byte: u8) {
+    self.output.push(byte);
+    let flush = match self.options.flush_policy {
+      FlushPolicy::Byte => true,
+      FlushPolicy::Line => byte == b'\n' || self.output.len() >= OUTPUT_BUFFER_SIZE,
+      FlushPolicy::Full => self.output.len() >= OUTPUT_BUFFER_SIZE,
+    };
+    if flush {
+      self.flush();
+    }
+  }
+
+  /// Read a byte, `None` on EOF
+  fn input(&mut self) -> Option<u8> {
+    //Pending output has to be visible before blocking on input
+    self.flush_io();
+    if self.error.is_some() {
+      return None
+    }
+    self.io.read_byte().unwrap_or_else(|err| {
+      self.error = Some(err);
+      None
+    })
+  }
+
+  /// `sources` holds each MulAdd source cell along with its value at the start of the unit
+  fn apply(&mut self, off: isize, effect: Effect, sources: &[(isize, u32)]) -> Result<(), RunError> {
+    let width = self.options.cell_width;
+    match effect {
+      Effect::CellInc(n) => {
+        let value = self.load(off)?;
+        self.store(off, width.wrap(value.wrapping_add(n as u32)))?;
+      },
+      Effect::CellSet(value) => self.store(off, value)?,
+      Effect::MulAdd { src, factor } => {
+        //Like in compiled code, the cell is not touched at all if the source is 0
+        let (_, src) = *sources.iter().find(|&&(cell, _)| cell == src).expect("missing MulAdd source");
+        if src != 0 {
+          let value = self.load(off)?;
+          self.store(off, width.wrap(value.wrapping_add(src.wrapping_mul(factor as u32))))?;
+        }
+      },
+      Effect::Output => {
+        let value = self.load(off)?;
+        self.output(value as u8);
+      },
+      Effect::Input => {
+        self.cell_offset(off)?;
+        match (self.input(), self.options.eof_mode) {
+          (Some(byte), _) => self.store(off, byte as u32)?,
+          (None, EofMode::Unchanged) => (),
+          (None, EofMode::Zero) => self.store(off, 0)?,
+          (None, EofMode::MinusOne) => self.store(off, width.max())?,
+        }
+      },
+    }
+    Ok(())
+  }
+
+  fn run_unit(&mut self, unit: &Unit) -> Result<(), RunError> {
+    //Units only read a handful of sources, so searching them is cheaper than any map
+    let mut sources = std::mem::take(&mut self.sources);
+    sources.clear();
+    for &src in &unit.sources {
+      sources.push((src, self.load(src)?));
+    }
+    for &(off, effect) in &unit.steps {
+      self.apply(off, effect, &sources)?;
+    }
+    self.sources = sources;
+    self.ptr += unit.ptr_offset;
+    Ok(())
+  }
+
+  fn run_block(&mut self, block: &Node) -> Result<(), RunError> {
+    match block {
+      Node::Master(children) => {
+        for child in children {
+          self.run_block(child)?;
+        }
+      },
+      //Tape errors are attributed to the innermost block they happen in
+      Node::Loop(children, span) => {
+        while self.load(0).map_err(|err| err.caused_by(Some(*span)))? != 0 {
+          for child in children {
+            self.run_block(child)?;
+          }
+        }
+      },
+      Node::Unit(unit) => self.run_unit(unit).map_err(|err| err.caused_by(unit.span))?,
+      Node::Scan { stride, span } => {
+        while self.load(0).map_err(|err| err.caused_by(Some(*span)))? != 0 {
+          self.ptr += stride;
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+/// Run a tree with custom I/O, with the data pointer starting at `tape[0]`\
+/// The tree must have been parsed for `options.cell_width`, other options apply as they do to compiled code
+///
+/// Behaves like [`Program::run_with_io`](crate::Program::run_with_io),
+/// except that the size of the tape guard regions doesn't matter
+pub fn run(block: &BfOpBlock, tape: &mut Tape, io: &mut dyn BfIo, options: &CompilerOptions) -> Result<usize, RunError> {
+  let mut interpreter = Interpreter {
+    tape, io, options,
+    ptr: 0,
+    output: Vec::with_capacity(OUTPUT_BUFFER_SIZE),
+    sources: vec![],
+    error: None,
+  };
+  let result = interpreter.run_block(&Node::new(block));
+  interpreter.flush_io();
+  result?;
+  if let Some(err) = interpreter.error {
+    return Err(err.into())
+  }
+  //The data pointer may end up out of bounds without the program ever accessing the cell there
+  let Interpreter { tape, ptr, .. } = interpreter;
+  let bytes = options.cell_width.bytes();
+  match usize::try_from(ptr) {
+    Ok(offset) if offset < tape.limit() / bytes => {
+      tape.grow(((offset + 1) * bytes).max(tape.len()));
+      Ok(offset)
+    },
+    Ok(offset) => Err(RunError::TapeOverflow { offset, span: None }),
+    Err(_) => Err(RunError::TapeUnderflow { offset: ptr, span: None }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{brainfuck, CellWidth, Program};
+
+  /// Everything a program did to its I/O, in order
+  #[derive(Debug, Default, PartialEq, Eq)]
+  struct Log {
+    input: Vec<u8>,
+    /// Chunks of output, `None` for reads
+    events: Vec<Option<Vec<u8>>>,
+  }
+
+  impl BfIo for Log {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+      self.events.push(None);
+      Ok((!self.input.is_empty()).then(|| self.input.remove(0)))
+    }
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+      self.events.push(Some(bytes.to_vec()));
+      Ok(())
+    }
+  }
+
+  /// Order of writes and flushes
+  #[derive(Default)]
+  struct Flushes(String);
+
+  impl BfIo for Flushes {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+      Ok(None)
+    }
+    fn write_bytes(&mut self, _: &[u8]) -> io::Result<()> {
+      self.0.push('w');
+      Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      self.0.push('f');
+      Ok(())
+    }
+  }
+
+  /// Result of a run, with errors reduced to something comparable
+  #[derive(Debug, PartialEq, Eq)]
+  struct Outcome {
+    result: Result<usize, (&'static str, isize, Option<Span>)>,
+    events: Vec<Option<Vec<u8>>>,
+    /// The tape up to its last nonzero byte, which doesn't depend on how far it grew
+    cells: Vec<u8>,
+  }
+
+  fn outcome(result: Result<usize, RunError>, log: Log, tape: &Tape) -> Outcome {
+    let result = result.map_err(|err| match err {
+      RunError::TapeUnderflow { offset, span } => ("underflow", offset, span),
+      RunError::TapeOverflow { offset, span } => ("overflow", offset as isize, span),
+      RunError::Io(err) => panic!("unexpected I/O error {err}"),
+    });
+    let len = tape.iter().rposition(|&byte| byte != 0).map_or(0, |idx| idx + 1);
+    Outcome { result, events: log.events, cells: tape[..len].to_vec() }
+  }
+
+  /// Check a bounds checked run against the interpreter\
+  /// Checks run ahead of the code they guard, so they may stop a program before earlier output in the same
+  /// straight-line code, and report the furthest cell it accesses rather than the first one.\
+  /// Their spans are those of the code they're in front of, so a hoisted check points at the whole loop
+  /// instead of the code in it, and the check for the cell a loop starts at points at the code before the loop
+  fn assert_checked(interpreted: &Outcome, checked: &Outcome, context: &str) {
+    match (&interpreted.result, &checked.result) {
+      (Ok(_), Ok(_)) => assert_eq!(interpreted, checked, "{context}"),
+      (Err((kind, _, span)), Err((checked_kind, _, checked_span))) => {
+        assert_eq!(kind, checked_kind, "{context}");
+        let nested = match (span, checked_span) {
+          (Some(a), Some(b)) => (a.start <= b.start && b.end <= a.end) || (b.start <= a.start && a.end <= b.end),
+          _ => span == checked_span,
+        };
+        assert!(nested, "{context}: {span:?} and {checked_span:?} are unrelated");
+        let checked_output = output(checked);
+        assert!(output(interpreted).starts_with(&checked_output), "{context}: {checked_output:?}");
+      },
+      _ => panic!("{context}: interpreted {interpreted:?}, checked {checked:?}"),
+    }
+  }
+
+  /// Run `code` on the interpreter and compiled with every combination of options, optimized and not,
+  /// checking that both behave the same on tapes from `new_tape`, which gets the cell width\
+  /// Returns the outcome with 8-bit cells and the first of `eof_modes`
+  fn check_on(code: &str, input: &[u8], eof_modes: &[EofMode], new_tape: impl Fn(CellWidth) -> Tape) -> Outcome {
+    let mut first = None;
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+      for &eof_mode in eof_modes {
+        for flush_policy in [FlushPolicy::Byte, FlushPolicy::Line, FlushPolicy::Full] {
+          for bounds_checks in [false, true] {
+            let options = CompilerOptions { cell_width, eof_mode, flush_policy, bounds_checks };
+            for block in [brainfuck::parse_tree(code, cell_width).unwrap(), brainfuck::parse_tree_unoptimized(code).unwrap()] {
+              let mut tape = new_tape(cell_width);
+              let mut log = Log { input: input.to_vec(), events: vec![] };
+              let interpreted = outcome(run(&block, &mut tape, &mut log, &options), log, &tape);
+              let mut tape = new_tape(cell_width);
+              let mut log = Log { input: input.to_vec(), events: vec![] };
+              let program = Program::from_tree(&block, &options).unwrap();
+              let compiled = outcome(program.run_with_io(&mut tape, &mut log), log, &tape);
+              let context = format!("{code:?} with {options:?}");
+              if bounds_checks {
+                assert_checked(&interpreted, &compiled, &context);
+              } else {
+                assert_eq!(interpreted, compiled, "{context}");
+              }
+              first.get_or_insert(interpreted);
+            }
+          }
+        }
+      }
+    }
+    first.unwrap()
+  }
+
+  fn check_with(code: &str, input: &[u8], eof_modes: &[EofMode]) -> Outcome {
+    check_on(code, input, eof_modes, |cell_width| Tape::cells(64, cell_width))
+  }
+
+  fn check(code: &str, input: &[u8]) -> Outcome {
+    check_with(code, input, &[EofMode::Unchanged, EofMode::Zero, EofMode::MinusOne])
+  }
+
+  fn output(outcome: &Outcome) -> Vec<u8> {
+    outcome.events.iter().flatten().flatten().copied().collect()
+  }
+
+  fn span(start: usize, end: usize) -> Option<Span> {
+    Some(Span { start, end, line: 1, column: start + 1 })
+  }
+
+  #[test]
+  fn eof() {
+    assert_eq!(output(&check_with("+,.", b"", &[EofMode::Unchanged])), [1]);
+    assert_eq!(output(&check_with("+,.", b"", &[EofMode::Zero])), [0]);
+    assert_eq!(output(&check_with("+,.", b"", &[EofMode::MinusOne])), [255]);
+    //Stops at the 0 byte whatever the EOF mode
+    assert_eq!(output(&check(",[.,]", b"ab\0c")), b"ab");
+    //Only stops at EOF if it reads as 0
+    assert_eq!(output(&check_with(",[.,]", b"abc", &[EofMode::Zero])), b"abc");
+    assert_eq!(output(&check_with(",[.[-],]", b"abc", &[EofMode::Unchanged, EofMode::Zero])), b"abc");
+    assert_eq!(output(&check_with(",+[-.,+]", b"abc", &[EofMode::MinusOne])), b"abc");
+  }
+
+  #[test]
+  fn io_order() {
+    let outcome = check("++>+.<.>>,.<<.", b"z");
+    assert_eq!(output(&outcome), [1, 2, b'z', 2]);
+    //Output is written out before every read
+    assert_eq!(check(">.<.,", b"").events.last(), Some(&None));
+  }
+
+  #[test]
+  fn flushes() {
+    let block = brainfuck::parse_tree("++++++++++.>+.", CellWidth::U8).unwrap();
+    for (flush_policy, expected) in [(FlushPolicy::Byte, "wfwff"), (FlushPolicy::Line, "wfwff"), (FlushPolicy::Full, "wf")] {
+      let options = CompilerOptions { flush_policy, ..Default::default() };
+      let mut interpreted = Flushes::default();
+      run(&block, &mut Tape::new(64), &mut interpreted, &options).unwrap();
+      assert_eq!(interpreted.0, expected, "{flush_policy:?}");
+      let mut compiled = Flushes::default();
+      Program::from_tree(&block, &options).unwrap().run_with_io(&mut Tape::new(64), &mut compiled).unwrap();
+      assert_eq!(compiled.0, expected, "{flush_policy:?}");
+    }
+  }
+
+  #[test]
+  fn mul_add() {
+    let outcome = check("+++++[->++>+++<<]", b"");
+    assert_eq!(outcome.result, Ok(0));
+    assert_eq!(outcome.cells[..3], [0, 10, 15]);
+    assert_eq!(output(&check("++[->++>+++<<]>.>.", b"")), [4, 6]);
+  }
+
+  #[test]
+  fn scans() {
+    assert_eq!(check("+>>+>>+<<<<[>>]", b"").result, Ok(6));
+    assert_eq!(check(">+>+>+[<]", b"").result, Ok(0));
+    assert_eq!(check("+[<]", b"").result, Err(("underflow", -1, span(1, 4))));
+  }
+
+  #[test]
+  fn underflow() {
+    assert_eq!(check("<+", b"").result, Err(("underflow", -1, span(0, 2))));
+    assert_eq!(check(">.<<<+", b"").result, Err(("underflow", -2, span(0, 6))));
+    assert_eq!(check(">>+[-<<<+>>>]", b"").result, Err(("underflow", -1, span(3, 13))));
+    assert_eq!(check("+>+[<<.>>-]", b"").result, Err(("underflow", -1, span(4, 10))));
+    //The tape is rounded up to the page size
+    let result = check("+[>+]", b"").result;
+    assert!(matches!(result, Err(("overflow", _, found)) if found == span(2, 4)), "{result:?}");
+    //Stopping outside of the tape is an error as well, but not attributed to any code
+    assert_eq!(check("<", b"").result, Err(("underflow", -1, None)));
+  }
+
+  #[test]
+  fn growable() {
+    //Growing past the committed part keeps what's on the tape
+    let code = format!("+{}+.", ">".repeat(5000));
+    let outcome = check_on(&code, b"", &[EofMode::Unchanged], |cell_width| Tape::growable_cells(1, 20000, cell_width));
+    assert_eq!((outcome.result, output(&outcome), outcome.cells.len()), (Ok(5000), vec![1], 5001));
+    //Scans read ahead of the data pointer, into pages that aren't committed yet,
+    //stopping at the first cell past a full page of nonzero ones
+    let page = tape::page_size();
+    let code = format!("{}{}[>]+", "+>".repeat(page), "<".repeat(page));
+    let outcome = check_on(&code, b"", &[EofMode::Unchanged], |cell_width| Tape::growable_cells(1, 4 * page, cell_width));
+    assert_eq!((outcome.result, outcome.cells), (Ok(page), vec![1; page + 1]));
+    //Running into the limit is an overflow, whether scanning or not
+    let outcome = check_on("+[>+]", b"", &[EofMode::Unchanged], |cell_width| Tape::growable_cells(1, 2 * page, cell_width));
+    assert!(matches!(outcome.result, Err(("overflow", offset, _)) if offset == 2 * page as isize), "{:?}", outcome.result);
+    let code = format!("{}{}[>]", "+>".repeat(2 * page), "<".repeat(2 * page));
+    let outcome = check_on(&code, b"", &[EofMode::Unchanged], |cell_width| Tape::growable_cells(1, 2 * page, cell_width));
+    assert_eq!(outcome.result, Err(("overflow", 2 * page as isize, span(6 * page, 6 * page + 3))));
+  }
+
+  #[test]
+  fn cells() {
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+      let mut tape = Tape::cells(3, cell_width);
+      assert_eq!((tape.cell_width(), tape.len() % cell_width.bytes()), (cell_width, 0));
+      tape.set_cell(1, 0x1234_5678);
+      let block = brainfuck::parse_tree(">+>-", cell_width).unwrap();
+      let options = CompilerOptions { cell_width, ..Default::default() };
+      Program::from_tree(&block, &options).unwrap().run_with_io(&mut tape, &mut Log::default()).unwrap();
+      let truncated = 0x1234_5679 & cell_width.max();
+      assert_eq!([tape.get_cell(0), tape.get_cell(1), tape.get_cell(2)], [Some(0), Some(truncated), Some(cell_width.max())]);
+      assert_eq!(tape.get_cell(tape.len() / cell_width.bytes()), None);
+    }
+  }
+
+  #[test]
+  fn checked_spans() {
+    //Each failed bounds check leads back to the code it guards, hoisted checks to the loop around it
+    let options = CompilerOptions { bounds_checks: true, ..Default::default() };
+    for (code, expected) in [("<+", span(0, 2)), (">.<<<+", span(0, 6)), ("+[<]", span(1, 4)), ("+[>+]", span(2, 4)), (">>+[-<<<+>>>]", span(3, 13)), ("+>+[<<.>>-]", span(3, 11))] {
+      let block = brainfuck::parse_tree(code, CellWidth::U8).unwrap();
+      let result = Program::from_tree(&block, &options).unwrap().run_with_io(&mut Tape::new(64), &mut Log::default());
+      let found = match result {
+        Err(RunError::TapeUnderflow { span, .. } | RunError::TapeOverflow { span, .. }) => span,
+        result => panic!("{code:?} gave {result:?}"),
+      };
+      assert_eq!(found, expected, "{code:?}");
+    }
+  }
+}
@@ -0,0 +1,213 @@
+//! Program I/O for the [`Target::Host`](crate::Target::Host) target
+//!
+//! Generated code calls back into the host through a [`RunContext`],
+//! and [`Program`](crate::Program) implements that on top of the [`BfIo`] trait.
+
+use std::io::{self, Read, Write};
+use crate::{compiler::FlushPolicy, tape::Tape};
+
+/// Callbacks and tape bounds used by code compiled for [`Target::Host`](crate::Target::Host)
+///
+/// A pointer to this struct is passed as the second argument of the generated function,
+/// and handed back to each callback as-is.\
+/// Embedders that need extra state can put this struct at the start of a larger `#[repr(C)]` one.
+#[repr(C)]
+pub struct RunContext {
+  /// Read a single byte, returning a negative value on EOF
+  pub read_byte: unsafe extern "C" fn(ctx: *mut RunContext) -> i32,
+  /// Write `len` bytes of program output starting at `bytes`
+  pub write_bytes: unsafe extern "C" fn(ctx: *mut RunContext, bytes: *const u8, len: usize),
+  /// Start of the tape the program runs on\
+  /// Used to avoid touching cells outside of the tape when the program itself wouldn't
+  pub tape_start: *mut u8,
+  /// End of the tape, see `tape_start`
+  pub tape_end: *mut u8,
+  /// Set by code compiled with bounds checks when it stops early,
+  /// see [`CompilerOptions::bounds_checks`](crate::CompilerOptions::bounds_checks)
+  pub status: RunStatus,
+  /// Offset from `tape_start` of the cell that failed the bounds check, if any
+  pub fault_offset: isize,
+  /// Offset in the generated code of the bounds check that failed, if any,
+  /// which the [`SourceMap`](crate::SourceMap) of the code traces back to the source
+  pub check_offset: usize,
+}
+
+/// Why generated code returned
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunStatus {
+  /// The program ran to completion
+  #[default]
+  Finished = 0,
+  /// The program tried to access a cell before the start of the tape
+  TapeUnderflow = 1,
+  /// The program tried to access a cell past the end of the tape
+  TapeOverflow = 2,
+}
+
+/// Source of program input and sink for program output
+pub trait BfIo {
+  /// Read a single byte, `None` on EOF
+  fn read_byte(&mut self) -> io::Result<Option<u8>>;
+  /// Write out a chunk of program output\
+  /// Called whenever the [`FlushPolicy`] flushes the program's output buffer
+  fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+  /// Flush output written so far\
+  /// Called before blocking on input, once the program stops,
+  /// and after every [`BfIo::write_bytes`] unless the policy is [`FlushPolicy::Full`]
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Collects all output, no input
+impl BfIo for Vec<u8> {
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    Ok(None)
+  }
+  fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.extend_from_slice(bytes);
+    Ok(())
+  }
+}
+
+/// Feeds the slice as input, output is discarded
+impl BfIo for &[u8] {
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    let Some((&byte, rest)) = self.split_first() else {
+      return Ok(None)
+    };
+    *self = rest;
+    Ok(Some(byte))
+  }
+  fn write_bytes(&mut self, _: &[u8]) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Input from a reader, output to a writer\
+/// With [`FlushPolicy::Full`] the writer is only flushed before input is read and at the end of the run,
+/// so buffering in between is left to the writer
+impl<R: Read, W: Write> BfIo for (R, W) {
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    let mut byte = 0;
+    loop {
+      match self.0.read(std::slice::from_mut(&mut byte)) {
+        Ok(0) => return Ok(None),
+        Ok(_) => return Ok(Some(byte)),
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        Err(err) => return Err(err),
+      }
+    }
+  }
+  fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.1.write_all(bytes)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    self.1.flush()
+  }
+}
+
+/// Process stdin and stdout
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdIo;
+
+impl BfIo for StdIo {
+  fn read_byte(&mut self) -> io::Result<Option<u8>> {
+    (io::stdin().lock(), io::sink()).read_byte()
+  }
+  fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+    io::stdout().lock().write_all(bytes)
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    io::stdout().lock().flush()
+  }
+}
+
+/// [`RunContext`] forwarding to a [`BfIo`]
+#[repr(C)]
+pub(crate) struct IoContext<'a> {
+  callbacks: RunContext,
+  io: &'a mut dyn BfIo,
+  /// Whether to flush `io` after every write
+  flush_writes: bool,
+  /// First error returned by `io`, after which all input is treated as EOF
+  error: Option<io::Error>,
+}
+
+impl<'a> IoContext<'a> {
+  pub fn new(io: &'a mut dyn BfIo, tape: &mut Tape, flush_policy: FlushPolicy) -> Self {
+    let tape_start = tape.as_mut_ptr();
+    Self {
+      callbacks: RunContext {
+        read_byte: Self::read_byte,
+        write_bytes: Self::write_bytes,
+        tape_start,
+        //Growable tapes grow on accesses past their end, so only their limit matters
+        tape_end: tape_start.wrapping_add(tape.limit()),
+        status: RunStatus::Finished,
+        fault_offset: 0,
+        check_offset: 0,
+      },
+      io,
+      flush_writes: flush_policy != FlushPolicy::Full,
+      error: None,
+    }
+  }
+
+  pub fn as_run_context(&mut self) -> *mut RunContext {
+    (self as *mut Self).cast()
+  }
+
+  pub fn run_context(&self) -> &RunContext {
+    &self.callbacks
+  }
+
+  pub fn finish(self) -> io::Result<()> {
+    match self.error {
+      Some(err) => Err(err),
+      None => self.io.flush(),
+    }
+  }
+
+  /// # Safety
+  ///
+  /// `ctx` must point to the `callbacks` field of a live `IoContext`
+  unsafe extern "C" fn read_byte(ctx: *mut RunContext) -> i32 {
+    let this = unsafe { &mut *ctx.cast::<Self>() };
+    if this.error.is_some() {
+      return -1
+    }
+    //Output the program wrote so far has to be visible before blocking on input
+    if let Err(err) = this.io.flush() {
+      this.error = Some(err);
+      return -1
+    }
+    match this.io.read_byte() {
+      Ok(Some(byte)) => byte as i32,
+      Ok(None) => -1,
+      Err(err) => {
+        this.error = Some(err);
+        -1
+      },
+    }
+  }
+
+  /// # Safety
+  ///
+  /// `ctx` must point to the `callbacks` field of a live `IoContext`,
+  /// and `bytes` must be valid for reads of `len` bytes
+  unsafe extern "C" fn write_bytes(ctx: *mut RunContext, bytes: *const u8, len: usize) {
+    let this = unsafe { &mut *ctx.cast::<Self>() };
+    if this.error.is_some() {
+      return
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, len) };
+    let result = this.io.write_bytes(bytes);
+    //Byte and line buffered output is meant to show up right away, even if `io` buffers it again
+    let result = result.and_then(|()| if this.flush_writes { this.io.flush() } else { Ok(()) });
+    if let Err(err) = result {
+      this.error = Some(err);
+    }
+  }
+}
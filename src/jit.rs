@@ -1,30 +1,118 @@
-pub struct Executable {
+/// Anonymous memory mapping, unmapped on drop
+struct Mapping {
   memptr: *mut libc::c_void,
   size: usize
 }
 
-impl Executable {
-  pub fn new(size: usize) -> Self {
-    let memptr;
-    unsafe {
-      memptr = libc::mmap(
-        core::ptr::null_mut(), 
-        size, 
-        libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE, 
-        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, 
+impl Mapping {
+  fn new(size: usize, prot: libc::c_int) -> Self {
+    let memptr = unsafe {
+      libc::mmap(
+        core::ptr::null_mut(),
+        size,
+        prot,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
         -1, 0
-      );
-      assert_ne!(memptr, libc::MAP_FAILED);
-      assert_ne!(memptr, core::ptr::null_mut());
-      libc::memset(memptr, 0xcc, size);
+      )
     };
+    assert_ne!(memptr, libc::MAP_FAILED);
+    assert_ne!(memptr, core::ptr::null_mut());
     Self { memptr, size }
   }
 
+  fn protect(&self, prot: libc::c_int) {
+    unsafe {
+      assert_eq!(libc::mprotect(self.memptr, self.size, prot), 0);
+    }
+  }
+
+  fn get(&self) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(self.memptr as *const u8, self.size) }
+  }
+
+  /// # Safety
+  ///
+  /// The mapping must currently be writable
+  unsafe fn get_mut(&mut self) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(self.memptr as *mut u8, self.size) }
+  }
+}
+
+/// Make freshly written code visible to instruction fetch\
+/// AArch64 instruction caches aren't coherent with data caches,
+/// so the code has to be cleaned to the point of unification and stale instructions invalidated
+#[cfg(target_arch = "aarch64")]
+fn sync_icache(code: &[u8]) {
+  use std::arch::asm;
+  let ctr: u64;
+  unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags)) };
+  let dcache_line = 4 << ((ctr >> 16) & 0xf);
+  let icache_line = 4 << (ctr & 0xf);
+  let (start, end) = (code.as_ptr() as usize, code.as_ptr() as usize + code.len());
+  for line in (start & !(dcache_line - 1)..end).step_by(dcache_line) {
+    unsafe { asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags)) };
+  }
+  unsafe { asm!("dsb ish", options(nostack, preserves_flags)) };
+  for line in (start & !(icache_line - 1)..end).step_by(icache_line) {
+    unsafe { asm!("ic ivau, {}", in(reg) line, options(nostack, preserves_flags)) };
+  }
+  unsafe { asm!("dsb ish", "isb", options(nostack, preserves_flags)) };
+}
+
+/// x86_64 keeps instruction caches coherent on its own
+#[cfg(not(target_arch = "aarch64"))]
+fn sync_icache(_: &[u8]) {}
+
+impl Drop for Mapping {
+  fn drop(&mut self) {
+    unsafe {
+      assert_eq!(libc::munmap(self.memptr, self.size), 0);
+    }
+  }
+}
+
+/// Writable, not yet executable code buffer\
+/// Call [`ExecutableBuilder::seal`] once the code is in place
+pub struct ExecutableBuilder {
+  mapping: Mapping,
+}
+
+impl ExecutableBuilder {
+  /// Map `size` bytes of read-write memory, filled with `int3`
+  pub fn new(size: usize) -> Self {
+    let mut mapping = Mapping::new(size, libc::PROT_READ | libc::PROT_WRITE);
+    unsafe { mapping.get_mut() }.fill(0xcc);
+    Self { mapping }
+  }
+
+  pub fn get(&self) -> &[u8] {
+    self.mapping.get()
+  }
+
+  pub fn get_mut(&mut self) -> &mut [u8] {
+    //Builders are always mapped read-write
+    unsafe { self.mapping.get_mut() }
+  }
+
+  /// Make the code read-only and executable
+  pub fn seal(self) -> Executable {
+    self.mapping.protect(libc::PROT_READ | libc::PROT_EXEC);
+    sync_icache(self.mapping.get());
+    Executable { mapping: self.mapping }
+  }
+}
+
+/// Read-only, executable code (W^X)\
+/// Use [`Executable::unseal`] to patch it
+pub struct Executable {
+  mapping: Mapping,
+}
+
+impl Executable {
   pub fn from_slice(slice: &[u8]) -> Self {
-    let mut new = Self::new(slice.len());
-    new.copy_from_slice(slice);
-    new
+    let mut builder = ExecutableBuilder::new(slice.len());
+    builder.get_mut().copy_from_slice(slice);
+    builder.seal()
   }
 
   // pub fn resize(&mut self, size: usize) {
@@ -38,25 +126,47 @@ impl Executable {
   // }
 
   pub fn get(&self) -> &[u8] {
-    unsafe { core::slice::from_raw_parts(self.memptr as *const u8, self.size) }
+    self.mapping.get()
   }
 
+  /// Make the code writable (and not executable) again, until the returned guard is resealed or dropped
+  ///
+  /// # Safety
+  ///
+  /// The code must not be running, or start running on another thread, until it's resealed
+  pub unsafe fn unseal(&mut self) -> Unsealed<'_> {
+    self.mapping.protect(libc::PROT_READ | libc::PROT_WRITE);
+    Unsealed { executable: self }
+  }
+}
+
+/// Writable view of an unsealed [`Executable`], sealed again on drop
+pub struct Unsealed<'a> {
+  executable: &'a mut Executable,
+}
+
+impl Unsealed<'_> {
   pub fn get_mut(&mut self) -> &mut [u8] {
-    unsafe { core::slice::from_raw_parts_mut(self.memptr as *mut u8, self.size) }
+    unsafe { self.executable.mapping.get_mut() }
   }
+
+  /// Make the code read-only and executable again
+  pub fn reseal(self) {}
 }
 
-impl Drop for Executable {
+impl Drop for Unsealed<'_> {
   fn drop(&mut self) {
-    unsafe {
-      assert_eq!(libc::munmap(self.memptr, self.size), 0);
-    }
+    self.executable.mapping.protect(libc::PROT_READ | libc::PROT_EXEC);
+    sync_icache(self.executable.mapping.get());
   }
 }
 
 // ToFnPtr impl
 
 pub trait ToFnPtr<A, F> {
+  /// # Safety
+  ///
+  /// The executable must contain a valid function with a matching signature
   unsafe fn to_fn_ptr(&self) -> F;
 }
 
@@ -65,7 +175,7 @@ macro_rules! to_fn_ptr_impl {
     impl<R, $($arg,)*> ToFnPtr<($($arg,)*), unsafe extern "C" fn($($arg,)*) -> R> for Executable {
       #[inline(always)]
       unsafe fn to_fn_ptr(&self) -> unsafe extern "C" fn($($arg,)*) -> R {
-        unsafe { core::mem::transmute::<_, unsafe extern "C" fn($($arg,)*) -> R>(self.memptr) }
+        unsafe { core::mem::transmute::<_, unsafe extern "C" fn($($arg,)*) -> R>(self.mapping.memptr) }
       }
     }
   };
@@ -87,9 +197,7 @@ to_fn_ptr_impl_recursive!(ARG9 ARG8 ARG7 ARG6 ARG5 ARG4 ARG3 ARG2 ARG1 ARG0);
 
 impl Clone for Executable {
   fn clone(&self) -> Self {
-    let mut new = Self::new(self.size);
-    new.copy_from_slice(self.get());
-    new
+    Self::from_slice(self.get())
   }
 }
 
@@ -102,22 +210,10 @@ impl core::ops::Deref for Executable {
   fn deref(&self) -> &Self::Target { self.get() }
 }
 
-impl core::ops::DerefMut for Executable {
-  fn deref_mut(&mut self) -> &mut Self::Target { self.get_mut() }
-}
-
 impl AsRef<[u8]> for Executable {
   fn as_ref(&self) -> &[u8] { self.get() }
 }
 
-impl AsMut<[u8]> for Executable {
-  fn as_mut(&mut self) -> &mut [u8] { self.get_mut() }
-}
-
 impl core::borrow::Borrow<[u8]> for Executable {
   fn borrow(&self) -> &[u8] { self.get() }
 }
-
-impl core::borrow::BorrowMut<[u8]> for Executable {
-  fn borrow_mut(&mut self) -> &mut [u8] { self.get_mut() }
-}
@@ -0,0 +1,22 @@
+#![forbid(unsafe_op_in_unsafe_fn)]
+
+#[cfg(not(unix))]
+compile_error!("non-unix-like systems are not supported");
+
+pub mod jit;
+pub mod brainfuck;
+pub mod bfil;
+pub mod compiler;
+pub mod io;
+pub mod tape;
+pub mod interpreter;
+pub mod elf;
+pub mod listing;
+mod program;
+
+pub use brainfuck::{parse_tree, BfOpBlock, CellWidth};
+pub use compiler::{AsmSyntax, CompilerImpl, CompilerOptions, EofMode, FlushPolicy, NativeCompiler, SourceMap, Target};
+pub use io::{BfIo, StdIo};
+pub use jit::{Executable, ExecutableBuilder};
+pub use program::{Program, Error, RunError};
+pub use tape::Tape;
@@ -0,0 +1,160 @@
+//! Assembly listings of generated code, annotated with the op tree and the source code it was generated from
+
+use std::{collections::HashSet, io::{self, Write}};
+use crate::{
+  bfil,
+  brainfuck::{self, BfOpBlock, Span},
+  compiler::{label, AsmSyntax, CompiledCode, CompilerImpl, Instruction},
+};
+
+/// Longest excerpt of the source code shown for a block, in instructions
+const EXCERPT_LEN: usize = 40;
+
+/// Width of the offset column, tree nodes and comments are indented to line up with the instructions
+const GUTTER: usize = 8;
+
+/// 1-based line and column of the character at byte `offset`
+fn position(source: &str, offset: usize) -> (usize, usize) {
+  let before = &source[..offset];
+  let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+  (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+struct Listing<'a, W> {
+  instructions: &'a [Instruction],
+  targets: HashSet<usize>,
+  op_offsets: &'a [usize],
+  source: &'a str,
+  /// Index of the next instruction to print
+  next_instruction: usize,
+  /// Index of the op the next block was lowered to
+  next_op: usize,
+  out: &'a mut W,
+}
+
+impl<W: Write> Listing<'_, W> {
+  fn comment(&mut self, indent: usize, comment: &str) -> io::Result<()> {
+    writeln!(self.out, "{:GUTTER$}{}; {comment}", "", "  ".repeat(indent))
+  }
+
+  /// `; 1:5-2:10 [->+<]`, with the excerpt cut short and everything besides instructions left out
+  fn source_range(&mut self, indent: usize, span: Option<Span>) -> io::Result<()> {
+    let Some(span) = span else {
+      return Ok(())
+    };
+    //Spans start and end at instructions, which are all ASCII
+    let (end_line, end_column) = position(self.source, span.end - 1);
+    let mut excerpt: String = self.source[span.start..span.end].chars()
+      .filter(|c| "+-<>[],.".contains(*c))
+      .collect();
+    if excerpt.len() > EXCERPT_LEN {
+      excerpt.truncate(EXCERPT_LEN);
+      excerpt.push_str("...");
+    }
+    let range = match (span.line, span.column) == (end_line, end_column) {
+      true => format!("{}:{}", span.line, span.column),
+      false => format!("{}:{}-{end_line}:{end_column}", span.line, span.column),
+    };
+    self.comment(indent, &format!("{range} {excerpt}"))
+  }
+
+  /// Print the instructions before `end`, along with labels for the branch targets among them
+  fn instructions_until(&mut self, end: usize, indent: usize) -> io::Result<()> {
+    while let Some(inst) = self.instructions.get(self.next_instruction).filter(|inst| inst.offset < end) {
+      if self.targets.contains(&inst.offset) {
+        writeln!(self.out, "{}:", label(inst.offset))?;
+      }
+      writeln!(self.out, "{:>w$x}  {}{}", inst.offset, "  ".repeat(indent), inst.text, w = GUTTER - 2)?;
+      self.next_instruction += 1;
+    }
+    Ok(())
+  }
+
+  /// Print the code of the next `count` ops
+  fn ops(&mut self, count: usize, indent: usize) -> io::Result<()> {
+    self.next_op += count;
+    self.instructions_until(self.op_offsets[self.next_op], indent)
+  }
+
+  /// Target of the last branch in the code of the next op, which is where a loop head exits to
+  /// or where a loop tail jumps back to
+  fn next_op_branch(&self) -> Option<usize> {
+    let (start, end) = (self.op_offsets[self.next_op], self.op_offsets[self.next_op + 1]);
+    self.instructions.iter()
+      .rev()
+      .filter(|inst| (start..end).contains(&inst.offset))
+      .find_map(|inst| inst.target)
+  }
+
+  /// Print a node of the tree, then the code it was lowered to
+  fn node(&mut self, block: &BfOpBlock, indent: usize) -> io::Result<()> {
+    let pad = " ".repeat(GUTTER) + &"  ".repeat(indent);
+    match block {
+      BfOpBlock::Master(children) => {
+        for child in children {
+          self.node(child, indent)?;
+        }
+      },
+      BfOpBlock::Loop(children, span) => {
+        self.source_range(indent, Some(*span))?;
+        let exit = self.next_op_branch().map(|target| format!("  ; exit to {}", label(target)));
+        writeln!(self.out, "{pad}loop {{{}", exit.unwrap_or_default())?;
+        self.ops(1, indent)?;
+        for child in children {
+          self.node(child, indent + 1)?;
+        }
+        let head = self.next_op_branch().map(|target| format!("  ; repeat from {}", label(target)));
+        writeln!(self.out, "{pad}}}{}", head.unwrap_or_default())?;
+        self.ops(1, indent)?;
+      },
+      BfOpBlock::Unit(_) | BfOpBlock::Scan { .. } => {
+        self.source_range(indent, block.span())?;
+        let mut node = vec![];
+        brainfuck::debug_print_tree(block, 0, &mut node)?;
+        for line in String::from_utf8_lossy(&node).lines() {
+          writeln!(self.out, "{pad}{line}")?;
+        }
+        self.ops(bfil::lower(block).len(), indent)?;
+      },
+    }
+    Ok(())
+  }
+}
+
+/// Print an assembly listing of the code `C` generated for `block`,
+/// with every node of the tree and the range of `source` it came from in front of its code\
+/// `compiled` has to be compiled from [`bfil::lower`] of `block`, and `source` has to be the code `block` was parsed from\
+/// Branch targets are labelled as in [`Instruction::text`]
+pub fn print<C: CompilerImpl, W: Write>(
+  block: &BfOpBlock,
+  compiled: &CompiledCode,
+  source: &str,
+  syntax: AsmSyntax,
+  out: &mut W,
+) -> io::Result<()> {
+  let instructions = C::disassemble(&compiled.code, syntax);
+  let mut listing = Listing {
+    targets: instructions.iter().filter_map(|inst| inst.target).collect(),
+    instructions: &instructions,
+    op_offsets: &compiled.op_offsets,
+    source,
+    next_instruction: 0,
+    next_op: 0,
+    out,
+  };
+  if compiled.op_offsets[0] > 0 {
+    listing.comment(0, "prologue")?;
+    listing.instructions_until(compiled.op_offsets[0], 0)?;
+  }
+  listing.node(block, 0)?;
+  assert_eq!(listing.next_op + 1, compiled.op_offsets.len(), "code wasn't compiled from this tree");
+  if listing.next_instruction < instructions.len() {
+    listing.comment(0, "epilogue and runtime routines")?;
+    listing.instructions_until(compiled.code.len(), 0)?;
+  }
+  //Forward branches may be bound right past the last instruction
+  if listing.targets.contains(&compiled.code.len()) {
+    writeln!(listing.out, "{}:", label(compiled.code.len()))?;
+  }
+  Ok(())
+}
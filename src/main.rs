@@ -1,48 +1,184 @@
-#![forbid(unsafe_op_in_unsafe_fn)]
+use std::{fs, io::{self, Read, Write}, os::unix::fs::OpenOptionsExt, process::ExitCode, time::Instant};
+use brainfuck_jit::{bfil, brainfuck, elf, listing, BfOpBlock, CompilerImpl, CompilerOptions, NativeCompiler, Program, Tape, Target};
 
-#[cfg(not(unix))]
-compile_error!("non-unix-like systems are not supported");
+mod cli;
+use cli::{Command, Emit, RunArgs, Source};
 
-use std::{rc::Rc, fs, env, time::Instant};
+fn compiler_options(args: &RunArgs) -> CompilerOptions {
+  CompilerOptions {
+    eof_mode: args.eof_mode,
+    flush_policy: args.flush_policy,
+    cell_width: args.cell_width,
+    bounds_checks: args.bounds_checks,
+  }
+}
 
-mod jit;
-mod brainfuck;
-mod compiler;
+/// Read and parse the program, printing the IR dumps requested by `args`\
+/// Returns the source code along with the tree
+fn parse(args: &RunArgs) -> Result<(String, BfOpBlock), String> {
+  let bf_code = match &args.source {
+    Source::File(path) => fs::read_to_string(path)
+      .map_err(|err| format!("failed to read {}: {err}", path.display()))?,
+    Source::Inline(code) => code.clone(),
+    Source::Stdin => {
+      let mut code = String::new();
+      io::stdin().read_to_string(&mut code)
+        .map_err(|err| format!("failed to read program from stdin: {err}"))?;
+      code
+    },
+  };
 
-use jit::{Executable, ToFnPtr};
-use compiler::{CompilerImpl, Target};
+  let mut stderr = io::stderr().lock();
 
-fn main() {
-  let bf_code = fs::read_to_string(env::args().nth(1).unwrap()).expect("file read error");
+  let block = match args.optimize {
+    true => brainfuck::parse_tree(&bf_code, args.cell_width),
+    false => brainfuck::parse_tree_unoptimized(&bf_code),
+  };
+  let block = match block {
+    Ok(block) => block,
+    Err(err) => {
+      for error in &err.errors {
+        writeln!(stderr, "{}", cli::render_syntax_error(error, &bf_code, &args.source.name())).unwrap();
+      }
+      return Err(match err.errors.len() {
+        1 => "aborting due to 1 previous error".into(),
+        n => format!("aborting due to {n} previous errors"),
+      })
+    }
+  };
+  if args.dump_ir {
+    writeln!(stderr, "=== IR").unwrap();
+    brainfuck::debug_print_tree(&block, 0, &mut stderr).unwrap();
+  }
 
-  println!("=== Parsing and optimizing bf code...");
-  println!("{bf_code}");
-  let block = brainfuck::parse_tree(&bf_code);
-  brainfuck::debug_print_tree(Rc::clone(&block), 0);
+  if args.dump_bfil {
+    let ops = bfil::lower(&block);
+    writeln!(stderr, "=== bfil").unwrap();
+    match args.bounds_checks {
+      //Show the checks the compiler is going to insert
+      true => bfil::print(&bfil::insert_bounds_checks(&ops), &mut stderr).unwrap(),
+      false => bfil::print(&ops, &mut stderr).unwrap(),
+    }
+  }
+  Ok((bf_code, block))
+}
 
-  println!("\n=== Running x86_64 codegen on the master block");
-  assert!(compiler::NativeCompiler::supported(), "compiler does not support current target");
-  let native_code = compiler::NativeCompiler::compile(
-    Rc::clone(&block),
-    Some(Target::Extern)
-  );
-  println!("{}",
-    native_code.iter()
-      .map(|b| format!("{:02x}", b).to_string())
+fn dump_hex(title: &str, code: &[u8]) {
+  eprintln!("=== {title} ({} bytes)", code.len());
+  eprintln!("{}",
+    code.iter()
+      .map(|b| format!("{:02x}", b))
       .collect::<Vec<String>>()
       .join(" ")
   );
+}
+
+/// Print the listing of the code compiled from `block` for `target`, the same code the program runs
+fn dump_asm(args: &RunArgs, block: &BfOpBlock, source: &str, target: Target) {
+  let compiled = NativeCompiler::compile(&bfil::lower(block), target, &compiler_options(args));
+  let mut stderr = io::stderr().lock();
+  writeln!(stderr, "=== Assembly ({} bytes)", compiled.code.len()).unwrap();
+  listing::print::<NativeCompiler, _>(block, &compiled, source, args.syntax, &mut stderr).unwrap();
+}
 
-  println!("\n=== Running the generated code:");
-  let mut bf_memory = [0u8; 0xffff];
-  let block = Executable::from(&native_code[..]);
-  let fn_ptr: unsafe extern fn(*mut u8) = unsafe { block.to_fn_ptr() };
+fn run(args: RunArgs) -> Result<(), String> {
+  let (source, block) = parse(&args)?;
+  let options = compiler_options(&args);
+  let program = match args.interpret {
+    true => Program::interpreted(&block, &options),
+    false => Program::from_tree(&block, &options).map_err(|err| err.to_string())?,
+  };
+  if args.dump_hex {
+    dump_hex("Machine code", program.code());
+  }
+  if args.dump_asm {
+    match program.is_interpreted() {
+      true => eprintln!("=== Assembly (none, running on the interpreter)"),
+      false => dump_asm(&args, &block, &source, Target::Host),
+    }
+  }
+
+  let guard_size = program.reach().max(Tape::DEFAULT_GUARD_SIZE);
+  let mut tape = match args.tape_limit {
+    Some(limit) => Tape::growable_cells_with_guard_size(args.tape_size, limit, args.cell_width, guard_size),
+    None => Tape::cells_with_guard_size(args.tape_size, args.cell_width, guard_size),
+  };
+  let mut stderr = io::stderr().lock();
   let instant = Instant::now();
-  unsafe { fn_ptr(bf_memory[0x100..].as_mut_ptr()) };
+  let result = program.run(&mut tape);
   let elapsed = instant.elapsed().as_secs_f64();
 
-  println!("\nNyaa~ no segfault! (*＾▽＾)っ✨");
-  println!("Execution time: {:.3}ms", elapsed * 1000.0);
-  println!("\n=== bfmem state (showing first 30 bytes)");
-  println!("{:02x?}", &bf_memory[0x100..(0x100 + 30)]);
+  if args.time {
+    writeln!(stderr, "=== Execution time: {:.3}ms", elapsed * 1000.0).unwrap();
+  }
+  if let Some(cells) = args.dump_tape {
+    writeln!(stderr, "=== Tape (first {cells} cells)").unwrap();
+    let values: Vec<u32> = (0..cells).map_while(|idx| tape.get_cell(idx)).collect();
+    writeln!(stderr, "{:02x?}", values).unwrap();
+    if let Ok(data_ptr) = result {
+      writeln!(stderr, "Data pointer: {data_ptr}").unwrap();
+    }
+  }
+  result
+    .map(drop)
+    .map_err(|err| err.to_string())
+}
+
+fn build(args: RunArgs) -> Result<(), String> {
+  let (source, block) = parse(&args)?;
+  let ops = bfil::lower(&block);
+  let options = compiler_options(&args);
+  let output = match args.emit {
+    Emit::Exe => {
+      let tape_size = args.tape_size.checked_mul(args.cell_width.bytes())
+        .filter(|&size| size <= elf::MAX_TAPE_SIZE)
+        .ok_or_else(|| format!("tape is too large for a standalone executable (at most {} bytes)", elf::MAX_TAPE_SIZE))?;
+      elf::executable(&ops, &options, tape_size)
+    },
+    Emit::Obj => elf::object(&ops, &options, &args.symbol),
+    Emit::Asm => elf::assembly(&ops, &options, &args.symbol, args.syntax).map(String::into_bytes),
+  }.map_err(|err| err.to_string())?;
+  if args.dump_hex {
+    dump_hex("Output", &output);
+  }
+  //Every kind of output contains the same function
+  if args.dump_asm {
+    dump_asm(&args, &block, &source, Target::Extern);
+  }
+  let path = args.output.expect("no output path");
+  fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(if args.emit == Emit::Exe { 0o755 } else { 0o644 })
+    .open(&path)
+    .and_then(|mut file| file.write_all(&output))
+    .map_err(|err| format!("failed to write {}: {err}", path.display()))
+}
+
+fn main() -> ExitCode {
+  let command = match cli::args() {
+    Ok(command) => command,
+    Err(err) => {
+      eprintln!("error: {err}\n\nFor more information, try '--help'.");
+      return ExitCode::FAILURE
+    }
+  };
+  match command {
+    Command::Help => println!("{}", cli::USAGE),
+    Command::Version => println!("beefk {}", env!("CARGO_PKG_VERSION")),
+    Command::Run(args) => {
+      if let Err(err) = run(args) {
+        eprintln!("error: {err}");
+        return ExitCode::FAILURE
+      }
+    },
+    Command::Build(args) => {
+      if let Err(err) = build(args) {
+        eprintln!("error: {err}");
+        return ExitCode::FAILURE
+      }
+    },
+  }
+  ExitCode::SUCCESS
 }
@@ -0,0 +1,311 @@
+use std::{fmt, io};
+use crate::{
+  bfil::{self, Op},
+  brainfuck::{self, BfOpBlock, CellWidth, ParseError, Span},
+  compiler::{CompilerImpl, CompilerOptions, FlushPolicy, NativeCompiler, SourceMap, Target},
+  interpreter,
+  io::{BfIo, IoContext, RunContext, RunStatus, StdIo},
+  jit::{Executable, ToFnPtr},
+  tape::{self, Fault, GuardedRun, Tape},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+  /// The source code contains syntax errors
+  Parse(ParseError),
+  /// The native compiler does not support the current target
+  Unsupported,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Parse(err) => write!(f, "{err}"),
+      Self::Unsupported => write!(f, "compiler does not support current target"),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+  fn from(err: ParseError) -> Self {
+    Self::Parse(err)
+  }
+}
+
+/// Failure while running a program
+#[derive(Debug)]
+pub enum RunError {
+  /// The program accessed (or stopped at) the cell at `offset`, before the start of the tape\
+  /// `span` is the source code doing the access, if known
+  TapeUnderflow { offset: isize, span: Option<Span> },
+  /// The program accessed (or stopped at) the cell at `offset`, past the end of the tape\
+  /// `span` is the source code doing the access, if known
+  TapeOverflow { offset: usize, span: Option<Span> },
+  /// Program I/O failed
+  Io(io::Error),
+}
+
+impl fmt::Display for RunError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let span = match self {
+      Self::TapeUnderflow { offset, span } => {
+        write!(f, "tape underflow at cell {offset}")?;
+        span
+      },
+      Self::TapeOverflow { offset, span } => {
+        write!(f, "tape overflow at cell {offset}")?;
+        span
+      },
+      Self::Io(err) => return write!(f, "program I/O failed: {err}"),
+    };
+    match span {
+      Some(span) => write!(f, ", caused by the code at {}:{}", span.line, span.column),
+      None => Ok(()),
+    }
+  }
+}
+
+impl std::error::Error for RunError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl RunError {
+  /// Attribute a tape error to `new_span`, unless it's already attributed to something more specific
+  pub(crate) fn caused_by(mut self, new_span: Option<Span>) -> Self {
+    if let Self::TapeUnderflow { span, .. } | Self::TapeOverflow { span, .. } = &mut self {
+      *span = span.or(new_span);
+    }
+    self
+  }
+}
+
+impl From<io::Error> for RunError {
+  fn from(err: io::Error) -> Self {
+    Self::Io(err)
+  }
+}
+
+/// Largest distance between the addresses of two consecutive tape accesses, in cells
+pub(crate) fn reach(ops: &[Op]) -> usize {
+  let mut reach = 0;
+  //Distance from the last accessed cell
+  let mut moved = 0;
+  let mut access = |moved: &mut usize, off: isize| {
+    reach = reach.max(*moved + off.unsigned_abs());
+    *moved = off.unsigned_abs();
+  };
+  for op in ops {
+    match *op {
+      Op::AddCell { off, .. } | Op::SetCell { off, .. } | Op::Out { off } | Op::In { off } => access(&mut moved, off),
+      Op::MulAdd { src, dst, .. } => {
+        access(&mut moved, src);
+        //dst is not accessed if src is 0
+        let src_moved = moved;
+        access(&mut moved, dst);
+        moved = moved.max(src_moved);
+      },
+      Op::MovePtr(n) => moved += n.unsigned_abs(),
+      //Loop heads and ends access the current cell, and so does every step of a scan\
+      //SSE2 scans read whole 16-byte blocks
+      Op::Scan(stride) => {
+        access(&mut moved, 0);
+        access(&mut moved, stride + 16 * stride.signum());
+        moved = 0;
+      },
+      Op::LoopStart | Op::LoopEnd | Op::CheckLoopBounds { .. } => access(&mut moved, 0),
+      Op::CheckBounds { .. } => (),
+    }
+  }
+  reach
+}
+
+enum Backend {
+  Native {
+    executable: Executable,
+    fault_exit: usize,
+    source_map: SourceMap,
+    /// Policy the code was compiled with, which decides when output written so far gets flushed
+    flush_policy: FlushPolicy,
+  },
+  /// Tree run by the [`interpreter`], with the options it was parsed and "compiled" with
+  Interpreter(BfOpBlock, CompilerOptions),
+}
+
+/// A compiled brainfuck program, ready to be executed
+pub struct Program {
+  backend: Backend,
+  cell_width: CellWidth,
+  reach: usize,
+}
+
+impl Program {
+  /// Parse, optimize and compile brainfuck source code using the default options
+  pub fn compile(code: &str) -> Result<Self, Error> {
+    Self::compile_with_options(code, &CompilerOptions::default())
+  }
+
+  /// Parse, optimize and compile brainfuck source code
+  pub fn compile_with_options(code: &str, options: &CompilerOptions) -> Result<Self, Error> {
+    Self::from_tree(&brainfuck::parse_tree(code, options.cell_width)?, options)
+  }
+
+  /// Compile an already parsed (and optionally optimized) tree\
+  /// Falls back to the [`interpreter`] if the native compiler does not support the current target
+  pub fn from_tree(block: &BfOpBlock, options: &CompilerOptions) -> Result<Self, Error> {
+    if !NativeCompiler::supported() {
+      return Ok(Self::interpreted(block, options))
+    }
+    let (ops, spans) = bfil::lower_with_spans(block);
+    Self::native(&ops, &spans, options)
+  }
+
+  /// Run a tree on the [`interpreter`] instead of compiling it
+  pub fn interpreted(block: &BfOpBlock, options: &CompilerOptions) -> Self {
+    Self {
+      backend: Backend::Interpreter(block.clone(), *options),
+      cell_width: options.cell_width,
+      reach: 0,
+    }
+  }
+
+  /// Compile a bfil program\
+  /// There is no fallback for bfil, so this fails if the native compiler does not support the current target
+  pub fn from_bfil(ops: &[Op], options: &CompilerOptions) -> Result<Self, Error> {
+    Self::native(ops, &vec![None; ops.len()], options)
+  }
+
+  /// Compile bfil ops lowered from the given spans
+  fn native(ops: &[Op], spans: &[Option<Span>], options: &CompilerOptions) -> Result<Self, Error> {
+    if !NativeCompiler::supported() {
+      return Err(Error::Unsupported)
+    }
+    let compiled = NativeCompiler::compile(ops, Target::Host, options);
+    Ok(Self {
+      backend: Backend::Native {
+        executable: Executable::from(&compiled.code[..]),
+        fault_exit: compiled.fault_exit,
+        source_map: compiled.source_map(spans),
+        flush_policy: options.flush_policy,
+      },
+      cell_width: options.cell_width,
+      //Bounds checks keep the program from accessing cells out of bounds in the first place
+      reach: if options.bounds_checks { 0 } else { reach(ops) * options.cell_width.bytes() },
+    })
+  }
+
+  /// Largest distance in bytes the program can move the data pointer without accessing the tape\
+  /// Guard regions of the tape must be at least this large
+  pub fn reach(&self) -> usize {
+    self.reach
+  }
+
+  /// Whether the program runs on the [`interpreter`]
+  pub fn is_interpreted(&self) -> bool {
+    matches!(self.backend, Backend::Interpreter(..))
+  }
+
+  /// Generated machine code, empty for interpreted programs
+  pub fn code(&self) -> &[u8] {
+    match &self.backend {
+      Backend::Native { executable, .. } => executable.get(),
+      Backend::Interpreter(..) => &[],
+    }
+  }
+
+  /// Table from offsets in [`Program::code`] to the source code they were generated from\
+  /// Empty for programs compiled from bfil, and `None` for interpreted programs
+  pub fn source_map(&self) -> Option<&SourceMap> {
+    match &self.backend {
+      Backend::Native { source_map, .. } => Some(source_map),
+      Backend::Interpreter(..) => None,
+    }
+  }
+
+  /// Run the program on stdin and stdout, with the data pointer starting at `tape[0]`
+  ///
+  /// See [`Program::run_with_io`]
+  pub fn run(&self, tape: &mut Tape) -> Result<usize, RunError> {
+    self.run_with_io(tape, &mut StdIo)
+  }
+
+  /// Run the program with custom I/O, with the data pointer starting at `tape[0]`
+  ///
+  /// The tape is indexed in cells of the width the program was compiled for,
+  /// stored in native byte order, and should hold a whole number of them\
+  /// Returns the final position of the data pointer on the tape in cells, so it can be resumed from there,
+  /// ending up outside of the tape is an error as well\
+  /// Generated code does not perform any bounds checks, accessing the tape out of bounds
+  /// hits its guard regions instead, stopping the program with an error\
+  /// Growable tapes grow as needed, and only accessing cells past their limit is an error\
+  /// If `io` reports an error, the program keeps running with all further input treated as EOF
+  /// and all further output discarded, and the first error is returned instead
+  ///
+  /// Panics if the guard regions of `tape` are smaller than [`Program::reach`]
+  pub fn run_with_io(&self, tape: &mut Tape, io: &mut dyn BfIo) -> Result<usize, RunError> {
+    assert!(self.reach <= tape.guard_size(), "tape guard regions are too small for this program");
+    let (executable, fault_exit, source_map, flush_policy) = match &self.backend {
+      Backend::Native { executable, fault_exit, source_map, flush_policy } => (executable, *fault_exit, source_map, *flush_policy),
+      Backend::Interpreter(block, options) => return interpreter::run(block, tape, io, options),
+    };
+    let code = executable.get().as_ptr() as usize;
+    let mut run = GuardedRun {
+      code: (code, code + executable.get().len()),
+      fault_exit: code + fault_exit,
+      tape_start: tape.as_ptr() as usize,
+      tape_len: tape.len(),
+      tape_limit: tape.limit(),
+      guard_size: tape.guard_size(),
+      page_size: tape::page_size(),
+    };
+    let fn_ptr: unsafe extern "C" fn(*mut u8, *mut RunContext) -> *mut u8 = unsafe { executable.to_fn_ptr() };
+    let mut ctx = IoContext::new(io, tape, flush_policy);
+    //Safety: every out of bounds access hits a guard region first, see `reach`
+    let (data_ptr, fault) = tape::run_guarded(&mut run, || unsafe { fn_ptr(tape.as_mut_ptr(), ctx.as_run_context()) });
+    tape.grow(run.tape_len);
+    let &RunContext { status, fault_offset, check_offset, .. } = ctx.run_context();
+    //Output is flushed even if the program faulted, but tape errors take precedence over I/O errors
+    let io_result = ctx.finish();
+    //Faults and bounds checks report byte offsets
+    let bytes = self.cell_width.bytes();
+    match fault {
+      Some((Fault::Underflow(offset), pc)) => {
+        let span = source_map.lookup(pc - code);
+        return Err(RunError::TapeUnderflow { offset: offset.div_euclid(bytes as isize), span })
+      },
+      Some((Fault::Overflow(offset), pc)) => {
+        let span = source_map.lookup(pc - code);
+        return Err(RunError::TapeOverflow { offset: offset / bytes, span })
+      },
+      None => (),
+    }
+    match status {
+      RunStatus::TapeUnderflow => {
+        let span = source_map.lookup(check_offset);
+        return Err(RunError::TapeUnderflow { offset: fault_offset.div_euclid(bytes as isize), span })
+      },
+      RunStatus::TapeOverflow => {
+        let span = source_map.lookup(check_offset);
+        return Err(RunError::TapeOverflow { offset: fault_offset as usize / bytes, span })
+      },
+      RunStatus::Finished => (),
+    }
+    io_result?;
+    //The data pointer may end up out of bounds without the program ever accessing the cell there
+    let offset = data_ptr as isize - tape.as_ptr() as isize;
+    match usize::try_from(offset) {
+      Ok(offset) if offset < tape.limit() => {
+        tape.grow((offset + bytes).max(tape.len()));
+        Ok(offset / bytes)
+      },
+      Ok(offset) => Err(RunError::TapeOverflow { offset: offset / bytes, span: None }),
+      Err(_) => Err(RunError::TapeUnderflow { offset: offset.div_euclid(bytes as isize), span: None }),
+    }
+  }
+}
@@ -0,0 +1,317 @@
+//! Guard page protected tape
+//!
+//! Generated code does not check bounds, instead the tape is surrounded by `PROT_NONE` guard regions.\
+//! A SIGSEGV handler recognizes faults inside them while generated code is running,
+//! and resumes execution at the fault exit of the program (see [`CompiledCode::fault_exit`]).
+//!
+//! Growable tapes reserve address space up to their limit, but only commit the part in use.
+//! Faults past the committed part commit more of it, and retry the access.
+//!
+//! [`CompiledCode::fault_exit`]: crate::compiler::CompiledCode::fault_exit
+
+use std::{cell::Cell, mem::MaybeUninit, ops::{Deref, DerefMut}, ptr, sync::Once};
+use crate::CellWidth;
+
+pub(crate) fn page_size() -> usize {
+  unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Value of a cell stored in native byte order, as wide as `bytes`
+pub(crate) fn load_cell(bytes: &[u8]) -> u32 {
+  match *bytes {
+    [byte] => byte as u32,
+    [a, b] => u16::from_ne_bytes([a, b]) as u32,
+    [a, b, c, d] => u32::from_ne_bytes([a, b, c, d]),
+    _ => unreachable!(),
+  }
+}
+
+/// Store `value` truncated to the width of `bytes`, in native byte order
+pub(crate) fn store_cell(bytes: &mut [u8], value: u32) {
+  //Native byte order keeps the low bytes first on little endian targets, and last otherwise
+  let value = value.to_ne_bytes();
+  let value = if cfg!(target_endian = "little") { &value[..bytes.len()] } else { &value[4 - bytes.len()..] };
+  bytes.copy_from_slice(value);
+}
+
+/// Memory for the cells of a program, with guard regions on both sides\
+/// Sizes are in bytes, except for the constructors and accessors named after cells.
+/// Tapes allocated in bytes have 8-bit cells
+pub struct Tape {
+  mapping: *mut libc::c_void,
+  map_size: usize,
+  guard_size: usize,
+  /// Committed bytes
+  len: usize,
+  /// Reserved bytes, which the tape can grow to
+  limit: usize,
+  cell_width: CellWidth,
+}
+
+impl Tape {
+  /// Guard region size used by [`Tape::new`]
+  pub const DEFAULT_GUARD_SIZE: usize = 1 << 20;
+
+  /// Allocate a zeroed tape of at least `len_bytes` bytes
+  pub fn new(len_bytes: usize) -> Self {
+    Self::with_guard_size(len_bytes, Self::DEFAULT_GUARD_SIZE)
+  }
+
+  /// Allocate a zeroed tape of at least `len_bytes` bytes, with `guard_size` bytes of guard region on each side\
+  /// Both sizes are rounded up to the page size
+  pub fn with_guard_size(len_bytes: usize, guard_size: usize) -> Self {
+    Self::growable_with_guard_size(len_bytes, len_bytes, guard_size)
+  }
+
+  /// Allocate a zeroed tape of at least `len_bytes` bytes, which grows on demand up to `limit_bytes` bytes
+  pub fn growable(len_bytes: usize, limit_bytes: usize) -> Self {
+    Self::growable_with_guard_size(len_bytes, limit_bytes, Self::DEFAULT_GUARD_SIZE)
+  }
+
+  /// Allocate a zeroed tape of at least `len_bytes` bytes, which grows on demand up to `limit_bytes` bytes,
+  /// with `guard_size` bytes of guard region on each side\
+  /// All sizes are rounded up to the page size
+  pub fn growable_with_guard_size(len_bytes: usize, limit_bytes: usize, guard_size: usize) -> Self {
+    let page_size = page_size();
+    let len = len_bytes.max(1).next_multiple_of(page_size);
+    let limit = limit_bytes.max(len).next_multiple_of(page_size);
+    let guard_size = guard_size.max(1).next_multiple_of(page_size);
+    let map_size = limit + 2 * guard_size;
+    let mapping = unsafe {
+      libc::mmap(
+        ptr::null_mut(),
+        map_size,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1, 0
+      )
+    };
+    assert_ne!(mapping, libc::MAP_FAILED);
+    let mut tape = Self { mapping, map_size, guard_size, len: 0, limit, cell_width: CellWidth::U8 };
+    tape.grow(len);
+    tape
+  }
+
+  /// Allocate a zeroed tape of at least `len` cells of `cell_width`
+  pub fn cells(len: usize, cell_width: CellWidth) -> Self {
+    Self::cells_with_guard_size(len, cell_width, Self::DEFAULT_GUARD_SIZE)
+  }
+
+  /// Allocate a zeroed tape of at least `len` cells of `cell_width`, with `guard_size` bytes of guard region on each side
+  pub fn cells_with_guard_size(len: usize, cell_width: CellWidth, guard_size: usize) -> Self {
+    Self::growable_cells_with_guard_size(len, len, cell_width, guard_size)
+  }
+
+  /// Allocate a zeroed tape of at least `len` cells of `cell_width`, which grows on demand up to `limit` cells
+  pub fn growable_cells(len: usize, limit: usize, cell_width: CellWidth) -> Self {
+    Self::growable_cells_with_guard_size(len, limit, cell_width, Self::DEFAULT_GUARD_SIZE)
+  }
+
+  /// Allocate a zeroed tape of at least `len` cells of `cell_width`, which grows on demand up to `limit` cells,
+  /// with `guard_size` bytes of guard region on each side
+  pub fn growable_cells_with_guard_size(len: usize, limit: usize, cell_width: CellWidth, guard_size: usize) -> Self {
+    let bytes = cell_width.bytes();
+    let mut tape = Self::growable_with_guard_size(len * bytes, limit * bytes, guard_size);
+    tape.cell_width = cell_width;
+    tape
+  }
+
+  /// Commit at least the first `len_bytes` bytes
+  pub(crate) fn grow(&mut self, len_bytes: usize) {
+    assert!(len_bytes <= self.limit);
+    let len = len_bytes.next_multiple_of(page_size());
+    if len > self.len {
+      unsafe { assert!(commit(self.as_ptr() as usize, self.len, len)) };
+      self.len = len;
+    }
+  }
+
+  /// Size of the tape in bytes, which only changes when a growable tape grows
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Size in bytes the tape can grow to, same as `len` unless it's growable
+  pub fn limit(&self) -> usize {
+    self.limit
+  }
+
+  /// Size of the guard region on each side
+  pub fn guard_size(&self) -> usize {
+    self.guard_size
+  }
+
+  /// Width of the cells [`Tape::get_cell`] and [`Tape::set_cell`] access
+  pub fn cell_width(&self) -> CellWidth {
+    self.cell_width
+  }
+
+  /// Value of cell `idx`, or `None` past the committed part of the tape
+  pub fn get_cell(&self, idx: usize) -> Option<u32> {
+    let bytes = self.cell_width.bytes();
+    let start = idx.checked_mul(bytes)?;
+    self.get(start..start.checked_add(bytes)?).map(load_cell)
+  }
+
+  /// Set cell `idx` to `value`, truncated to the cell width\
+  /// Panics past the committed part of the tape
+  pub fn set_cell(&mut self, idx: usize, value: u32) {
+    let bytes = self.cell_width.bytes();
+    store_cell(&mut self[idx * bytes..][..bytes], value);
+  }
+
+  pub fn as_ptr(&self) -> *const u8 {
+    unsafe { self.mapping.byte_add(self.guard_size) as *const u8 }
+  }
+
+  pub fn as_mut_ptr(&mut self) -> *mut u8 {
+    unsafe { self.mapping.byte_add(self.guard_size) as *mut u8 }
+  }
+}
+
+impl Deref for Tape {
+  type Target = [u8];
+  fn deref(&self) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len) }
+  }
+}
+
+impl DerefMut for Tape {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+  }
+}
+
+impl Drop for Tape {
+  fn drop(&mut self) {
+    unsafe {
+      assert_eq!(libc::munmap(self.mapping, self.map_size), 0);
+    }
+  }
+}
+
+/// Make the bytes from `len` up to `new_len` of the tape at `start` accessible\
+/// Only does a syscall, so it can be used from the signal handler
+unsafe fn commit(start: usize, len: usize, new_len: usize) -> bool {
+  unsafe { libc::mprotect((start + len) as *mut libc::c_void, new_len - len, libc::PROT_READ | libc::PROT_WRITE) == 0 }
+}
+
+/// Access to a guard region, as an offset from the start of the tape
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Fault {
+  Underflow(isize),
+  Overflow(usize),
+}
+
+/// Generated code running on the current thread
+#[derive(Clone, Copy)]
+pub(crate) struct GuardedRun {
+  /// Address range of the generated code
+  pub code: (usize, usize),
+  /// Address to resume at after a fault
+  pub fault_exit: usize,
+  pub tape_start: usize,
+  /// Committed bytes, updated as the tape grows
+  pub tape_len: usize,
+  pub tape_limit: usize,
+  pub guard_size: usize,
+  /// Queried up front, since [`page_size`] isn't async-signal-safe and can't be called from the signal handler
+  pub page_size: usize,
+}
+
+thread_local! {
+  static ACTIVE_RUN: Cell<Option<GuardedRun>> = const { Cell::new(None) };
+  /// Along with the address of the faulting instruction
+  static LAST_FAULT: Cell<Option<(Fault, usize)>> = const { Cell::new(None) };
+}
+
+static mut PREVIOUS_ACTION: MaybeUninit<libc::sigaction> = MaybeUninit::uninit();
+static INSTALL_HANDLER: Once = Once::new();
+
+/// Run `f`, which calls into generated code described by `run`,
+/// turning faults inside the tape guard regions into a [`Fault`], along with the address of the faulting instruction\
+/// and growing the tape on faults past its committed part
+pub(crate) fn run_guarded<R>(run: &mut GuardedRun, f: impl FnOnce() -> R) -> (R, Option<(Fault, usize)>) {
+  INSTALL_HANDLER.call_once(|| unsafe {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handle_sigsegv as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+    let previous_action = &raw mut PREVIOUS_ACTION;
+    assert_eq!(libc::sigaction(libc::SIGSEGV, &action, (*previous_action).as_mut_ptr()), 0);
+  });
+  //Programs may be run from within I/O callbacks of other programs
+  let outer_run = ACTIVE_RUN.replace(Some(*run));
+  let outer_fault = LAST_FAULT.take();
+  let result = f();
+  *run = ACTIVE_RUN.replace(outer_run).unwrap();
+  (result, LAST_FAULT.replace(outer_fault))
+}
+
+/// Program counter of the interrupted code
+#[cfg(target_arch = "x86_64")]
+fn program_counter(context: &mut libc::ucontext_t) -> &mut libc::greg_t {
+  &mut context.uc_mcontext.gregs[libc::REG_RIP as usize]
+}
+
+/// Program counter of the interrupted code
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn program_counter(context: &mut libc::ucontext_t) -> &mut u64 {
+  &mut context.uc_mcontext.pc
+}
+
+extern "C" fn handle_sigsegv(signal: libc::c_int, info: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+  let context = unsafe { &mut *(ucontext as *mut libc::ucontext_t) };
+  let pc = program_counter(context);
+  let address = unsafe { (*info).si_addr() } as usize;
+  if let Some(mut run) = ACTIVE_RUN.get() {
+    let in_code = (run.code.0..run.code.1).contains(&(*pc as usize));
+    let (tape_end, tape_limit) = (run.tape_start + run.tape_len, run.tape_start + run.tape_limit);
+    if in_code && address >= tape_end && address < tape_limit {
+      //Grow the tape to cover the address, at least doubling it, then retry the access
+      let new_len = (address - run.tape_start + 1).next_multiple_of(run.page_size)
+        .max(run.tape_len * 2)
+        .min(run.tape_limit);
+      if unsafe { commit(run.tape_start, run.tape_len, new_len) } {
+        run.tape_len = new_len;
+        ACTIVE_RUN.set(Some(run));
+        return
+      }
+    }
+    let fault = if address < run.tape_start && address >= run.tape_start - run.guard_size {
+      Some(Fault::Underflow(address as isize - run.tape_start as isize))
+    } else if address >= tape_limit && address < tape_limit + run.guard_size {
+      Some(Fault::Overflow(address - run.tape_start))
+    } else {
+      None
+    };
+    if let (true, Some(fault)) = (in_code, fault) {
+      LAST_FAULT.set(Some((fault, *pc as usize)));
+      *pc = run.fault_exit as _;
+      return
+    }
+  }
+  //Not ours, hand it over to whoever was there before
+  unsafe {
+    let previous_action = &raw const PREVIOUS_ACTION;
+    let previous_action = (*previous_action).assume_init_ref();
+    match previous_action.sa_sigaction {
+      libc::SIG_DFL | libc::SIG_IGN => {
+        //Returning re-executes the faulting instruction, which then crashes as usual
+        libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+      },
+      handler if previous_action.sa_flags & libc::SA_SIGINFO != 0 => {
+        let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = std::mem::transmute(handler);
+        handler(signal, info, ucontext);
+      },
+      handler => {
+        let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+        handler(signal);
+      },
+    }
+  }
+}